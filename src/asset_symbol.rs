@@ -1,11 +1,29 @@
+use std::convert::Infallible;
 use std::fmt::Display;
 use std::ops::Deref;
+use std::str::FromStr;
 use compact_str::CompactString;
 use rust_extensions::sorted_vec::EntityWithKey;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct AssetSymbol(pub CompactString);
 
+impl AssetSymbol {
+    /// Creates symbol in upper case, use it on ingestion boundaries before inserting into
+    /// collections ordered or hashed by symbol
+    pub fn new_normalized(value: &str) -> Self {
+        AssetSymbol(value.to_ascii_uppercase().into())
+    }
+
+    /// Returns symbol in upper case
+    pub fn normalized(&self) -> AssetSymbol {
+        Self::new_normalized(&self.0)
+    }
+}
+
 impl Deref for AssetSymbol {
     type Target = str;
 
@@ -32,6 +50,14 @@ impl From<&String> for AssetSymbol {
     }
 }
 
+impl FromStr for AssetSymbol {
+    type Err = Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(value.into())
+    }
+}
+
 impl Display for AssetSymbol {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0.to_string())
@@ -42,4 +68,26 @@ impl EntityWithKey<AssetSymbol> for AssetSymbol {
     fn get_key(&self) -> &AssetSymbol {
         self
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AssetSymbol;
+
+    #[test]
+    fn normalized_ignores_case() {
+        let upper: AssetSymbol = "BTC".into();
+        let lower: AssetSymbol = "btc".into();
+
+        assert_ne!(upper, lower);
+        assert_eq!(upper.normalized(), lower.normalized());
+        assert_eq!(upper, AssetSymbol::new_normalized("bTc"));
+    }
+
+    #[test]
+    fn parse() {
+        let symbol = "BTC".parse::<AssetSymbol>();
+
+        assert_eq!(Ok(AssetSymbol::from("BTC")), symbol);
+    }
+}
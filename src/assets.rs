@@ -1,12 +1,44 @@
-use rust_extensions::sorted_vec::EntityWithKey;
+use ahash::AHashMap;
+use rust_extensions::sorted_vec::{EntityWithKey, SortedVec};
 use crate::asset_symbol::AssetSymbol;
+use crate::calculations::RoundingMode;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct AssetAmount {
     pub amount: f64,
     pub symbol: AssetSymbol,
 }
 
+impl AssetAmount {
+    pub fn rounded(&self, precision: u32, mode: RoundingMode) -> AssetAmount {
+        AssetAmount {
+            amount: mode.apply(self.amount, precision),
+            symbol: self.symbol.clone(),
+        }
+    }
+
+    pub fn round_in_place(&mut self, precision: u32, mode: RoundingMode) {
+        self.amount = mode.apply(self.amount, precision);
+    }
+
+    /// Adds items to target amounts by symbol, inserting missing symbols
+    pub fn accumulate_into<'a>(
+        target: &mut SortedVec<AssetSymbol, AssetAmount>,
+        items: impl Iterator<Item = &'a AssetAmount>,
+    ) {
+        for item in items {
+            if let Some(target_amount) = target.get_mut(&item.symbol) {
+                target_amount.amount += item.amount;
+            } else {
+                target.insert_or_replace(item.clone());
+            }
+        }
+    }
+}
+
 impl EntityWithKey<AssetSymbol> for AssetAmount {
     fn get_key(&self) -> &AssetSymbol {
         &self.symbol
@@ -14,6 +46,7 @@ impl EntityWithKey<AssetSymbol> for AssetAmount {
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct AssetPrice {
     pub price: f64,
     pub symbol: AssetSymbol,
@@ -34,3 +67,88 @@ impl EntityWithKey<AssetSymbol> for AssetPrice {
     }
 }
 
+/// Registry of amount precision per asset
+#[derive(Clone, Debug)]
+pub struct AssetDecimals {
+    decimals_by_assets: AHashMap<AssetSymbol, u32>,
+    default_decimals: u32,
+    mode: RoundingMode,
+}
+
+impl AssetDecimals {
+    pub fn new(default_decimals: u32, mode: RoundingMode) -> Self {
+        Self {
+            decimals_by_assets: AHashMap::new(),
+            default_decimals,
+            mode,
+        }
+    }
+
+    pub fn set_decimals(&mut self, asset: AssetSymbol, decimals: u32) {
+        self.decimals_by_assets.insert(asset, decimals);
+    }
+
+    /// Returns decimals of asset or default decimals if asset isn't registered
+    pub fn decimals_for(&self, asset: &AssetSymbol) -> u32 {
+        self.decimals_by_assets
+            .get(asset)
+            .copied()
+            .unwrap_or(self.default_decimals)
+    }
+
+    pub fn round_amount(&self, amount: &AssetAmount) -> AssetAmount {
+        amount.rounded(self.decimals_for(&amount.symbol), self.mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_amount_by_asset_decimals() {
+        let mut decimals = AssetDecimals::new(4, RoundingMode::Floor);
+        decimals.set_decimals("BTC".into(), 8);
+        decimals.set_decimals("USDT".into(), 2);
+
+        let btc = decimals.round_amount(&AssetAmount {amount: 0.123456789, symbol: "BTC".into()});
+        let usdt = decimals.round_amount(&AssetAmount {amount: 10.129, symbol: "USDT".into()});
+        let eth = decimals.round_amount(&AssetAmount {amount: 1.23456, symbol: "ETH".into()});
+
+        assert_eq!(0.12345678, btc.amount);
+        assert_eq!(10.12, usdt.amount);
+        assert_eq!(1.2345, eth.amount);
+        assert_eq!(4, decimals.decimals_for(&"ETH".into()));
+    }
+
+    #[test]
+    fn accumulate_into_matches_manual_sum() {
+        let order_assets = [
+            AssetAmount {amount: 100.0, symbol: "USDT".into()},
+            AssetAmount {amount: 0.5, symbol: "BTC".into()},
+        ];
+        let top_up_assets = [
+            AssetAmount {amount: 25.0, symbol: "USDT".into()},
+            AssetAmount {amount: 2.0, symbol: "ETH".into()},
+        ];
+        let mut manual: SortedVec<AssetSymbol, AssetAmount> = SortedVec::new();
+
+        for item in order_assets.iter().chain(top_up_assets.iter()) {
+            if let Some(total_amount) = manual.get_mut(&item.symbol) {
+                total_amount.amount += item.amount;
+            } else {
+                manual.insert_or_replace(item.clone());
+            }
+        }
+
+        let mut accumulated: SortedVec<AssetSymbol, AssetAmount> = SortedVec::new();
+        AssetAmount::accumulate_into(&mut accumulated, order_assets.iter());
+        AssetAmount::accumulate_into(&mut accumulated, top_up_assets.iter());
+
+        assert_eq!(manual.len(), accumulated.len());
+        for item in manual.iter() {
+            assert_eq!(item.amount, accumulated.get(&item.symbol).unwrap().amount);
+        }
+        assert_eq!(125.0, accumulated.get(&"USDT".into()).unwrap().amount);
+    }
+}
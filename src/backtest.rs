@@ -0,0 +1,107 @@
+use crate::monitoring::{PositionMonitoringEvent, PositionsMonitor};
+use crate::positions::BidAsk;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BacktestSummary {
+    pub ticks_count: usize,
+    pub closed_count: usize,
+    /// Sum of pnl of positions closed during run
+    pub total_pnl: f64,
+}
+
+/// Feeds historical ticks through monitor and collects events
+pub struct Backtester {
+    monitor: PositionsMonitor,
+    summary: BacktestSummary,
+}
+
+impl Backtester {
+    pub fn new(monitor: PositionsMonitor) -> Self {
+        Self {
+            monitor,
+            summary: BacktestSummary::default(),
+        }
+    }
+
+    pub fn run(&mut self, ticks: impl Iterator<Item = BidAsk>) -> Vec<PositionMonitoringEvent> {
+        let mut events = Vec::new();
+
+        for bidask in ticks {
+            let tick_events = self.monitor.update(&bidask);
+            self.summary.ticks_count += 1;
+
+            for event in tick_events.iter() {
+                if let PositionMonitoringEvent::PositionClosed(position) = event {
+                    self.summary.closed_count += 1;
+                    self.summary.total_pnl += position.pnl.unwrap_or(0.0);
+                }
+            }
+
+            events.extend(tick_events);
+        }
+
+        events
+    }
+
+    pub fn get_summary(&self) -> &BacktestSummary {
+        &self.summary
+    }
+
+    pub fn get_monitor(&self) -> &PositionsMonitor {
+        &self.monitor
+    }
+
+    pub fn get_monitor_mut(&mut self) -> &mut PositionsMonitor {
+        &mut self.monitor
+    }
+
+    pub fn into_monitor(self) -> PositionsMonitor {
+        self.monitor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Backtester;
+    use crate::assets::AssetPrice;
+    use crate::monitoring::{PositionMonitoringEvent, PositionsMonitor};
+    use crate::orders::{new_test_order, Order};
+    use crate::positions::BidAsk;
+    use rust_extensions::sorted_vec::SortedVec;
+    use std::time::Duration;
+
+    #[test]
+    fn run_with_stop_out() {
+        let mut monitor = PositionsMonitor::new(100, Duration::from_secs(1), 10.0, None, false);
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+        monitor.add(new_order().open(&bidask, &prices, None).unwrap());
+        let mut backtester = Backtester::new(monitor);
+        let ticks = [9.9, 9.5, 7.5, 7.0]
+            .into_iter()
+            .map(|price| BidAsk::new_synthetic("ATOMUSDT".into(), price, price));
+
+        let events = backtester.run(ticks);
+
+        let summary = backtester.get_summary();
+        assert_eq!(4, summary.ticks_count);
+        assert_eq!(1, summary.closed_count);
+        assert_eq!(-250.0, summary.total_pnl);
+        assert_eq!(
+            1,
+            events
+                .iter()
+                .filter(|event| matches!(event, PositionMonitoringEvent::PositionClosed(_)))
+                .count()
+        );
+        assert_eq!(0, backtester.get_monitor().count());
+    }
+
+    fn new_order() -> Order {
+        Order {
+            leverage: 10.0,
+            ..new_test_order()
+        }
+    }
+}
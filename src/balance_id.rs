@@ -0,0 +1,31 @@
+use std::fmt::Display;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BalanceId(pub String);
+
+impl From<&str> for BalanceId {
+    fn from(value: &str) -> Self {
+        BalanceId(value.to_string())
+    }
+}
+
+impl From<&String> for BalanceId {
+    fn from(value: &String) -> Self {
+        BalanceId(value.to_owned())
+    }
+}
+
+impl From<String> for BalanceId {
+    fn from(value: String) -> Self {
+        BalanceId(value)
+    }
+}
+
+impl Display for BalanceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
@@ -1,4 +1,5 @@
 use std::mem;
+use crate::orders::OrderSide;
 use crate::positions::{BidAsk, Position};
 use ahash::{AHashMap, AHashSet};
 use rust_extensions::sorted_vec::{EntityWithKey, SortedVec};
@@ -7,6 +8,7 @@ use crate::assets::AssetPrice;
 use crate::instrument_symbol::InstrumentSymbol;
 use crate::position_id::PositionId;
 use crate::wallet_id::WalletId;
+use crate::errors::TradingError;
 
 impl EntityWithKey<InstrumentSymbol> for BidAsk {
     fn get_key(&self) -> &InstrumentSymbol {
@@ -14,6 +16,11 @@ impl EntityWithKey<InstrumentSymbol> for BidAsk {
     }
 }
 
+/// Source of current quotes by instruments
+pub trait PriceSource {
+    fn get(&self, instrument: &InstrumentSymbol) -> Option<BidAsk>;
+}
+
 #[derive(Clone, Debug)]
 pub struct BidAsksCache {
     items: SortedVec<InstrumentSymbol, BidAsk>,
@@ -32,7 +39,16 @@ impl BidAsksCache {
         }
     }
 
-    pub fn update(&mut self, bidask: BidAsk) {
+    /// Updates quote if it's valid, otherwise keeps previous quote
+    pub fn update(&mut self, bidask: BidAsk) -> Result<(), TradingError> {
+        bidask.validate()?;
+        self.update_unchecked(bidask);
+
+        Ok(())
+    }
+
+    /// Updates quote without validation, for trusted feeds
+    pub fn update_unchecked(&mut self, bidask: BidAsk) {
         let current_bidask = self.items.get_mut(&bidask.instrument);
 
         if let Some(current_bidask) = current_bidask {
@@ -42,6 +58,33 @@ impl BidAsksCache {
         }
     }
 
+    /// Updates all valid quotes. Returns error of first invalid quote
+    pub fn update_many(&mut self, bidasks: Vec<BidAsk>) -> Result<(), TradingError> {
+        let mut result = Ok(());
+
+        for bidask in bidasks.into_iter() {
+            if let Err(err) = self.update(bidask) {
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+
+        result
+    }
+
+    pub fn remove(&mut self, instrument: &InstrumentSymbol) -> Option<BidAsk> {
+        self.items.remove(instrument)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
     pub fn get(&self, instrument: &InstrumentSymbol) -> Option<&BidAsk> {
         self.items.get(instrument)
     }
@@ -65,6 +108,16 @@ impl BidAsksCache {
     }
 
     pub fn find_prices(&self, to_asset: &AssetSymbol, from_assets: &[&AssetSymbol]) -> SortedVec<AssetSymbol, AssetPrice> {
+        self.find_prices_by_side(to_asset, from_assets, &OrderSide::Sell)
+    }
+
+    /// Same as find_prices but values assets by provided side of quote
+    pub fn find_prices_by_side(
+        &self,
+        to_asset: &AssetSymbol,
+        from_assets: &[&AssetSymbol],
+        side: &OrderSide,
+    ) -> SortedVec<AssetSymbol, AssetPrice> {
         let mut prices = SortedVec::new_with_capacity(from_assets.len());
 
         for asset in from_assets {
@@ -79,7 +132,7 @@ impl BidAsksCache {
             let bidask = self.items.get(&instrument);
 
             if let Some(bidask) = bidask {
-                let price = bidask.get_asset_price(asset, &crate::orders::OrderSide::Sell);
+                let price = bidask.get_asset_price(asset, side);
                 prices.insert_or_replace(AssetPrice {price, symbol: symbol.clone()});
             }
         }
@@ -88,6 +141,12 @@ impl BidAsksCache {
     }
 }
 
+impl PriceSource for BidAsksCache {
+    fn get(&self, instrument: &InstrumentSymbol) -> Option<BidAsk> {
+        self.items.get(instrument).cloned()
+    }
+}
+
 pub struct PositionsCache {
     positions_by_ids: AHashMap<PositionId, Position>,
     ids_by_wallet_ids: AHashMap<WalletId, AHashSet<PositionId>>,
@@ -109,11 +168,25 @@ impl PositionsCache {
         self.positions_by_ids.is_empty()
     }
 
+    /// Adds or replaces position. If replaced position belongs to other wallet, it's removed
+    /// from index of that wallet
     pub fn add(&mut self, position: Position) {
         let id = position.get_id().to_owned();
         let wallet_id = position.get_order().wallet_id.clone();
 
-        self.positions_by_ids.insert(id.clone(), position);
+        if let Some(replaced) = self.positions_by_ids.insert(id.clone(), position) {
+            let replaced_wallet_id = &replaced.get_order().wallet_id;
+
+            if replaced_wallet_id != &wallet_id {
+                if let Some(ids) = self.ids_by_wallet_ids.get_mut(replaced_wallet_id) {
+                    ids.remove(&id);
+
+                    if ids.is_empty() {
+                        self.ids_by_wallet_ids.remove(replaced_wallet_id);
+                    }
+                }
+            }
+        }
 
         if let Some(ids) = self.ids_by_wallet_ids.get_mut(&wallet_id) {
             ids.insert(id);
@@ -123,13 +196,26 @@ impl PositionsCache {
         }
     }
 
+    /// Returns positions of the wallet sorted by id
     pub fn get_by_wallet_id(&self, wallet_id: &WalletId, limit: usize) -> Vec<&Position> {
+        self.get_by_wallet_paged(wallet_id, 0, limit)
+    }
+
+    /// Returns page of wallet positions ordered by position id
+    pub fn get_by_wallet_paged(
+        &self,
+        wallet_id: &WalletId,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<&Position> {
         let ids = self.ids_by_wallet_ids.get(wallet_id);
 
         if let Some(ids) = ids {
-            let mut positions = Vec::with_capacity(limit);
+            let mut ids: Vec<&PositionId> = ids.iter().collect();
+            ids.sort();
+            let mut positions = Vec::with_capacity(limit.min(ids.len().saturating_sub(offset)));
 
-            for id in ids.iter().take(limit) {
+            for id in ids.into_iter().skip(offset).take(limit) {
                 positions.push(self.positions_by_ids.get(id).expect("Error in add method"));
             }
 
@@ -143,6 +229,14 @@ impl PositionsCache {
         self.ids_by_wallet_ids.contains_key(wallet_id)
     }
 
+    pub fn get(&self, id: &PositionId) -> Option<&Position> {
+        self.positions_by_ids.get(id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Position> {
+        self.positions_by_ids.values()
+    }
+
     pub fn get_mut(&mut self, id: &PositionId) -> Option<&mut Position> {
         self.positions_by_ids.get_mut(id)
     }
@@ -163,9 +257,10 @@ impl PositionsCache {
 #[cfg(test)]
 mod tests {
     use rust_extensions::date_time::DateTimeAsMicroseconds;
-    use super::{PositionsCache};
+    use super::{BidAsksCache, PositionsCache};
+    use crate::errors::TradingError;
     use crate::{
-        orders::Order,
+        orders::{new_test_order, Order},
         positions::{BidAsk, Position},
     };
     use rust_extensions::sorted_vec::SortedVec;
@@ -173,6 +268,45 @@ mod tests {
     use crate::assets::{AssetAmount, AssetPrice};
     use crate::wallet_id::WalletId;
 
+    #[test]
+    fn bidasks_cache_update_many_and_remove() {
+        let mut cache = BidAsksCache::new(Vec::new());
+
+        cache.update_many(vec![
+            BidAsk::new_synthetic("BTCUSDT".into(), 20000.0, 20001.0),
+            BidAsk::new_synthetic("ETHUSDT".into(), 1500.0, 1501.0),
+            BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.1),
+        ]).unwrap();
+        cache.update_many(vec![BidAsk::new_synthetic("BTCUSDT".into(), 21000.0, 21001.0)]).unwrap();
+        let removed = cache.remove(&"ETHUSDT".into());
+
+        assert!(removed.is_some());
+        assert_eq!(2, cache.len());
+        assert!(cache.get(&"ETHUSDT".into()).is_none());
+        assert_eq!(21000.0, cache.get(&"BTCUSDT".into()).unwrap().bid);
+        assert!(cache.get(&"ATOMUSDT".into()).is_some());
+    }
+
+    #[test]
+    fn bidasks_cache_rejects_nan_ask() {
+        let mut cache = BidAsksCache::new(vec![BidAsk::new_synthetic("BTCUSDT".into(), 20000.0, 20001.0)]);
+
+        let result = cache.update(BidAsk::new_synthetic("BTCUSDT".into(), 20000.0, f64::NAN));
+
+        assert!(matches!(result, Err(TradingError::InvalidQuote(_))));
+        assert_eq!(20001.0, cache.get(&"BTCUSDT".into()).unwrap().ask);
+    }
+
+    #[test]
+    fn bidasks_cache_rejects_crossed_quote() {
+        let mut cache = BidAsksCache::new(vec![BidAsk::new_synthetic("BTCUSDT".into(), 20000.0, 20001.0)]);
+
+        let result = cache.update(BidAsk::new_synthetic("BTCUSDT".into(), 20002.0, 20001.0));
+
+        assert!(matches!(result, Err(TradingError::CrossedBook { .. })));
+        assert_eq!(20000.0, cache.get(&"BTCUSDT".into()).unwrap().bid);
+    }
+
     #[test]
     fn positions_cache_is_empty() {
         let cache = PositionsCache::with_capacity(10);
@@ -206,6 +340,21 @@ mod tests {
         assert!(cache.is_empty());
     }
 
+    #[test]
+    fn positions_cache_get() {
+        let position = new_position();
+        let other_position = new_position();
+        let mut cache = PositionsCache::with_capacity(10);
+
+        cache.add(position.clone());
+        cache.add(other_position.clone());
+        let first = cache.get(position.get_id()).expect("must exist");
+        let second = cache.get(other_position.get_id()).expect("must exist");
+
+        assert_eq!(first.get_id(), position.get_id());
+        assert_eq!(second.get_id(), other_position.get_id());
+    }
+
     #[test]
     fn positions_cache_get_by_wallet() {
         let position = new_position();
@@ -238,27 +387,86 @@ mod tests {
         assert_eq!(positions.len(), limit);
     }
 
+    #[test]
+    fn positions_cache_get_by_wallet_sorted() {
+        let wallet_id: WalletId = Uuid::new_v4().into();
+        let mut cache = PositionsCache::with_capacity(10);
+
+        for _ in 0..10 {
+            cache.add(new_position_with_wallet(&wallet_id));
+        }
+
+        let ids: Vec<_> = cache
+            .get_by_wallet_id(&wallet_id, 10)
+            .iter()
+            .map(|position| position.get_id().clone())
+            .collect();
+        let mut sorted_ids = ids.clone();
+        sorted_ids.sort();
+        let limited_ids: Vec<_> = cache
+            .get_by_wallet_id(&wallet_id, 3)
+            .iter()
+            .map(|position| position.get_id().clone())
+            .collect();
+
+        assert_eq!(sorted_ids, ids);
+        assert_eq!(ids[..3], limited_ids[..]);
+    }
+
+    #[test]
+    fn positions_cache_add_duplicate_id_on_other_wallet() {
+        let first_wallet_id: WalletId = Uuid::new_v4().into();
+        let second_wallet_id: WalletId = Uuid::new_v4().into();
+        let mut cache = PositionsCache::with_capacity(2);
+        let first_position = new_position_with_wallet(&first_wallet_id);
+        let id = first_position.get_id().clone();
+        let Position::Active(mut second_position) = new_position_with_wallet(&second_wallet_id) else {
+            panic!("Must be active position");
+        };
+        second_position.id = id.clone();
+
+        cache.add(first_position);
+        cache.add(Position::Active(second_position));
+
+        assert_eq!(1, cache.count());
+        assert!(!cache.contains_by_wallet_id(&first_wallet_id));
+        assert!(cache.get_by_wallet_id(&first_wallet_id, 10).is_empty());
+        assert_eq!(&id, cache.get_by_wallet_id(&second_wallet_id, 10)[0].get_id());
+    }
+
+    #[test]
+    fn positions_cache_get_by_wallet_paged() {
+        let wallet_id: WalletId = Uuid::new_v4().into();
+        let mut cache = PositionsCache::with_capacity(10);
+
+        for _ in 0..10 {
+            cache.add(new_position_with_wallet(&wallet_id));
+        }
+
+        let all_ids: Vec<_> = cache
+            .get_by_wallet_id(&wallet_id, usize::MAX)
+            .iter()
+            .map(|position| position.get_id().clone())
+            .collect();
+        let mut paged_ids = Vec::new();
+
+        for offset in (0..12).step_by(4) {
+            let page = cache.get_by_wallet_paged(&wallet_id, offset, 4);
+            paged_ids.extend(page.iter().map(|position| position.get_id().clone()));
+        }
+
+        assert_eq!(all_ids, paged_ids);
+        assert!(cache.get_by_wallet_paged(&wallet_id, 10, 4).is_empty());
+    }
+
     fn new_position() -> Position {
         let mut invest_assets = SortedVec::new();
         invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "BTC".into()});
         let order = Order {
-            base_asset: "USDT".into(),
-            id: "test".to_string(),
-            instrument: "ATOMUSDT".into(),
-            trader_id: "test".to_string(),
-            wallet_id: Uuid::new_v4().into(),
-            created_date: DateTimeAsMicroseconds::now(),
-            desire_price: None,
-            funding_fee_period: None,
             invest_assets,
-            leverage: 1.0,
-            side: crate::orders::OrderSide::Buy,
-            take_profit: None,
-            stop_loss: None,
             stop_out_percent: 10.0,
             margin_call_percent: 10.0,
-            top_up_enabled: false,
-            top_up_percent: 10.0,
+            ..new_test_order()
         };
         let mut prices = SortedVec::new();
         prices.insert_or_replace(AssetPrice {price: 22300.0, symbol: "BTC".into()});
@@ -269,30 +477,18 @@ mod tests {
             instrument: "ATOMUSDT".into(),
         };
 
-        order.open(&bidask, &prices)
+        order.open(&bidask, &prices, None).unwrap()
     }
 
     fn new_position_with_wallet(wallet_id: &WalletId) -> Position {
         let mut invest_assets = SortedVec::new();
         invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "BTC".into()});
         let order = Order {
-            base_asset: "USDT".into(),
-            id: "test".to_string(),
-            instrument: "ATOMUSDT".into(),
-            trader_id: "test".to_string(),
-            wallet_id: wallet_id.to_owned(),
-            created_date: DateTimeAsMicroseconds::now(),
-            desire_price: None,
-            funding_fee_period: None,
             invest_assets,
-            leverage: 1.0,
-            side: crate::orders::OrderSide::Buy,
-            take_profit: None,
-            stop_loss: None,
+            wallet_id: wallet_id.to_owned(),
             stop_out_percent: 10.0,
             margin_call_percent: 10.0,
-            top_up_enabled: false,
-            top_up_percent: 10.0,
+            ..new_test_order()
         };
         let mut prices = SortedVec::new();
         prices.insert_or_replace(AssetPrice {price: 22300.0, symbol: "BTC".into()});
@@ -303,6 +499,6 @@ mod tests {
             instrument: "ATOMUSDT".into(),
         };
 
-        order.open(&bidask, &prices)
+        order.open(&bidask, &prices, None).unwrap()
     }
 }
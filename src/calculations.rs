@@ -1,15 +1,18 @@
-use crate::{orders::OrderSide, positions::BidAsk};
-use std::collections::HashMap;
+use crate::orders::OrderSide;
+use crate::caches::PriceSource;
 use rust_extensions::sorted_vec::SortedVec;
 use crate::asset_symbol::AssetSymbol;
 use crate::assets::{AssetAmount, AssetPrice};
+use crate::errors::TradingError;
+use crate::instrument_symbol::InstrumentSymbol;
+use crate::units::{Percent, Price, Volume};
 
 pub fn get_close_price(
-    bidasks: &HashMap<String, BidAsk>,
-    instrument: &str,
+    prices: &dyn PriceSource,
+    instrument: &InstrumentSymbol,
     side: &OrderSide,
 ) -> f64 {
-    let bidask = bidasks
+    let bidask = prices
         .get(instrument)
         .unwrap_or_else(|| panic!("BidAsk not found for {}", instrument));
 
@@ -17,25 +20,30 @@ pub fn get_close_price(
 }
 
 pub fn get_open_price(
-    bidasks: &HashMap<String, BidAsk>,
-    instrument: &str,
+    prices: &dyn PriceSource,
+    instrument: &InstrumentSymbol,
     side: &OrderSide,
 ) -> f64 {
-    let bidask = bidasks
+    let bidask = prices
         .get(instrument)
         .unwrap_or_else(|| panic!("BidAsk not found for {}", instrument));
 
     bidask.get_open_price(side)
 }
 
-pub fn calculate_margin_percent(invest_amount: f64, pnl: f64) -> f64 {
+pub fn calculate_margin_percent(invest_amount: f64, pnl: f64) -> Percent {
     let margin = pnl + invest_amount;
 
-    margin / invest_amount * 100.0
+    Percent::from_rate(margin / invest_amount)
 }
 
-pub fn calculate_percent(from_number: f64, number: f64) -> f64 {
-    number / from_number * 100.0
+pub fn calculate_percent(from_number: f64, number: f64) -> Percent {
+    Percent::from_rate(number / from_number)
+}
+
+/// Calculates value of one pip move in base asset
+pub fn pip_value(volume: Volume, pip_size: Price, quote_to_base_rate: Price) -> f64 {
+    volume * pip_size * quote_to_base_rate.value()
 }
 
 pub fn calculate_total_amount(
@@ -48,6 +56,11 @@ pub fn calculate_total_amount(
         let price = asset_prices
             .get(&item.symbol)
             .unwrap_or_else(|| panic!("Price not found for {}", item.symbol));
+        debug_assert!(
+            price.price.is_finite() && item.amount.is_finite(),
+            "Non-finite amount or price of {}",
+            item.symbol
+        );
         let estimated_amount = price.price * item.amount;
         total_amount += estimated_amount;
     }
@@ -55,6 +68,56 @@ pub fn calculate_total_amount(
     total_amount
 }
 
+/// Same as calculate_total_amount but returns error instead of panic if price is missing
+/// or amount or price isn't finite
+pub fn calculate_total_amount_checked(
+    asset_amounts: &SortedVec<AssetSymbol, AssetAmount>,
+    asset_prices: &SortedVec<AssetSymbol, AssetPrice>,
+) -> Result<f64, TradingError> {
+    let mut total_amount = 0.0;
+
+    for item in asset_amounts.iter() {
+        let Some(price) = asset_prices.get(&item.symbol) else {
+            return Err(TradingError::PriceNotFound(item.symbol.clone()));
+        };
+
+        if !price.price.is_finite() || !item.amount.is_finite() {
+            return Err(TradingError::NonFinite(item.symbol.clone()));
+        }
+
+        total_amount += price.price * item.amount;
+    }
+
+    Ok(total_amount)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoundingMode {
+    Floor,
+    Ceil,
+    Round,
+}
+
+impl RoundingMode {
+    pub fn apply(&self, x: f64, precision: u32) -> f64 {
+        match self {
+            RoundingMode::Floor => floor(x, precision),
+            RoundingMode::Ceil => ceil(x, precision),
+            RoundingMode::Round => round(x, precision),
+        }
+    }
+}
+
+pub fn round_amounts(
+    asset_amounts: &mut SortedVec<AssetSymbol, AssetAmount>,
+    precision: u32,
+    mode: RoundingMode,
+) {
+    for item in asset_amounts.iter_mut() {
+        item.round_in_place(precision, mode);
+    }
+}
+
 pub fn ceil(x: f64, precision: u32) -> f64 {
     let y = 10_i64.pow(precision) as f64;
     (x * y).ceil() / y
@@ -69,3 +132,82 @@ pub fn round(x: f64, precision: u32) -> f64 {
     let y = 10_i64.pow(precision) as f64;
     (x * y).round() / y
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::positions::BidAsk;
+
+    struct MockPriceSource {
+        bidask: BidAsk,
+    }
+
+    impl PriceSource for MockPriceSource {
+        fn get(&self, instrument: &InstrumentSymbol) -> Option<BidAsk> {
+            if instrument == &self.bidask.instrument {
+                Some(self.bidask.clone())
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn get_prices_from_price_source() {
+        let source = MockPriceSource {
+            bidask: BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.1),
+        };
+        let instrument: InstrumentSymbol = "ATOMUSDT".into();
+
+        assert_eq!(10.1, get_open_price(&source, &instrument, &OrderSide::Buy));
+        assert_eq!(10.0, get_close_price(&source, &instrument, &OrderSide::Buy));
+    }
+
+    #[test]
+    fn calculate_total_amount_checked_with_missing_price() {
+        let mut amounts = SortedVec::new();
+        amounts.insert_or_replace(AssetAmount {amount: 1.0, symbol: "BTC".into()});
+        let prices = SortedVec::new();
+
+        let result = calculate_total_amount_checked(&amounts, &prices);
+
+        assert_eq!(Err(TradingError::PriceNotFound("BTC".into())), result);
+    }
+
+    #[test]
+    fn calculate_total_amount_checked_with_non_finite() {
+        let mut amounts = SortedVec::new();
+        amounts.insert_or_replace(AssetAmount {amount: 1.0, symbol: "BTC".into()});
+        amounts.insert_or_replace(AssetAmount {amount: f64::INFINITY, symbol: "ETH".into()});
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: f64::NAN, symbol: "BTC".into()});
+        prices.insert_or_replace(AssetPrice {price: 1500.0, symbol: "ETH".into()});
+
+        let nan_result = calculate_total_amount_checked(&amounts, &prices);
+        prices.insert_or_replace(AssetPrice {price: 20000.0, symbol: "BTC".into()});
+        let infinity_result = calculate_total_amount_checked(&amounts, &prices);
+
+        assert_eq!(Err(TradingError::NonFinite("BTC".into())), nan_result);
+        assert_eq!(Err(TradingError::NonFinite("ETH".into())), infinity_result);
+    }
+
+    #[test]
+    fn percents() {
+        assert_eq!(Percent::new(5.0), calculate_percent(200.0, 10.0));
+        assert_eq!(Percent::new(90.0), calculate_margin_percent(100.0, -10.0));
+    }
+
+    #[test]
+    fn pip_value_standard() {
+        let value = pip_value(Volume::new(100_000.0), Price::new(0.0001), Price::new(1.0));
+
+        assert_eq!(10.0, round(value, 8));
+    }
+
+    #[test]
+    fn pip_value_jpy() {
+        let value = pip_value(Volume::new(100_000.0), Price::new(0.01), Price::new(1.0 / 150.0));
+
+        assert_eq!(6.66666667, round(value, 8));
+    }
+}
@@ -0,0 +1,133 @@
+use crate::asset_symbol::AssetSymbol;
+use crate::instrument_symbol::InstrumentSymbol;
+use crate::position_id::PositionId;
+use std::fmt::Display;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TradingError {
+    /// Spread of instrument is wider than allowed
+    SpreadTooWide {
+        spread_percent: f64,
+        max_spread_percent: f64,
+    },
+    /// Bid is higher than ask
+    CrossedBook { bid: f64, ask: f64 },
+    /// Price of asset is required but not provided
+    PriceNotFound(AssetSymbol),
+    /// Wallet has no balance of asset
+    BalanceNotFound(AssetSymbol),
+    /// Stop loss would be triggered immediately at current price
+    InvalidStopLoss { value: f64, current_price: f64 },
+    /// Take profit would be triggered immediately at current price
+    InvalidTakeProfit { value: f64, current_price: f64 },
+    /// Order type doesn't match its desire price
+    InvalidOrderType(String),
+    /// Quote has invalid bid or ask
+    InvalidQuote(String),
+    /// Invest amount of asset is zero or negative
+    InvalidInvestAmount(AssetSymbol),
+    /// Invest amount of asset is less than minimum
+    InvestBelowMinimum(AssetSymbol),
+    /// Invest amount of asset is more than maximum
+    InvestAboveMaximum(AssetSymbol),
+    /// Position already has maximum count of top-ups
+    TopUpsLimitReached(u32),
+    /// Leverage is not positive or exceeds max leverage
+    InvalidLeverage(f64),
+    /// Position isn't found in cache
+    PositionNotFound(PositionId),
+    /// Operation requires pending position
+    PositionNotPending(PositionId),
+    /// Pending position can't be activated before desire price is reached
+    DesirePriceNotReached(PositionId),
+    /// Pending position can't be activated without invest assets
+    NoInvestAssets(PositionId),
+    /// Prices of assets are required but not provided
+    PricesNotFound(Vec<AssetSymbol>),
+    /// Margin call percent must be positive and less than stop out percent, which is at most 100
+    InvalidRiskLimits { stop_out_percent: f64, margin_call_percent: f64 },
+    /// Amount or price of asset is NaN or infinite
+    NonFinite(AssetSymbol),
+    /// Fraction must be greater than 0 and less than 1
+    InvalidFraction(f64),
+    /// Desire price is not positive or not finite
+    InvalidDesirePrice(f64),
+    /// Quote is for other instrument than order
+    InstrumentMismatch { expected: InstrumentSymbol, actual: InstrumentSymbol },
+}
+
+impl Display for TradingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TradingError::SpreadTooWide {
+                spread_percent,
+                max_spread_percent,
+            } => write!(
+                f,
+                "Spread {}% is wider than max {}%",
+                spread_percent, max_spread_percent
+            ),
+            TradingError::CrossedBook { bid, ask } => {
+                write!(f, "Crossed book: bid {} is higher than ask {}", bid, ask)
+            }
+            TradingError::PriceNotFound(symbol) => write!(f, "Price not found for {}", symbol),
+            TradingError::BalanceNotFound(symbol) => write!(f, "Balance not found for {}", symbol),
+            TradingError::InvalidStopLoss { value, current_price } => write!(
+                f,
+                "Stop loss {} is already triggered at price {}",
+                value, current_price
+            ),
+            TradingError::InvalidTakeProfit { value, current_price } => write!(
+                f,
+                "Take profit {} is already triggered at price {}",
+                value, current_price
+            ),
+            TradingError::InvalidOrderType(message) => write!(f, "Invalid order type: {}", message),
+            TradingError::InvalidQuote(message) => write!(f, "Invalid quote: {}", message),
+            TradingError::InvalidInvestAmount(symbol) => {
+                write!(f, "Invest amount of {} must be positive", symbol)
+            }
+            TradingError::InvestBelowMinimum(symbol) => {
+                write!(f, "Invest amount of {} is below minimum", symbol)
+            }
+            TradingError::InvestAboveMaximum(symbol) => {
+                write!(f, "Invest amount of {} is above maximum", symbol)
+            }
+            TradingError::TopUpsLimitReached(max_top_ups) => {
+                write!(f, "Top-ups limit {} is reached", max_top_ups)
+            }
+            TradingError::InvalidLeverage(leverage) => write!(f, "Invalid leverage {}", leverage),
+            TradingError::PositionNotFound(id) => write!(f, "Position {} not found", id),
+            TradingError::PositionNotPending(id) => write!(f, "Position {} is not pending", id),
+            TradingError::DesirePriceNotReached(id) => {
+                write!(f, "Desire price of position {} is not reached", id)
+            }
+            TradingError::NoInvestAssets(id) => {
+                write!(f, "Position {} has no invest assets", id)
+            }
+            TradingError::PricesNotFound(symbols) => {
+                let symbols: Vec<&str> = symbols.iter().map(|symbol| &**symbol).collect();
+                write!(f, "Prices not found for {}", symbols.join(", "))
+            }
+            TradingError::InvalidRiskLimits {
+                stop_out_percent,
+                margin_call_percent,
+            } => write!(
+                f,
+                "Invalid risk limits: stop out {}%, margin call {}%",
+                stop_out_percent, margin_call_percent
+            ),
+            TradingError::NonFinite(symbol) => {
+                write!(f, "Amount or price of {} is not finite", symbol)
+            }
+            TradingError::InvalidFraction(fraction) => write!(f, "Invalid fraction {}", fraction),
+            TradingError::InvalidDesirePrice(price) => write!(f, "Invalid desire price {}", price),
+            TradingError::InstrumentMismatch { expected, actual } => {
+                write!(f, "Quote of {} can't be used for {}", actual, expected)
+            }
+        }
+    }
+}
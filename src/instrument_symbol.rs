@@ -1,9 +1,17 @@
+use std::borrow::Borrow;
+use std::convert::Infallible;
 use std::fmt::Display;
 use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::Arc;
+use ahash::AHashMap;
 use compact_str::CompactString;
 use rust_extensions::sorted_vec::EntityWithKey;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct InstrumentSymbol(pub CompactString);
 
 impl Deref for InstrumentSymbol {
@@ -44,6 +52,13 @@ impl From<&String> for InstrumentSymbol {
     }
 }
 
+impl FromStr for InstrumentSymbol {
+    type Err = Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(value.into())
+    }
+}
 
 impl Display for InstrumentSymbol {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -51,8 +66,91 @@ impl Display for InstrumentSymbol {
     }
 }
 
+impl Borrow<str> for InstrumentSymbol {
+    fn borrow(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
 impl EntityWithKey<InstrumentSymbol> for InstrumentSymbol {
     fn get_key(&self) -> &InstrumentSymbol {
         self
     }
-}
\ No newline at end of file
+}
+
+/// Hands out shared instances of instrument symbols, so equal symbols are stored once
+#[derive(Default, Debug)]
+pub struct Interner {
+    items: AHashMap<InstrumentSymbol, Arc<InstrumentSymbol>>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn intern(&mut self, symbol: &str) -> Arc<InstrumentSymbol> {
+        if let Some(item) = self.items.get(symbol) {
+            return item.clone();
+        }
+
+        let item = Arc::new(InstrumentSymbol::from(symbol));
+        self.items.insert(symbol.into(), item.clone());
+
+        item
+    }
+
+    /// Interns symbol of instrument made of base and quote assets, short symbols are joined on
+    /// stack, so only symbols seen for the first time are allocated
+    pub fn intern_pair(&mut self, base_asset: &str, quote_asset: &str) -> Arc<InstrumentSymbol> {
+        let mut symbol = CompactString::with_capacity(base_asset.len() + quote_asset.len());
+        symbol.push_str(base_asset);
+        symbol.push_str(quote_asset);
+
+        self.intern(&symbol)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InstrumentSymbol, Interner};
+    use std::sync::Arc;
+
+    #[test]
+    fn intern_returns_same_instance() {
+        let mut interner = Interner::new();
+
+        let symbols: Vec<_> = (0..1000).map(|_| interner.intern("ATOMUSDT")).collect();
+        let other_symbol = interner.intern("BTCUSDT");
+
+        assert!(symbols.iter().all(|symbol| Arc::ptr_eq(symbol, &symbols[0])));
+        assert!(!Arc::ptr_eq(&symbols[0], &other_symbol));
+        assert_eq!(2, interner.len());
+    }
+
+    #[test]
+    fn intern_pair_returns_same_instance() {
+        let mut interner = Interner::new();
+
+        let symbol = interner.intern("ATOMUSDT");
+        let pair_symbol = interner.intern_pair("ATOM", "USDT");
+
+        assert!(Arc::ptr_eq(&symbol, &pair_symbol));
+        assert_eq!(1, interner.len());
+    }
+
+    #[test]
+    fn parse() {
+        let symbol = "BTCUSDT".parse::<InstrumentSymbol>();
+
+        assert_eq!(Ok(InstrumentSymbol::from("BTCUSDT")), symbol);
+    }
+}
@@ -2,15 +2,25 @@ pub mod positions;
 pub mod orders;
 pub mod caches;
 pub mod calculations;
+pub mod units;
 pub mod monitoring;
+pub mod predicates;
 pub mod top_ups;
 pub mod wallets;
 pub mod instrument_symbol;
 pub mod position_id;
 pub mod asset_symbol;
 pub mod wallet_id;
+pub mod order_id;
+pub mod trader_id;
+pub mod balance_id;
 pub mod assets;
 pub mod sharding;
+pub mod statistics;
+pub mod backtest;
+#[cfg(feature = "serde")]
+mod serialization;
+pub mod errors;
 
 pub use ahash::AHashMap;
 
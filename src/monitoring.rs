@@ -1,33 +1,41 @@
 use crate::asset_symbol::AssetSymbol;
 use crate::assets::AssetAmount;
-use crate::instrument_symbol::InstrumentSymbol;
+use crate::calculations::calculate_total_amount_checked;
+use crate::errors::TradingError;
+use crate::instrument_symbol::{InstrumentSymbol, Interner};
+use crate::orders::Order;
 use crate::position_id::PositionId;
 use crate::positions::PendingPosition;
 use crate::top_ups::{ActiveTopUp, CanceledTopUp};
+use crate::trader_id::TraderId;
 use crate::wallet_id::WalletId;
 use crate::wallets::{Wallet, WalletBalance};
 use crate::{
-    caches::PositionsCache,
-    positions::{ActivePosition, BidAsk, ClosedPosition, Position},
+    caches::{BidAsksCache, PositionsCache},
+    positions::{ActivePosition, BidAsk, ClosePositionReason, ClosedPosition, Position},
 };
 use ahash::{AHashMap, AHashSet};
+use rust_extensions::date_time::DateTimeAsMicroseconds;
 use rust_extensions::sorted_vec::{EntityWithKey, SortedVec};
+use std::sync::Arc;
 use std::time::Duration;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 pub struct PositionIdsByInstrumentSymbol {
     pub items: AHashSet<PositionId>,
-    instrument_symbol: InstrumentSymbol,
+    instrument_symbol: Arc<InstrumentSymbol>,
 }
 
 impl PositionIdsByInstrumentSymbol {
-    pub fn new(instrument_symbol: InstrumentSymbol) -> Self {
+    pub fn new(instrument_symbol: Arc<InstrumentSymbol>) -> Self {
         PositionIdsByInstrumentSymbol {
             items: Default::default(),
             instrument_symbol,
         }
     }
 
-    pub fn new_with_one(instrument_symbol: InstrumentSymbol, id: PositionId) -> Self {
+    pub fn new_with_one(instrument_symbol: Arc<InstrumentSymbol>, id: PositionId) -> Self {
         PositionIdsByInstrumentSymbol {
             items: AHashSet::from([id]),
             instrument_symbol,
@@ -47,18 +55,18 @@ impl EntityWithKey<InstrumentSymbol> for PositionIdsByInstrumentSymbol {
 
 pub struct WalletIdsByInstrumentSymbol {
     pub items: AHashSet<WalletId>,
-    instrument_symbol: InstrumentSymbol,
+    instrument_symbol: Arc<InstrumentSymbol>,
 }
 
 impl WalletIdsByInstrumentSymbol {
-    pub fn new(instrument_symbol: InstrumentSymbol) -> Self {
+    pub fn new(instrument_symbol: Arc<InstrumentSymbol>) -> Self {
         WalletIdsByInstrumentSymbol {
             items: Default::default(),
             instrument_symbol,
         }
     }
 
-    pub fn new_with_one(instrument_symbol: InstrumentSymbol, id: WalletId) -> Self {
+    pub fn new_with_one(instrument_symbol: Arc<InstrumentSymbol>, id: WalletId) -> Self {
         WalletIdsByInstrumentSymbol {
             items: AHashSet::from([id]),
             instrument_symbol,
@@ -82,11 +90,18 @@ pub struct PositionsMonitor {
     cancel_top_up_delay: Duration,
     cancel_top_up_price_change_percent: f64,
     locked_ids: SortedVec<PositionId, PositionId>,
+    lock_kinds_by_ids: AHashMap<PositionId, PositionLockKind>,
+    last_bidasks_by_instruments: AHashMap<InstrumentSymbol, BidAsk>,
     pnl_accuracy: Option<u32>,
+    pnl_accuracy_by_instruments: AHashMap<InstrumentSymbol, u32>,
+    ids_by_trader_ids: AHashMap<TraderId, AHashSet<PositionId>>,
     wallets_by_ids: AHashMap<WalletId, Wallet>,
     wallet_ids_by_instruments: SortedVec<InstrumentSymbol, WalletIdsByInstrumentSymbol>,
+    /// Shared instrument symbols of index buckets
+    instrument_interner: Interner,
     wallet_monitoring_enabled: bool,
     last_update_events_count: usize,
+    event_sink: Option<Box<dyn FnMut(PositionMonitoringEvent) + Send>>,
     // reused allocations
     top_up_pnls_by_wallet_ids: AHashMap<WalletId, f64>,
     top_up_reserved_by_wallet_ids: AHashMap<WalletId, SortedVec<AssetSymbol, AssetAmount>>,
@@ -109,13 +124,19 @@ impl PositionsMonitor {
             ids_by_instruments: SortedVec::new_with_capacity(instruments_count),
             cancel_top_up_delay,
             locked_ids: SortedVec::new_with_capacity(capacity / 1000),
+            lock_kinds_by_ids: AHashMap::with_capacity(capacity / 1000),
+            last_bidasks_by_instruments: AHashMap::with_capacity(instruments_count),
             cancel_top_up_price_change_percent,
             pnl_accuracy,
+            pnl_accuracy_by_instruments: AHashMap::new(),
+            ids_by_trader_ids: AHashMap::with_capacity(wallet_ids_count),
             wallet_ids_by_instruments: SortedVec::new_with_capacity(instruments_count),
+            instrument_interner: Interner::new(),
             top_up_pnls_by_wallet_ids: AHashMap::with_capacity(wallet_ids_count),
             top_up_reserved_by_wallet_ids: AHashMap::with_capacity(wallet_ids_count),
             wallet_monitoring_enabled,
             last_update_events_count: 0,
+            event_sink: None,
         }
     }
 
@@ -123,6 +144,191 @@ impl PositionsMonitor {
         self.positions_cache.count()
     }
 
+    /// Closes active unlocked positions of order wallet on the same instrument with opposite side
+    /// to the extent of order volume, used by netting accounts before adding new order. Oldest
+    /// positions are offset first and last one can be closed partially. Returns empty vec if there
+    /// are no opposing positions. Result is returned instead of Option, so caller can tell that
+    /// nothing was offset from order invest assets that can't be valued, in that case nothing is
+    /// closed
+    pub fn offset_opposing(
+        &mut self,
+        new_order: &Order,
+    ) -> Result<Vec<ClosedPosition>, TradingError> {
+        let mut opposing_positions: Vec<(PositionId, i64)> = self
+            .positions_cache
+            .get_by_wallet_id(&new_order.wallet_id, usize::MAX)
+            .into_iter()
+            .filter_map(|position| match position {
+                Position::Active(position)
+                    if position.order.instrument == new_order.instrument
+                        && position.order.side != new_order.side
+                        && !self.locked_ids.contains(&position.id) =>
+                {
+                    Some((position.id.clone(), position.activate_date.unix_microseconds))
+                }
+                _ => None,
+            })
+            .collect();
+        opposing_positions.sort_by_key(|(_, activate_date)| *activate_date);
+        let opposing_ids: Vec<PositionId> =
+            opposing_positions.into_iter().map(|(id, _)| id).collect();
+        let Some(first_id) = opposing_ids.first() else {
+            return Ok(Vec::new());
+        };
+        let Some(Position::Active(first_position)) = self.positions_cache.get(first_id) else {
+            panic!("Position is in Active case");
+        };
+        let invest_amount = calculate_total_amount_checked(
+            &new_order.invest_assets,
+            &first_position.current_asset_prices,
+        )?;
+        let mut remaining_volume = new_order.calculate_volume(invest_amount);
+        let pnl_accuracy = resolve_pnl_accuracy(
+            &self.pnl_accuracy_by_instruments,
+            self.pnl_accuracy,
+            &new_order.instrument,
+        );
+        let mut closed_positions = Vec::with_capacity(opposing_ids.len());
+        let mut partial_close = None;
+
+        for id in opposing_ids.iter() {
+            if remaining_volume <= 0.0 {
+                break;
+            }
+
+            let Some(Position::Active(position)) = self.positions_cache.get(id) else {
+                panic!("Position is in Active case");
+            };
+            let volume = position.current_volume();
+            let closed_position = self.close_cached(id, ClosePositionReason::Offset)?;
+
+            if volume > remaining_volume {
+                partial_close = Some((id, remaining_volume / volume));
+                break;
+            }
+
+            remaining_volume -= volume;
+            closed_positions.push(closed_position);
+        }
+
+        for closed_position in closed_positions.iter() {
+            self.remove(&closed_position.id);
+        }
+
+        if let Some((id, fraction)) = partial_close {
+            let Some(Position::Active(position)) = self.positions_cache.get_mut(id) else {
+                panic!("Position is in Active case");
+            };
+            closed_positions.push(position.close_part(
+                fraction,
+                ClosePositionReason::Offset,
+                pnl_accuracy,
+            ));
+        }
+
+        Ok(closed_positions)
+    }
+
+    /// Closes unlocked active positions whose instrument wasn't quoted longer than max_age.
+    /// Position that can't be closed stays in cache and is reported to event sink with
+    /// PositionCloseFailed
+    pub fn close_stale(
+        &mut self,
+        max_age: Duration,
+        now: DateTimeAsMicroseconds,
+    ) -> Vec<ClosedPosition> {
+        let min_update_date = now.sub(max_age);
+        let stale_ids: Vec<PositionId> = self
+            .positions_cache
+            .iter()
+            .filter_map(|position| match position {
+                Position::Active(position)
+                    if min_update_date.is_later_than(position.last_update_date)
+                        && !self.locked_ids.contains(&position.id) =>
+                {
+                    Some(position.id.clone())
+                }
+                _ => None,
+            })
+            .collect();
+        let mut closed_positions = Vec::with_capacity(stale_ids.len());
+
+        for id in stale_ids.iter() {
+            match self.close_cached(id, ClosePositionReason::Stale) {
+                Ok(closed_position) => {
+                    self.remove(id);
+                    closed_positions.push(closed_position);
+                }
+                Err(err) => {
+                    let Some(Position::Active(position)) = self.positions_cache.get(id) else {
+                        panic!("Position is in Active case");
+                    };
+                    let event = PositionMonitoringEvent::PositionCloseFailed((position.clone(), err));
+
+                    if let Some(sink) = self.event_sink.as_mut() {
+                        sink(event);
+                    }
+                }
+            }
+        }
+
+        closed_positions
+    }
+
+    /// Closes copy of cached active position, so cache stays unchanged if close fails
+    fn close_cached(
+        &self,
+        position_id: &PositionId,
+        reason: ClosePositionReason,
+    ) -> Result<ClosedPosition, TradingError> {
+        let Some(Position::Active(position)) = self.positions_cache.get(position_id) else {
+            panic!("Position is in Active case");
+        };
+        let pnl_accuracy = self.get_pnl_accuracy(&position.order.instrument);
+
+        position.clone().close_checked(reason, pnl_accuracy)
+    }
+
+    /// Returns true if unlocked balance of position wallet covers required top-up amount.
+    /// Wallets not added to monitor aren't checked
+    pub fn can_afford_top_up(&self, position_id: &PositionId, required: f64) -> bool {
+        let Some(Position::Active(position)) = self.positions_cache.get(position_id) else {
+            return false;
+        };
+
+        is_top_up_affordable(&self.wallets_by_ids, position, required)
+    }
+
+    pub fn metrics(&self) -> MonitorMetrics {
+        let mut metrics = MonitorMetrics {
+            total_positions: self.positions_cache.count(),
+            locked_count: self.locked_ids.len(),
+            wallet_count: self.wallets_by_ids.len(),
+            instrument_count: self.ids_by_instruments.len(),
+            ..Default::default()
+        };
+
+        for position in self.positions_cache.iter() {
+            match position {
+                Position::Active(_) => metrics.active_count += 1,
+                Position::Pending(_) => metrics.pending_count += 1,
+                Position::Closed(_) => {}
+            }
+        }
+
+        metrics
+    }
+
+    /// Overrides default pnl accuracy for instrument
+    pub fn set_pnl_accuracy(&mut self, instrument: InstrumentSymbol, pnl_accuracy: u32) {
+        self.pnl_accuracy_by_instruments.insert(instrument, pnl_accuracy);
+    }
+
+    /// Returns pnl accuracy of instrument or default one
+    pub fn get_pnl_accuracy(&self, instrument: &InstrumentSymbol) -> Option<u32> {
+        resolve_pnl_accuracy(&self.pnl_accuracy_by_instruments, self.pnl_accuracy, instrument)
+    }
+
     pub fn get_wallet_mut(&mut self, wallet_id: &WalletId) -> Option<&mut Wallet> {
         let wallet = self.wallets_by_ids.get_mut(wallet_id);
 
@@ -168,11 +374,21 @@ impl PositionsMonitor {
                 Position::Pending(_) => {}
             }
 
-            for instrument in position.get_instruments() {
+            for instrument in position.get_interned_instruments(&mut self.instrument_interner) {
                 if let Some(ids) = self.ids_by_instruments.get_mut(&instrument) {
                     ids.items.remove(position.get_id());
+
+                    if ids.items.is_empty() {
+                        self.ids_by_instruments.remove(&instrument);
+                    }
                 }
             }
+
+            remove_trader_position_id(
+                &mut self.ids_by_trader_ids,
+                &position.get_order().trader_id,
+                position.get_id(),
+            );
         }
 
         position
@@ -187,6 +403,10 @@ impl PositionsMonitor {
 
                 if let Some(wallet_ids) = wallet_ids {
                     wallet_ids.items.remove(wallet_id);
+
+                    if wallet_ids.items.is_empty() {
+                        self.wallet_ids_by_instruments.remove(instrument);
+                    }
                 }
             }
 
@@ -205,7 +425,7 @@ impl PositionsMonitor {
             } else {
                 self.wallet_ids_by_instruments.insert_or_replace(
                     WalletIdsByInstrumentSymbol::new_with_one(
-                        instrument.clone(),
+                        self.instrument_interner.intern(instrument),
                         wallet.id.clone(),
                     ),
                 );
@@ -215,6 +435,22 @@ impl PositionsMonitor {
         self.wallets_by_ids.insert(wallet.id.clone(), wallet);
     }
 
+    /// Updates prices of all wallet balances by quotes from cache, use it after restore before
+    /// instruments start to tick
+    pub fn prime_wallet_prices(&mut self, cache: &BidAsksCache) {
+        for wallet_ids in self.wallet_ids_by_instruments.iter() {
+            let Some(bidask) = cache.get(&wallet_ids.instrument_symbol) else {
+                continue;
+            };
+
+            for wallet_id in wallet_ids.items.iter() {
+                if let Some(wallet) = self.wallets_by_ids.get_mut(wallet_id) {
+                    wallet.update_price(bidask);
+                }
+            }
+        }
+    }
+
     pub fn update_wallet(
         &mut self,
         wallet_id: &WalletId,
@@ -233,7 +469,7 @@ impl PositionsMonitor {
 
     pub fn add(&mut self, position: Position) {
         let id = position.get_id().to_owned();
-        let instruments = position.get_instruments();
+        let instruments = position.get_interned_instruments(&mut self.instrument_interner);
 
         for invest_instrument in instruments {
             if let Some(ids) = self.ids_by_instruments.get_mut(&invest_instrument) {
@@ -245,15 +481,275 @@ impl PositionsMonitor {
             }
         }
 
+        if let Some(ids) = self.ids_by_trader_ids.get_mut(&position.get_order().trader_id) {
+            ids.insert(id);
+        } else {
+            self.ids_by_trader_ids
+                .insert(position.get_order().trader_id.clone(), AHashSet::from([id]));
+        }
+
         self.positions_cache.add(position);
     }
 
+    /// Clears and repopulates instrument and trader indexes from positions and wallets
+    pub fn rebuild_indexes(&mut self) {
+        let instruments_count = self.ids_by_instruments.len();
+        let wallet_instruments_count = self.wallet_ids_by_instruments.len();
+        self.ids_by_instruments = SortedVec::new_with_capacity(instruments_count);
+        self.wallet_ids_by_instruments = SortedVec::new_with_capacity(wallet_instruments_count);
+        self.ids_by_trader_ids.clear();
+
+        for position in self.positions_cache.iter() {
+            let id = position.get_id();
+
+            for instrument in position.get_interned_instruments(&mut self.instrument_interner) {
+                if let Some(ids) = self.ids_by_instruments.get_mut(&instrument) {
+                    ids.items.insert(id.clone());
+                } else {
+                    self.ids_by_instruments.insert_or_replace(
+                        PositionIdsByInstrumentSymbol::new_with_one(instrument, id.clone()),
+                    );
+                }
+            }
+
+            self.ids_by_trader_ids
+                .entry(position.get_order().trader_id.clone())
+                .or_default()
+                .insert(id.clone());
+        }
+
+        for wallet in self.wallets_by_ids.values() {
+            for instrument in wallet.get_instruments() {
+                if let Some(wallet_ids) = self.wallet_ids_by_instruments.get_mut(instrument) {
+                    wallet_ids.items.insert(wallet.id.clone());
+                } else {
+                    self.wallet_ids_by_instruments.insert_or_replace(
+                        WalletIdsByInstrumentSymbol::new_with_one(
+                            self.instrument_interner.intern(instrument),
+                            wallet.id.clone(),
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Calculates instrument price at which wallet reaches margin call, see
+    /// Wallet::margin_call_price
+    pub fn wallet_margin_call_price(
+        &self,
+        wallet_id: &WalletId,
+        instrument: &InstrumentSymbol,
+    ) -> Option<f64> {
+        let wallet = self.wallets_by_ids.get(wallet_id)?;
+        let positions = self
+            .positions_cache
+            .get_by_wallet_id(wallet_id, usize::MAX)
+            .into_iter()
+            .filter_map(|position| match position {
+                Position::Active(position) => Some(position),
+                _ => None,
+            });
+
+        wallet.margin_call_price(instrument, positions)
+    }
+
+    /// Returns positions matching predicate, see predicates module for prebuilt ones
+    pub fn query(&self, predicate: impl Fn(&Position) -> bool) -> Vec<&Position> {
+        self.positions_cache
+            .iter()
+            .filter(|position| predicate(position))
+            .collect()
+    }
+
+    /// Returns positions of all trader wallets
+    pub fn get_by_trader(&self, trader_id: &str) -> Vec<&Position> {
+        let Some(ids) = self.ids_by_trader_ids.get(trader_id) else {
+            return Vec::with_capacity(0);
+        };
+
+        ids.iter()
+            .filter_map(|id| self.positions_cache.get(id))
+            .collect()
+    }
+
     pub fn get_by_wallet_id(&self, wallet_id: &WalletId, limit: usize) -> Vec<&Position> {
         self.positions_cache.get_by_wallet_id(wallet_id, limit)
     }
 
+    pub fn wallet_of(&self, id: &PositionId) -> Option<WalletId> {
+        self.positions_cache
+            .get(id)
+            .map(|position| position.get_order().wallet_id.clone())
+    }
+
+    pub fn trader_of(&self, id: &PositionId) -> Option<TraderId> {
+        self.positions_cache
+            .get(id)
+            .map(|position| position.get_order().trader_id.clone())
+    }
+
+    /// Returns page of wallet positions ordered by position id
+    pub fn get_by_wallet_paged(
+        &self,
+        wallet_id: &WalletId,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<&Position> {
+        self.positions_cache.get_by_wallet_paged(wallet_id, offset, limit)
+    }
+
+    /// Sums current pnl of all active positions of the wallet
+    pub fn wallet_pnl(&self, wallet_id: &WalletId) -> f64 {
+        self.get_by_wallet_id(wallet_id, usize::MAX)
+            .into_iter()
+            .filter_map(|position| match position {
+                Position::Active(position) => Some(position.current_pnl),
+                _ => None,
+            })
+            .sum()
+    }
+
+    /// Sums pnl by invested assets of all active positions of the wallet
+    pub fn wallet_pnl_by_asset(&self, wallet_id: &WalletId) -> SortedVec<AssetSymbol, AssetAmount> {
+        let mut pnls: SortedVec<AssetSymbol, AssetAmount> = SortedVec::new();
+
+        for position in self.get_by_wallet_id(wallet_id, usize::MAX) {
+            let Position::Active(position) = position else {
+                continue;
+            };
+
+            AssetAmount::accumulate_into(&mut pnls, position.calc_pnls_by_assets(None).iter());
+        }
+
+        pnls
+    }
+
+    pub fn get_by_instrument(&self, instrument: &InstrumentSymbol) -> Vec<&Position> {
+        let Some(ids) = self.ids_by_instruments.get(instrument) else {
+            return Vec::with_capacity(0);
+        };
+
+        ids.items
+            .iter()
+            .filter_map(|id| self.positions_cache.get(id))
+            .collect()
+    }
+
     pub fn unlock(&mut self, position_id: &PositionId) {
         self.locked_ids.remove(position_id);
+        self.lock_kinds_by_ids.remove(position_id);
+    }
+
+    pub fn locked_ids(&self) -> Vec<PositionId> {
+        self.locked_ids.iter().cloned().collect()
+    }
+
+    pub fn is_locked(&self, position_id: &PositionId) -> bool {
+        self.locked_ids.contains(position_id)
+    }
+
+    /// Returns why position was locked by the last update
+    pub fn get_lock_kind(&self, position_id: &PositionId) -> Option<PositionLockKind> {
+        self.lock_kinds_by_ids.get(position_id).copied()
+    }
+
+    /// Unlocks position and checks active position against the last known price of its instrument
+    pub fn unlock_and_evaluate(&mut self, position_id: &PositionId) -> Vec<PositionMonitoringEvent> {
+        self.unlock(position_id);
+        let mut events = Vec::with_capacity(2);
+
+        let Some(Position::Active(position)) = self.positions_cache.get_mut(position_id) else {
+            return events;
+        };
+
+        if let Some(bidask) = self.last_bidasks_by_instruments.get(&position.order.instrument) {
+            position.update(bidask);
+        }
+
+        if position.is_margin_call() {
+            events.push(PositionMonitoringEvent::PositionMarginCall(position.clone()));
+        }
+
+        let Some(reason) = position.determine_close_reason() else {
+            return events;
+        };
+
+        match self.close_cached(position_id, reason) {
+            Ok(closed_position) => {
+                self.remove(position_id);
+                events.push(PositionMonitoringEvent::PositionClosed(closed_position));
+            }
+            Err(err) => {
+                let Some(Position::Active(position)) = self.positions_cache.get(position_id) else {
+                    panic!("Position is in Active case");
+                };
+                events.push(PositionMonitoringEvent::PositionCloseFailed((position.clone(), err)));
+            }
+        }
+
+        events
+    }
+
+    /// Removes pending position even if it's locked and closes it as canceled. Total invest
+    /// assets of closed position are released reserved assets to credit back to wallet
+    pub fn cancel_pending(
+        &mut self,
+        position_id: &PositionId,
+    ) -> Result<ClosedPosition, TradingError> {
+        match self.positions_cache.get(position_id) {
+            Some(Position::Pending(_)) => {}
+            Some(_) => return Err(TradingError::PositionNotPending(position_id.clone())),
+            None => return Err(TradingError::PositionNotFound(position_id.clone())),
+        }
+
+        self.unlock(position_id);
+
+        let Some(Position::Pending(position)) = self.remove(position_id) else {
+            panic!("Position is in Pending case");
+        };
+
+        Ok(position.close(ClosePositionReason::Canceled))
+    }
+
+    /// Adds externally reserved assets to pending position which reached desire price,
+    /// activates and unlocks it
+    pub fn reserve_and_activate(
+        &mut self,
+        position_id: &PositionId,
+        reserved: SortedVec<AssetSymbol, AssetAmount>,
+    ) -> Result<PositionMonitoringEvent, TradingError> {
+        let Some(position) = self.positions_cache.get_mut(position_id) else {
+            return Err(TradingError::PositionNotFound(position_id.clone()));
+        };
+
+        let Position::Pending(position) = position else {
+            return Err(TradingError::PositionNotPending(position_id.clone()));
+        };
+
+        if !position.is_price_reached() {
+            return Err(TradingError::DesirePriceNotReached(position_id.clone()));
+        }
+
+        if position.total_invest_assets.is_empty() && reserved.is_empty() {
+            return Err(TradingError::NoInvestAssets(position_id.clone()));
+        }
+
+        position.add_invest_assets(&reserved)?;
+
+        let Some(Position::Pending(position)) = self.positions_cache.remove(position_id) else {
+            panic!("Position is in Pending case");
+        };
+        let mut position = position.activate().expect("checked by is_price_reached and invest assets");
+
+        if let Some(bidask) = self.last_bidasks_by_instruments.get(&position.order.instrument) {
+            position.update(bidask);
+        }
+
+        self.add(Position::Active(position.clone()));
+        self.unlock(position_id);
+
+        Ok(PositionMonitoringEvent::PositionActivated(position))
     }
 
     pub fn add_top_up(
@@ -268,15 +764,18 @@ impl PositionsMonitor {
         };
 
         match position {
-            Position::Active(position) => {
-                position.add_top_up(top_up);
-                Ok(())
-            }
+            Position::Active(position) => position
+                .add_top_up(top_up)
+                .map_err(|err| err.to_string()),
             Position::Closed(_) => Err("Can't add top-up to closed position ".to_string()),
             Position::Pending(_) => Err("Can't add top-up to pending position".to_string()),
         }
     }
 
+    pub fn get(&self, id: &PositionId) -> Option<&Position> {
+        self.positions_cache.get(id)
+    }
+
     pub fn get_mut(&mut self, id: &PositionId) -> Option<&mut Position> {
         self.positions_cache.get_mut(id)
     }
@@ -286,14 +785,59 @@ impl PositionsMonitor {
         self.top_up_reserved_by_wallet_ids.clear();
     }
 
+    /// Sets sink invoked by update_with_sink for each event and by close_stale for close failures
+    pub fn set_event_sink(
+        &mut self,
+        sink: Box<dyn FnMut(PositionMonitoringEvent) + Send>,
+    ) {
+        self.event_sink = Some(sink);
+    }
+
     pub fn update(&mut self, bidask: &BidAsk) -> Vec<PositionMonitoringEvent> {
+        let mut events = Vec::with_capacity(self.last_update_events_count / 4 + 10);
+        self.update_inner(bidask, &mut |event| events.push(event));
+
+        events
+    }
+
+    /// Passes events to the event sink instead of collecting them. Returns count of events.
+    /// Sink must be set with set_event_sink before, otherwise events are dropped
+    pub fn update_with_sink(&mut self, bidask: &BidAsk) -> usize {
+        debug_assert!(self.event_sink.is_some(), "Event sink is not set");
+
+        let Some(mut sink) = self.event_sink.take() else {
+            return self.update_inner(bidask, &mut |_| {});
+        };
+
+        let events_count = self.update_inner(bidask, &mut sink);
+        self.event_sink = Some(sink);
+
+        events_count
+    }
+
+    fn update_inner(
+        &mut self,
+        bidask: &BidAsk,
+        sink: &mut dyn FnMut(PositionMonitoringEvent),
+    ) -> usize {
+        if let Some(last_bidask) = self.last_bidasks_by_instruments.get_mut(&bidask.instrument) {
+            *last_bidask = bidask.clone();
+        } else {
+            self.last_bidasks_by_instruments
+                .insert(bidask.instrument.clone(), bidask.clone());
+        }
+
         let position_ids = self.ids_by_instruments.get_mut(&bidask.instrument);
 
         let Some(position_ids) = position_ids else {
-            return Vec::with_capacity(0);
+            return 0;
         };
 
-        let mut events = Vec::with_capacity(self.last_update_events_count / 4 + 10);
+        let mut events_count = 0;
+        let mut emit = |event: PositionMonitoringEvent| {
+            events_count += 1;
+            sink(event);
+        };
         let wallet_ids_to_remove_count = if self.wallet_monitoring_enabled { self.wallets_by_ids.len() / 1000 + 10 } else { 0 };
         let mut wallet_ids_to_remove = Vec::with_capacity(wallet_ids_to_remove_count);
 
@@ -316,7 +860,12 @@ impl PositionsMonitor {
                         Position::Closed(position) => position,
                         _ => panic!("Checked"),
                     };
-                    events.push(PositionMonitoringEvent::PositionClosed(position));
+                    remove_trader_position_id(
+                        &mut self.ids_by_trader_ids,
+                        &position.order.trader_id,
+                        position_id,
+                    );
+                    emit(PositionMonitoringEvent::PositionClosed(position));
 
                     false // remove closed position
                 }
@@ -324,7 +873,7 @@ impl PositionsMonitor {
                     position.update(bidask);
 
                     if position.is_price_reached() {
-                        if position.can_activate() {
+                        if position.can_fill() {
                             let position =
                                 match self.positions_cache.remove(position_id).expect("Checked") {
                                     Position::Pending(position) => position,
@@ -333,14 +882,30 @@ impl PositionsMonitor {
                             let mut position =
                                 position.activate().expect("checked by can_activate");
                             position.update(bidask);
-                            events
-                                .push(PositionMonitoringEvent::PositionActivated(position.clone()));
+                            emit(PositionMonitoringEvent::PositionActivated(position.clone()));
                             self.positions_cache.add(Position::Active(position));
+                        } else if position.order.time_in_force.is_immediate() {
+                            let position =
+                                match self.positions_cache.remove(position_id).expect("Checked") {
+                                    Position::Pending(position) => position,
+                                    _ => panic!("Checked"),
+                                };
+                            remove_trader_position_id(
+                                &mut self.ids_by_trader_ids,
+                                &position.order.trader_id,
+                                position_id,
+                            );
+                            let position = position.close(ClosePositionReason::Canceled);
+                            emit(PositionMonitoringEvent::PositionClosed(position));
+
+                            return false; // remove canceled position
                         } else {
                             self.locked_ids.insert_or_replace(position.id.clone());
+                            self.lock_kinds_by_ids
+                                .insert(position.id.clone(), PositionLockKind::ActivationPending);
                             let lock_reason =
                                 PositionLockReason::ActivationPending(position.clone());
-                            events.push(PositionMonitoringEvent::PositionLocked(lock_reason));
+                            emit(PositionMonitoringEvent::PositionLocked(lock_reason));
                         }
                     }
 
@@ -350,17 +915,30 @@ impl PositionsMonitor {
                     position.update(bidask);
 
                     if position.is_margin_call() {
-                        events.push(PositionMonitoringEvent::PositionMarginCall(
+                        emit(PositionMonitoringEvent::PositionMarginCall(
                             position.clone(),
                         ));
                     }
 
-                    if position.is_top_up() {
+                    let wallet_balance = self
+                        .wallets_by_ids
+                        .get(&position.order.wallet_id)
+                        .and_then(|wallet| unlocked_balance_in_base_asset(wallet, position))
+                        .unwrap_or(0.0);
+                    let required_top_up_amount =
+                        position.try_calculate_required_top_up_amount(wallet_balance);
+                    let is_top_up_unaffordable = required_top_up_amount.is_some_and(|amount| {
+                        !is_top_up_affordable(&self.wallets_by_ids, position, amount)
+                    });
+
+                    if required_top_up_amount.is_some() && !is_top_up_unaffordable {
                         self.locked_ids.insert_or_replace(position.id.clone());
+                        self.lock_kinds_by_ids
+                            .insert(position.id.clone(), PositionLockKind::TopUp);
                         let event = PositionMonitoringEvent::PositionLocked(
                             PositionLockReason::TopUp(position.to_owned()),
                         );
-                        events.push(event);
+                        emit(event);
                     } else {
                         let canceled_top_ups = position.try_cancel_top_ups(
                             self.cancel_top_up_price_change_percent,
@@ -369,25 +947,54 @@ impl PositionsMonitor {
 
                         if !canceled_top_ups.is_empty() {
                             self.locked_ids.insert_or_replace(position.id.clone());
+                            self.lock_kinds_by_ids
+                                .insert(position.id.clone(), PositionLockKind::TopUpsCanceled);
                             let reason = PositionLockReason::TopUpsCanceled((
                                 position.to_owned(),
                                 canceled_top_ups,
                             ));
                             let event = PositionMonitoringEvent::PositionLocked(reason);
-                            events.push(event);
+                            emit(event);
                         }
                     }
 
-                    if let Some(reason) = position.determine_close_reason() {
-                        let position = match self
-                            .positions_cache
-                            .remove(position_id)
-                            .expect("Must exists")
-                        {
-                            Position::Active(position) => position,
-                            _ => panic!("Position is in Active case"),
-                        };
-                        let position = position.close(reason, self.pnl_accuracy);
+                    let close_reason = if is_top_up_unaffordable {
+                        // top-up isn't possible so position is checked for stop-out
+                        position.determine_close_reason_ignoring_top_up().map(|reason| match reason {
+                            ClosePositionReason::StopOut => ClosePositionReason::InsufficientBalance,
+                            reason => reason,
+                        })
+                    } else {
+                        position.determine_close_reason()
+                    };
+
+                    let closed_position = close_reason.and_then(|reason| {
+                        let pnl_accuracy = resolve_pnl_accuracy(
+                            &self.pnl_accuracy_by_instruments,
+                            self.pnl_accuracy,
+                            &position.order.instrument,
+                        );
+
+                        match position.clone().close_checked(reason, pnl_accuracy) {
+                            Ok(closed_position) => Some(closed_position),
+                            Err(err) => {
+                                // position stays monitored and is closed on next update
+                                emit(PositionMonitoringEvent::PositionCloseFailed((
+                                    position.to_owned(),
+                                    err,
+                                )));
+                                None
+                            }
+                        }
+                    });
+
+                    if let Some(position) = closed_position {
+                        self.positions_cache.remove(position_id).expect("Must exists");
+                        remove_trader_position_id(
+                            &mut self.ids_by_trader_ids,
+                            &position.order.trader_id,
+                            position_id,
+                        );
 
                         if self.wallet_monitoring_enabled && self
                             .positions_cache
@@ -396,7 +1003,7 @@ impl PositionsMonitor {
                             wallet_ids_to_remove.push(position.order.wallet_id.clone());
                         }
 
-                        events.push(PositionMonitoringEvent::PositionClosed(position));
+                        emit(PositionMonitoringEvent::PositionClosed(position));
 
                         false // remove closed position
                     } else {
@@ -418,18 +1025,10 @@ impl PositionsMonitor {
                                 .get_mut(&position.order.wallet_id);
 
                             if let Some(reserved_by_assets) = reserved_by_assets {
-                                for item in position.total_invest_assets.iter() {
-                                    let reserved_amount = reserved_by_assets.get_mut(&item.symbol);
-
-                                    if let Some(reserved_amount) = reserved_amount {
-                                        reserved_amount.amount += item.amount;
-                                    } else {
-                                        reserved_by_assets.insert_or_replace(AssetAmount {
-                                            amount: item.amount,
-                                            symbol: item.symbol.clone(),
-                                        });
-                                    }
-                                }
+                                AssetAmount::accumulate_into(
+                                    reserved_by_assets,
+                                    position.total_invest_assets.iter(),
+                                );
                             } else {
                                 self.top_up_reserved_by_wallet_ids.insert(
                                     position.order.wallet_id.clone(),
@@ -444,22 +1043,24 @@ impl PositionsMonitor {
             }
         });
 
+        if position_ids.items.is_empty() {
+            self.ids_by_instruments.remove(&bidask.instrument);
+        }
+
         if self.wallet_monitoring_enabled {
             for wallet_id in wallet_ids_to_remove {
                 self.remove_wallet(&wallet_id);
             }
 
             self.update_wallet_prices(bidask);
-            self.update_wallet_reserved(bidask);
-            for event in self.update_wallet_pnls(bidask) {
-                events.push(event);
-            }
+            self.update_wallet_reserved(bidask, &mut emit);
+            self.update_wallet_pnls(bidask, &mut emit);
         }
         
         self.clear_reused_allocations();
-        self.last_update_events_count = events.len();
+        self.last_update_events_count = events_count;
 
-        events
+        events_count
     }
 
     fn update_wallet_prices(&mut self, bidask: &BidAsk) {
@@ -476,7 +1077,11 @@ impl PositionsMonitor {
         }
     }
 
-    fn update_wallet_reserved(&mut self, bidask: &BidAsk) {
+    fn update_wallet_reserved(
+        &mut self,
+        bidask: &BidAsk,
+        emit: &mut impl FnMut(PositionMonitoringEvent),
+    ) {
         for (wallet_id, reserved_by_assets) in &self.top_up_reserved_by_wallet_ids {
             let wallet = self.wallets_by_ids.get_mut(wallet_id);
 
@@ -484,13 +1089,18 @@ impl PositionsMonitor {
                 continue;
             };
 
-            wallet.set_top_up_reserved(&bidask.instrument, reserved_by_assets);
+            // previous reserved amount is kept if price of any reserved asset is missing
+            if let Err(err) = wallet.set_top_up_reserved(&bidask.instrument, reserved_by_assets) {
+                emit(PositionMonitoringEvent::WalletTopUpReservedFailed((wallet_id.clone(), err)));
+            }
         }
     }
 
-    fn update_wallet_pnls(&mut self, bidask: &BidAsk) -> Vec<PositionMonitoringEvent> {
-        let mut events = Vec::new();
-
+    fn update_wallet_pnls(
+        &mut self,
+        bidask: &BidAsk,
+        emit: &mut impl FnMut(PositionMonitoringEvent),
+    ) {
         for (wallet_id, pnl) in self.top_up_pnls_by_wallet_ids.iter() {
             let wallet = self.wallets_by_ids.get_mut(&wallet_id);
 
@@ -502,7 +1112,7 @@ impl PositionsMonitor {
             wallet.update_loss();
 
             if wallet.is_margin_call() {
-                events.push(PositionMonitoringEvent::WalletMarginCall(
+                emit(PositionMonitoringEvent::WalletMarginCall(
                     WalletMarginCallInfo {
                         loss_percent: wallet.current_loss_percent,
                         pnl: *pnl,
@@ -512,11 +1122,70 @@ impl PositionsMonitor {
                 ));
             }
         }
+    }
+}
 
-        events
+fn remove_trader_position_id(
+    ids_by_trader_ids: &mut AHashMap<TraderId, AHashSet<PositionId>>,
+    trader_id: &str,
+    position_id: &PositionId,
+) {
+    if let Some(ids) = ids_by_trader_ids.get_mut(trader_id) {
+        ids.remove(position_id);
+
+        if ids.is_empty() {
+            ids_by_trader_ids.remove(trader_id);
+        }
+    }
+}
+
+/// Checks required top-up amount in base asset against unlocked wallet balance converted to
+/// base asset. Top-up of position whose wallet isn't monitored or whose wallet balance can't be
+/// converted is considered affordable
+fn is_top_up_affordable(
+    wallets_by_ids: &AHashMap<WalletId, Wallet>,
+    position: &ActivePosition,
+    required: f64,
+) -> bool {
+    let Some(wallet) = wallets_by_ids.get(&position.order.wallet_id) else {
+        return true;
+    };
+
+    let Some(balance) = unlocked_balance_in_base_asset(wallet, position) else {
+        return true;
+    };
+
+    balance >= required
+}
+
+/// Converts unlocked wallet balance from wallet estimate asset to position base asset by
+/// current asset prices of position. Returns None if estimate asset price is unknown
+fn unlocked_balance_in_base_asset(wallet: &Wallet, position: &ActivePosition) -> Option<f64> {
+    let estimate_asset = wallet.get_estimate_asset();
+
+    if estimate_asset == &position.order.base_asset {
+        return Some(wallet.total_unlocked_balance);
     }
+
+    position
+        .current_asset_prices
+        .get(estimate_asset)
+        .map(|price| wallet.total_unlocked_balance * price.price)
 }
 
+fn resolve_pnl_accuracy(
+    pnl_accuracy_by_instruments: &AHashMap<InstrumentSymbol, u32>,
+    default_pnl_accuracy: Option<u32>,
+    instrument: &InstrumentSymbol,
+) -> Option<u32> {
+    pnl_accuracy_by_instruments
+        .get(instrument)
+        .copied()
+        .or(default_pnl_accuracy)
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", content = "data"))]
 pub enum PositionMonitoringEvent {
     /// Active position was closed due to stop-out and removed from cache
     PositionClosed(ClosedPosition),
@@ -529,8 +1198,14 @@ pub enum PositionMonitoringEvent {
     PositionLocked(PositionLockReason),
     /// Wallet has margin call
     WalletMarginCall(WalletMarginCallInfo),
+    /// Active position has close reason but can't be closed, it stays in cache
+    PositionCloseFailed((ActivePosition, TradingError)),
+    /// Top-up reserved balance of wallet wasn't updated, previous amount is kept
+    WalletTopUpReservedFailed((WalletId, TradingError)),
 }
 
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", content = "data"))]
 pub enum PositionLockReason {
     /// Active position needs to add a top-up
     TopUp(ActivePosition),
@@ -540,13 +1215,895 @@ pub enum PositionLockReason {
     ActivationPending(PendingPosition),
 }
 
+impl PositionLockReason {
+    pub fn kind(&self) -> PositionLockKind {
+        match self {
+            PositionLockReason::TopUp(_) => PositionLockKind::TopUp,
+            PositionLockReason::TopUpsCanceled(_) => PositionLockKind::TopUpsCanceled,
+            PositionLockReason::ActivationPending(_) => PositionLockKind::ActivationPending,
+        }
+    }
+}
+
+/// Lock reason without position data
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PositionLockKind {
+    TopUp,
+    TopUpsCanceled,
+    ActivationPending,
+}
+
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct WalletMarginCallInfo {
     pub loss_percent: f64,
     pub pnl: f64,
     pub wallet_id: WalletId,
-    pub trader_id: String,
+    pub trader_id: TraderId,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MonitorMetrics {
+    pub total_positions: usize,
+    pub active_count: usize,
+    pub pending_count: usize,
+    pub locked_count: usize,
+    pub wallet_count: usize,
+    /// Count of instruments with positions, including invest asset instruments
+    pub instrument_count: usize,
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::{
+        PositionIdsByInstrumentSymbol, PositionLockKind, PositionMonitoringEvent, PositionsMonitor,
+    };
+    use crate::asset_symbol::AssetSymbol;
+    use crate::assets::{AssetAmount, AssetPrice};
+    use crate::errors::TradingError;
+    use crate::orders::{new_test_order, Order, OrderSide, OrderType, TimeInForce, TopUpSizing};
+    use crate::positions::{BidAsk, ClosePositionReason, Position, PositionStatus};
+    use crate::caches::BidAsksCache;
+    use crate::calculations::round;
+    use crate::predicates::{by_instrument, by_side, loss_above};
+    use crate::wallets::{Wallet, WalletBalance};
+    use rust_extensions::date_time::DateTimeAsMicroseconds;
+    use rust_extensions::sorted_vec::SortedVec;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    #[test]
+    fn update_with_sink_on_stop_out() {
+        let mut monitor = new_monitor();
+        let closed_count = Arc::new(AtomicUsize::new(0));
+        let sink_closed_count = closed_count.clone();
+        monitor.set_event_sink(Box::new(move |event| {
+            if let PositionMonitoringEvent::PositionClosed(_) = event {
+                sink_closed_count.fetch_add(1, Ordering::SeqCst);
+            }
+        }));
+        monitor.add(new_position(10.0));
+
+        let events_count =
+            monitor.update_with_sink(&BidAsk::new_synthetic("ATOMUSDT".into(), 8.0, 8.0));
+
+        assert_eq!(2, events_count); // margin call and close
+        assert_eq!(1, closed_count.load(Ordering::SeqCst));
+        assert_eq!(0, monitor.count());
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "Event sink is not set")]
+    fn update_with_sink_without_sink() {
+        let mut monitor = new_monitor();
+        monitor.add(new_position(10.0));
+
+        monitor.update_with_sink(&BidAsk::new_synthetic("ATOMUSDT".into(), 8.0, 8.0));
+    }
+
+    fn new_monitor() -> PositionsMonitor {
+        PositionsMonitor::new(100, Duration::from_secs(1), 10.0, None, false)
+    }
+
+    fn new_prices() -> SortedVec<AssetSymbol, AssetPrice> {
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+
+        prices
+    }
+
+    #[test]
+    fn close_with_pnl_accuracy_by_instrument() {
+        let mut monitor = PositionsMonitor::new(100, Duration::from_secs(1), 10.0, Some(4), false);
+        monitor.set_pnl_accuracy("ATOMUSDT".into(), 1);
+        monitor.add(new_position_on("ATOMUSDT", 10.0));
+        monitor.add(new_position_on("BTCUSDT", 10.0));
+        let mut events = monitor.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 8.7777, 8.7777));
+        events.extend(monitor.update(&BidAsk::new_synthetic("BTCUSDT".into(), 8.7777, 8.7777)));
+
+        let mut pnls = events.iter().filter_map(|event| match event {
+            PositionMonitoringEvent::PositionClosed(position) => {
+                Some((position.order.instrument.to_string(), round(position.pnl.unwrap(), 8)))
+            }
+            _ => None,
+        });
+
+        assert_eq!(Some(("ATOMUSDT".to_string(), -122.3)), pnls.next());
+        assert_eq!(Some(("BTCUSDT".to_string(), -122.2301)), pnls.next());
+    }
+
+    #[test]
+    fn index_buckets_share_interned_instrument() {
+        let mut monitor = PositionsMonitor::new(100, Duration::from_secs(1), 10.0, None, true);
+        let position = new_position(10.0);
+        let wallet_id = position.get_order().wallet_id.clone();
+        let mut wallet = Wallet::new(wallet_id, "test", "USDT".into(), 50.0);
+        let balance = WalletBalance {
+            id: "1".into(),
+            instrument_symbol: "ATOMUSDT".into(),
+            asset_symbol: "ATOM".into(),
+            asset_amount: 2.0,
+            is_locked: false,
+        };
+        wallet.add_balance(balance, &BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0)).unwrap();
+        monitor.add_wallet(wallet);
+        monitor.add(position);
+
+        let position_bucket = monitor.ids_by_instruments.get(&"ATOMUSDT".into()).unwrap();
+        let wallet_bucket = monitor.wallet_ids_by_instruments.get(&"ATOMUSDT".into()).unwrap();
+
+        assert!(Arc::ptr_eq(&position_bucket.instrument_symbol, &wallet_bucket.instrument_symbol));
+    }
+
+    #[test]
+    fn top_up_reserved_without_price() {
+        let mut monitor = PositionsMonitor::new(100, Duration::from_secs(1), 10.0, None, true);
+        let mut order = new_order("ATOMUSDT", 10.0);
+        order.top_up_enabled = true;
+        let position = order.open(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0), &new_prices(), None).unwrap();
+        let wallet_id = position.get_order().wallet_id.clone();
+        let mut wallet = Wallet::new(wallet_id.clone(), "test", "BTC".into(), 50.0);
+        let balance = WalletBalance {
+            id: "1".into(),
+            instrument_symbol: "ATOMBTC".into(),
+            asset_symbol: "ATOM".into(),
+            asset_amount: 2.0,
+            is_locked: false,
+        };
+        wallet.add_balance(balance, &BidAsk::new_synthetic("ATOMBTC".into(), 0.001, 0.001)).unwrap();
+        monitor.add_wallet(wallet);
+        monitor.add(position);
+
+        let events = monitor.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0));
+
+        assert!(events.iter().any(|event| matches!(
+            event,
+            PositionMonitoringEvent::WalletTopUpReservedFailed((id, TradingError::PricesNotFound(_)))
+                if *id == wallet_id
+        )));
+    }
+
+    #[test]
+    fn rebuild_indexes() {
+        let mut monitor = new_monitor();
+        monitor.add(new_position(10.0));
+        monitor.ids_by_instruments.insert_or_replace(
+            PositionIdsByInstrumentSymbol::new_with_one(
+                Arc::new("FAKEUSDT".into()),
+                Position::generate_id(),
+            ),
+        );
+
+        monitor.rebuild_indexes();
+
+        assert!(monitor.ids_by_instruments.get(&"FAKEUSDT".into()).is_none());
+        assert_eq!(1, monitor.get_by_instrument(&"ATOMUSDT".into()).len());
+    }
+
+    #[test]
+    fn get_by_trader() {
+        let mut monitor = new_monitor();
+        let mut first_wallet_order = new_order("ATOMUSDT", 10.0);
+        first_wallet_order.trader_id = "trader".into();
+        let mut second_wallet_order = new_order("BTCUSDT", 10.0);
+        second_wallet_order.trader_id = "trader".into();
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+        let first_position = first_wallet_order.open(&bidask, &new_prices(), None).unwrap();
+        let first_id = first_position.get_id().clone();
+        monitor.add(first_position);
+        let second_bidask = BidAsk::new_synthetic("BTCUSDT".into(), 20000.0, 20000.0);
+        monitor.add(second_wallet_order.open(&second_bidask, &new_prices(), None).unwrap());
+        monitor.add(new_position(10.0));
+
+        assert_eq!(2, monitor.get_by_trader("trader").len());
+
+        monitor.remove(&first_id);
+
+        assert_eq!(1, monitor.get_by_trader("trader").len());
+    }
+
+    #[test]
+    fn wallet_pnl() {
+        let mut monitor = new_monitor();
+        let first_order = new_order("ATOMUSDT", 10.0);
+        let wallet_id = first_order.wallet_id.clone();
+        let mut second_order = new_order("ATOMUSDT", 10.0);
+        second_order.wallet_id = wallet_id.clone();
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+        monitor.add(first_order.open(&bidask, &new_prices(), None).unwrap());
+        monitor.add(second_order.open(&bidask, &new_prices(), None).unwrap());
+        monitor.add(new_position(10.0));
+
+        monitor.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 12.5, 12.5));
+
+        assert_eq!(500.0, monitor.wallet_pnl(&wallet_id));
+        let pnls = monitor.wallet_pnl_by_asset(&wallet_id);
+        assert_eq!(1, pnls.len());
+        assert_eq!(500.0, pnls.get(&"USDT".into()).unwrap().amount);
+    }
+
+    #[test]
+    fn query() {
+        let mut monitor = new_monitor();
+        let losing_buy = new_position_on("BTCUSDT", 10.0);
+        let losing_buy_id = losing_buy.get_id().clone();
+        monitor.add(losing_buy);
+        monitor.add(new_position_on("BTCUSDT", 1.0));
+        monitor.add(new_position_on("ATOMUSDT", 10.0));
+        let mut sell_order = new_order("BTCUSDT", 10.0);
+        sell_order.side = OrderSide::Sell;
+        monitor.add(sell_order.open(&BidAsk::new_synthetic("BTCUSDT".into(), 10.0, 10.0), &new_prices(), None).unwrap());
+        monitor.update(&BidAsk::new_synthetic("BTCUSDT".into(), 9.4, 9.4));
+        let is_buy = by_side(OrderSide::Buy);
+        let is_btc = by_instrument("BTCUSDT".into());
+        let is_losing = loss_above(50.0);
+
+        let positions = monitor.query(|position| is_buy(position) && is_btc(position) && is_losing(position));
+
+        assert_eq!(1, positions.len());
+        assert_eq!(&losing_buy_id, positions[0].get_id());
+        assert_eq!(3, monitor.query(&is_btc).len());
+    }
+
+    #[test]
+    fn offset_opposing() {
+        let mut monitor = new_monitor();
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+        let buy_order = new_order("ATOMUSDT", 10.0);
+        let wallet_id = buy_order.wallet_id.clone();
+        let buy_position = buy_order.open(&bidask, &new_prices(), None).unwrap();
+        let buy_id = buy_position.get_id().clone();
+        monitor.add(buy_position);
+        let mut sell_order = new_order("ATOMUSDT", 10.0);
+        sell_order.wallet_id = wallet_id.clone();
+        sell_order.side = OrderSide::Sell;
+        let mut other_instrument_order = sell_order.clone();
+        other_instrument_order.instrument = "BTCUSDT".into();
+
+        let not_opposing = monitor.offset_opposing(&other_instrument_order).unwrap();
+        let closed_positions = monitor.offset_opposing(&sell_order).unwrap();
+
+        assert!(not_opposing.is_empty());
+        assert_eq!(1, closed_positions.len());
+        assert_eq!(buy_id, closed_positions[0].id);
+        assert!(matches!(closed_positions[0].close_reason, ClosePositionReason::Offset));
+        assert_eq!(100.0, closed_positions[0].total_invest_assets.get(&"USDT".into()).unwrap().amount);
+        assert!(monitor.get_by_wallet_id(&wallet_id, 10).is_empty());
+    }
+
+    #[test]
+    fn offset_opposing_partially() {
+        let mut monitor = new_monitor();
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+        let buy_order = new_order("ATOMUSDT", 10.0);
+        let wallet_id = buy_order.wallet_id.clone();
+        monitor.add(buy_order.open(&bidask, &new_prices(), None).unwrap());
+        let mut sell_order = new_order("ATOMUSDT", 4.0);
+        sell_order.wallet_id = wallet_id.clone();
+        sell_order.side = OrderSide::Sell;
+
+        let closed_positions = monitor.offset_opposing(&sell_order).unwrap();
+
+        let Position::Active(remaining_position) = monitor.get_by_wallet_id(&wallet_id, 10)[0] else {
+            panic!("Must be active position");
+        };
+        assert_eq!(1, closed_positions.len());
+        assert_eq!(40.0, round(closed_positions[0].total_invest_assets.get(&"USDT".into()).unwrap().amount, 8));
+        assert_eq!(600.0, round(remaining_position.current_volume(), 8));
+    }
+
+    #[test]
+    fn offset_opposing_oldest_first() {
+        let mut monitor = new_monitor();
+        let buy_order = new_order("ATOMUSDT", 10.0);
+        let wallet_id = buy_order.wallet_id.clone();
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+        let ids: Vec<_> = (0..3).map(|_| Position::generate_id()).collect();
+
+        for (id, seconds) in ids.iter().zip([30, 10, 20]) {
+            let open_date = DateTimeAsMicroseconds::new(seconds * 1_000_000);
+            let position = buy_order.clone().open_at(id.clone(), &bidask, &new_prices(), open_date);
+            monitor.add(position);
+        }
+
+        let mut sell_order = new_order("ATOMUSDT", 10.0);
+        sell_order.wallet_id = wallet_id.clone();
+        sell_order.side = OrderSide::Sell;
+
+        let closed_positions = monitor.offset_opposing(&sell_order).unwrap();
+
+        assert_eq!(1, closed_positions.len());
+        assert_eq!(ids[1], closed_positions[0].id);
+        assert_eq!(2, monitor.get_by_wallet_id(&wallet_id, 10).len());
+    }
+
+    #[test]
+    fn offset_opposing_without_price() {
+        let mut monitor = new_monitor();
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+        let buy_order = new_order("ATOMUSDT", 10.0);
+        let wallet_id = buy_order.wallet_id.clone();
+        monitor.add(buy_order.open(&bidask, &new_prices(), None).unwrap());
+        let mut sell_order = new_order("ATOMUSDT", 10.0);
+        sell_order.wallet_id = wallet_id.clone();
+        sell_order.side = OrderSide::Sell;
+        sell_order.invest_assets.insert_or_replace(AssetAmount {amount: 1.0, symbol: "BTC".into()});
+
+        let result = monitor.offset_opposing(&sell_order);
+
+        assert_eq!(Err(TradingError::PriceNotFound("BTC".into())), result.map(|_| ()));
+        assert_eq!(1, monitor.get_by_wallet_id(&wallet_id, 10).len());
+    }
+
+    #[test]
+    fn prime_wallet_prices() {
+        let mut monitor = new_monitor();
+        let mut wallet = Wallet::new(Uuid::new_v4().into(), "test", "USDT".into(), 50.0);
+        let balance = WalletBalance {
+            id: "1".into(),
+            instrument_symbol: "BTCUSDT".into(),
+            asset_symbol: "BTC".into(),
+            asset_amount: 2.0,
+            is_locked: false,
+        };
+        wallet.add_balance(balance, &BidAsk::new_synthetic("BTCUSDT".into(), 20000.0, 20000.0)).unwrap();
+        let wallet_id = wallet.id.clone();
+        monitor.add_wallet(wallet);
+        let cache = BidAsksCache::new(vec![
+            BidAsk::new_synthetic("BTCUSDT".into(), 21000.0, 21010.0),
+            BidAsk::new_synthetic("ETHUSDT".into(), 1500.0, 1500.0),
+        ]);
+
+        monitor.prime_wallet_prices(&cache);
+
+        let wallet = monitor.wallets_by_ids.get(&wallet_id).unwrap();
+        assert_eq!(2.0 * 21010.0, wallet.total_unlocked_balance);
+    }
+
+    #[test]
+    fn wallet_and_trader_of_position() {
+        let mut monitor = new_monitor();
+        let position = new_position(10.0);
+        let id = position.get_id().clone();
+        let wallet_id = position.get_order().wallet_id.clone();
+        let trader_id = position.get_order().trader_id.clone();
+        monitor.add(position);
+
+        assert_eq!(Some(wallet_id), monitor.wallet_of(&id));
+        assert_eq!(Some(trader_id), monitor.trader_of(&id));
+        assert_eq!(None, monitor.wallet_of(&Position::generate_id()));
+    }
+
+    #[test]
+    fn unaffordable_top_up_stops_out() {
+        let mut monitor = new_monitor();
+        let mut order = new_order("ATOMUSDT", 10.0);
+        order.top_up_enabled = true;
+        let wallet = Wallet::new(order.wallet_id.clone(), "test", "USDT".into(), 50.0);
+        let position = order.open(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0), &new_prices(), None).unwrap();
+        let id = position.get_id().clone();
+        monitor.add(position);
+        monitor.add_wallet(wallet);
+
+        let events = monitor.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 8.0, 8.0));
+
+        assert!(!monitor.is_locked(&id));
+        assert_eq!(0, monitor.count());
+        assert!(events.iter().any(|event| matches!(
+            event,
+            PositionMonitoringEvent::PositionClosed(position)
+                if matches!(position.close_reason, ClosePositionReason::InsufficientBalance)
+        )));
+    }
+
+    #[test]
+    fn equity_top_up_without_equity_isnt_requested() {
+        let mut monitor = new_monitor();
+        let mut order = new_order("ATOMUSDT", 10.0);
+        order.top_up_enabled = true;
+        order.top_up_sizing = TopUpSizing::PercentOfEquity;
+        let position = order.open(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0), &new_prices(), None).unwrap();
+        let id = position.get_id().clone();
+        monitor.add(position);
+
+        let events = monitor.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 9.9, 9.9));
+
+        assert!(!monitor.is_locked(&id));
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn can_afford_top_up() {
+        let mut monitor = new_monitor();
+        let mut order = new_order("ATOMUSDT", 10.0);
+        order.top_up_enabled = true;
+        let mut wallet = Wallet::new(order.wallet_id.clone(), "test", "USDT".into(), 50.0);
+        wallet.total_unlocked_balance = 15.0;
+        let position = order.open(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0), &new_prices(), None).unwrap();
+        let id = position.get_id().clone();
+        monitor.add(position);
+        monitor.add_wallet(wallet);
+
+        assert!(monitor.can_afford_top_up(&id, 10.0));
+        assert!(!monitor.can_afford_top_up(&id, 20.0));
+
+        let events = monitor.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 9.9, 9.9));
+
+        assert!(monitor.is_locked(&id));
+        assert!(matches!(
+            events.as_slice(),
+            [PositionMonitoringEvent::PositionLocked(_)]
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_events() {
+        use super::{PositionLockReason, WalletMarginCallInfo};
+
+        let Position::Active(active_position) = new_position(10.0) else {
+            panic!("Must be active position");
+        };
+        let mut order = new_order("ATOMUSDT", 10.0);
+        order.desire_price = Some(9.0);
+        order.order_type = OrderType::Limit;
+        let Position::Pending(pending_position) =
+            order.open(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0), &new_prices(), None).unwrap()
+        else {
+            panic!("Must be pending position");
+        };
+        let closed_position = active_position.clone().close(ClosePositionReason::StopOut, None);
+        let events = vec![
+            ("PositionClosed", None, PositionMonitoringEvent::PositionClosed(closed_position)),
+            (
+                "PositionActivated",
+                None,
+                PositionMonitoringEvent::PositionActivated(active_position.clone()),
+            ),
+            (
+                "PositionMarginCall",
+                None,
+                PositionMonitoringEvent::PositionMarginCall(active_position.clone()),
+            ),
+            (
+                "PositionLocked",
+                Some("TopUp"),
+                PositionMonitoringEvent::PositionLocked(PositionLockReason::TopUp(
+                    active_position.clone(),
+                )),
+            ),
+            (
+                "PositionCloseFailed",
+                None,
+                PositionMonitoringEvent::PositionCloseFailed((
+                    active_position.clone(),
+                    TradingError::PriceNotFound("BTC".into()),
+                )),
+            ),
+            (
+                "PositionLocked",
+                Some("TopUpsCanceled"),
+                PositionMonitoringEvent::PositionLocked(PositionLockReason::TopUpsCanceled((
+                    active_position,
+                    Vec::new(),
+                ))),
+            ),
+            (
+                "PositionLocked",
+                Some("ActivationPending"),
+                PositionMonitoringEvent::PositionLocked(PositionLockReason::ActivationPending(
+                    pending_position,
+                )),
+            ),
+            (
+                "WalletMarginCall",
+                None,
+                PositionMonitoringEvent::WalletMarginCall(WalletMarginCallInfo {
+                    loss_percent: 50.0,
+                    pnl: -50.0,
+                    wallet_id: "wallet".into(),
+                    trader_id: "test".into(),
+                }),
+            ),
+            (
+                "WalletTopUpReservedFailed",
+                None,
+                PositionMonitoringEvent::WalletTopUpReservedFailed((
+                    "wallet".into(),
+                    TradingError::PricesNotFound(vec!["BTC".into()]),
+                )),
+            ),
+        ];
+
+        for (event_type, lock_type, event) in events {
+            let json = serde_json::to_value(&event).unwrap();
+            let deserialized: PositionMonitoringEvent = serde_json::from_value(json.clone()).unwrap();
+
+            assert_eq!(event_type, json["type"]);
+            if let Some(lock_type) = lock_type {
+                assert_eq!(lock_type, json["data"]["type"]);
+            }
+            assert_eq!(json, serde_json::to_value(&deserialized).unwrap());
+        }
+    }
+
+    #[test]
+    fn close_stale() {
+        let mut monitor = new_monitor();
+        let stale_position = new_position_on("BTCUSDT", 10.0);
+        let stale_id = stale_position.get_id().clone();
+        monitor.add(stale_position);
+        monitor.add(new_position(10.0));
+        let now = DateTimeAsMicroseconds::now().add(Duration::from_secs(120));
+        if let Some(Position::Active(position)) = monitor.get_mut(&stale_id) {
+            position.last_update_date = now.sub(Duration::from_secs(600));
+        }
+        monitor.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0));
+
+        let closed_positions = monitor.close_stale(Duration::from_secs(300), now);
+
+        assert_eq!(1, closed_positions.len());
+        assert_eq!(stale_id, closed_positions[0].id);
+        assert!(matches!(closed_positions[0].close_reason, ClosePositionReason::Stale));
+        assert_eq!(1, monitor.count());
+        assert!(monitor.get_by_instrument(&"BTCUSDT".into()).is_empty());
+    }
+
+    #[test]
+    fn close_stale_without_price() {
+        let mut monitor = new_monitor();
+        let failed_count = Arc::new(AtomicUsize::new(0));
+        let sink_failed_count = failed_count.clone();
+        monitor.set_event_sink(Box::new(move |event| {
+            if let PositionMonitoringEvent::PositionCloseFailed((_, TradingError::PriceNotFound(_))) =
+                event
+            {
+                sink_failed_count.fetch_add(1, Ordering::SeqCst);
+            }
+        }));
+        let position = new_position(10.0);
+        let unpriced_id = position.get_id().clone();
+        monitor.add(position);
+        let position = new_position_on("BTCUSDT", 10.0);
+        let priced_id = position.get_id().clone();
+        monitor.add(position);
+        let now = DateTimeAsMicroseconds::now().add(Duration::from_secs(600));
+        if let Some(Position::Active(position)) = monitor.get_mut(&unpriced_id) {
+            position.current_asset_prices = SortedVec::new();
+        }
+
+        let closed_positions = monitor.close_stale(Duration::from_secs(300), now);
+
+        assert_eq!(1, closed_positions.len());
+        assert_eq!(priced_id, closed_positions[0].id);
+        assert_eq!(1, failed_count.load(Ordering::SeqCst));
+        assert!(matches!(monitor.get(&unpriced_id), Some(Position::Active(_))));
+    }
+
+    #[test]
+    fn metrics() {
+        let mut monitor = new_monitor();
+        let mut order = new_order("BTCUSDT", 10.0);
+        order.desire_price = Some(9.0);
+        order.order_type = OrderType::Limit;
+        monitor.add(order.open(&BidAsk::new_synthetic("BTCUSDT".into(), 10.0, 10.0), &new_prices(), None).unwrap());
+        monitor.add(new_position(10.0));
+        monitor.add(new_position(10.0));
+        monitor.add_wallet(Wallet::new("test".into(), "test", "USDT".into(), 50.0));
+
+        monitor.update(&BidAsk::new_synthetic("BTCUSDT".into(), 8.5, 8.5));
+
+        let metrics = monitor.metrics();
+        assert_eq!(3, metrics.total_positions);
+        assert_eq!(2, metrics.active_count);
+        assert_eq!(1, metrics.pending_count);
+        assert_eq!(1, metrics.locked_count);
+        assert_eq!(1, metrics.wallet_count);
+        assert_eq!(3, metrics.instrument_count); // BTCUSDT, ATOMUSDT and USDTUSDT of invest asset
+    }
+
+    #[test]
+    fn get_by_instrument() {
+        let mut monitor = new_monitor();
+        monitor.add(new_position(10.0));
+        monitor.add(new_position(10.0));
+        monitor.add(new_position_on("BTCUSDT", 10.0));
+
+        let positions = monitor.get_by_instrument(&"ATOMUSDT".into());
+
+        assert_eq!(2, positions.len());
+    }
+
+    #[test]
+    fn remove_empty_instrument_bucket() {
+        let mut monitor = new_monitor();
+        let first_position = new_position(10.0);
+        let second_position = new_position(10.0);
+        let first_id = first_position.get_id().clone();
+        let second_id = second_position.get_id().clone();
+        monitor.add(first_position);
+        monitor.add(second_position);
+        monitor.add(new_position_on("BTCUSDT", 10.0));
+
+        monitor.remove(&first_id);
+        monitor.remove(&second_id);
+
+        assert!(monitor.ids_by_instruments.get(&"ATOMUSDT".into()).is_none());
+        assert!(monitor.ids_by_instruments.get(&"BTCUSDT".into()).is_some());
+    }
+
+    #[test]
+    fn remove_empty_instrument_bucket_on_update() {
+        let mut monitor = new_monitor();
+        monitor.add(new_position(10.0));
+
+        monitor.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 8.0, 8.0));
+
+        assert!(monitor.ids_by_instruments.get(&"ATOMUSDT".into()).is_none());
+    }
+
+    #[test]
+    fn reserve_and_activate_locked_pending() {
+        let mut monitor = new_monitor();
+        let mut order = new_order("ATOMUSDT", 10.0);
+        order.desire_price = Some(9.0);
+        order.order_type = OrderType::Limit;
+        let position = order.open(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0), &new_prices(), None).unwrap();
+        let id = position.get_id().clone();
+        monitor.add(position);
+        monitor.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 8.5, 8.5));
+        let is_locked_before = monitor.is_locked(&id);
+        let mut reserved = SortedVec::new();
+        reserved.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+
+        let event = monitor.reserve_and_activate(&id, reserved).unwrap();
+
+        assert!(is_locked_before);
+        assert!(matches!(event, PositionMonitoringEvent::PositionActivated(position) if position.id == id));
+        assert!(!monitor.is_locked(&id));
+        assert!(matches!(monitor.get(&id), Some(Position::Active(_))));
+    }
+
+    #[test]
+    fn reserve_and_activate_not_reached() {
+        let mut monitor = new_monitor();
+        let mut order = new_order("ATOMUSDT", 10.0);
+        order.desire_price = Some(9.0);
+        order.order_type = OrderType::Limit;
+        let position = order.open(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0), &new_prices(), None).unwrap();
+        let id = position.get_id().clone();
+        monitor.add(position);
+
+        let result = monitor.reserve_and_activate(&id, SortedVec::new());
+
+        assert!(matches!(result, Err(TradingError::DesirePriceNotReached(_))));
+    }
+
+    #[test]
+    fn reserve_and_activate_without_invest_assets() {
+        let mut monitor = new_monitor();
+        let mut order = new_order("ATOMUSDT", 10.0);
+        order.desire_price = Some(9.0);
+        order.order_type = OrderType::Limit;
+        let position = order.open(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0), &new_prices(), None).unwrap();
+        let id = position.get_id().clone();
+        monitor.add(position);
+        monitor.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 8.5, 8.5));
+
+        let result = monitor.reserve_and_activate(&id, SortedVec::new());
+
+        assert!(matches!(result, Err(TradingError::NoInvestAssets(_))));
+        assert!(matches!(monitor.get(&id), Some(Position::Pending(_))));
+    }
+
+    #[test]
+    fn cancel_pending() {
+        let mut monitor = new_monitor();
+        let mut order = new_order("ATOMUSDT", 10.0);
+        order.desire_price = Some(9.0);
+        order.order_type = OrderType::Limit;
+        let position = order.open(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0), &new_prices(), None).unwrap();
+        let id = position.get_id().clone();
+        monitor.add(position);
+
+        let position = monitor.cancel_pending(&id).unwrap();
+
+        assert!(position.total_invest_assets.is_empty());
+        assert!(position.get_status() == PositionStatus::Canceled);
+        assert!(matches!(position.close_reason, ClosePositionReason::Canceled));
+        assert_eq!(0, monitor.count());
+        assert!(monitor.get_by_trader("test").is_empty());
+    }
+
+    #[test]
+    fn cancel_pending_with_reserved_assets() {
+        let mut monitor = new_monitor();
+        let mut order = new_order("ATOMUSDT", 10.0);
+        order.desire_price = Some(9.0);
+        order.order_type = OrderType::Limit;
+        let mut position = order.open(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0), &new_prices(), None).unwrap();
+        let id = position.get_id().clone();
+        let mut reserved = SortedVec::new();
+        reserved.insert_or_replace(AssetAmount {amount: 40.0, symbol: "USDT".into()});
+        if let Position::Pending(position) = &mut position {
+            position.add_invest_assets(&reserved).unwrap();
+            position.add_invest_assets(&reserved).unwrap();
+        }
+        monitor.add(position);
+
+        let position = monitor.cancel_pending(&id).unwrap();
+
+        assert_eq!(1, position.total_invest_assets.len());
+        assert_eq!(80.0, position.total_invest_assets.get(&"USDT".into()).unwrap().amount);
+        assert!(position.get_status() == PositionStatus::Canceled);
+    }
+
+    #[test]
+    fn cancel_pending_active() {
+        let mut monitor = new_monitor();
+        let position = new_position(10.0);
+        let id = position.get_id().clone();
+        monitor.add(position);
+
+        let result = monitor.cancel_pending(&id);
+
+        assert!(matches!(result, Err(TradingError::PositionNotPending(_))));
+        assert_eq!(1, monitor.count());
+    }
+
+    #[test]
+    fn fill_or_kill_pending_is_canceled() {
+        let mut monitor = new_monitor();
+        let mut order = new_order("ATOMUSDT", 10.0);
+        order.desire_price = Some(9.0);
+        order.order_type = OrderType::Limit;
+        order.time_in_force = TimeInForce::FillOrKill;
+        let position = order.open(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0), &new_prices(), None).unwrap();
+        monitor.add(position);
+
+        let events = monitor.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 8.5, 8.5));
+
+        assert_eq!(1, events.len());
+        assert!(matches!(
+            &events[0],
+            PositionMonitoringEvent::PositionClosed(position)
+                if position.get_status() == PositionStatus::Canceled
+        ));
+        assert_eq!(0, monitor.count());
+    }
+
+    #[test]
+    fn fill_or_kill_partially_reserved_pending_is_canceled() {
+        let mut monitor = new_monitor();
+        monitor.add(new_partially_reserved_pending(TimeInForce::FillOrKill));
+
+        let events = monitor.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 8.5, 8.5));
+
+        assert_eq!(1, events.len());
+        assert!(matches!(
+            &events[0],
+            PositionMonitoringEvent::PositionClosed(position)
+                if position.get_status() == PositionStatus::Canceled
+        ));
+        assert_eq!(0, monitor.count());
+    }
+
+    #[test]
+    fn immediate_or_cancel_partially_reserved_pending_is_activated() {
+        let mut monitor = new_monitor();
+        monitor.add(new_partially_reserved_pending(TimeInForce::ImmediateOrCancel));
+
+        let events = monitor.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 8.5, 8.5));
+
+        assert_eq!(1, events.len());
+        let PositionMonitoringEvent::PositionActivated(position) = &events[0] else {
+            panic!("position must be activated");
+        };
+        assert_eq!(50.0, position.order.invest_assets.get(&"USDT".into()).unwrap().amount);
+        assert_eq!(1, monitor.count());
+    }
+
+    #[test]
+    fn locked_ids_on_top_up() {
+        let mut monitor = new_monitor();
+        let mut order = new_order("ATOMUSDT", 10.0);
+        order.top_up_enabled = true;
+        let position = order.open(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0), &new_prices(), None).unwrap();
+        let id = position.get_id().clone();
+        monitor.add(position);
+
+        monitor.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 9.9, 9.9));
+
+        assert_eq!(vec![id.clone()], monitor.locked_ids());
+        assert!(monitor.is_locked(&id));
+        assert_eq!(Some(PositionLockKind::TopUp), monitor.get_lock_kind(&id));
+
+        monitor.unlock(&id);
+
+        assert!(!monitor.is_locked(&id));
+        assert_eq!(None, monitor.get_lock_kind(&id));
+    }
+
+    #[test]
+    fn unlock_and_evaluate_closes_on_stop_out() {
+        let mut monitor = new_monitor();
+        let mut order = new_order("ATOMUSDT", 10.0);
+        order.top_up_enabled = true;
+        let position = order.open(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0), &new_prices(), None).unwrap();
+        let id = position.get_id().clone();
+        monitor.add(position);
+        monitor.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 9.9, 9.9));
+        if let Some(Position::Active(position)) = monitor.get_mut(&id) {
+            position.top_up_locked = true; // top-up isn't possible anymore
+        }
+        let locked_events = monitor.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 8.0, 8.0));
+
+        let events = monitor.unlock_and_evaluate(&id);
+
+        assert!(locked_events.is_empty());
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, PositionMonitoringEvent::PositionClosed(_))));
+        assert_eq!(0, monitor.count());
+        assert!(!monitor.is_locked(&id));
+    }
+
+    fn new_order(instrument: &str, leverage: f64) -> Order {
+        Order {
+            instrument: instrument.into(),
+            leverage,
+            ..new_test_order()
+        }
+    }
+
+    /// Limit order of 100 USDT with 50 USDT reserved
+    fn new_partially_reserved_pending(time_in_force: TimeInForce) -> Position {
+        let mut order = new_order("ATOMUSDT", 10.0);
+        order.desire_price = Some(9.0);
+        order.order_type = OrderType::Limit;
+        order.time_in_force = time_in_force;
+        let Position::Pending(mut position) =
+            order.open(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0), &new_prices(), None).unwrap()
+        else {
+            panic!("position must be pending");
+        };
+        let mut reserved = SortedVec::new();
+        reserved.insert_or_replace(AssetAmount {amount: 50.0, symbol: "USDT".into()});
+        position.add_invest_assets(&reserved).unwrap();
+
+        Position::Pending(position)
+    }
+
+    fn new_position(leverage: f64) -> Position {
+        new_position_on("ATOMUSDT", leverage)
+    }
+
+    fn new_position_on(instrument: &str, leverage: f64) -> Position {
+        let bidask = BidAsk::new_synthetic(instrument.into(), 10.0, 10.0);
+
+        new_order(instrument, leverage).open(&bidask, &new_prices(), None).unwrap()
+    }
+}
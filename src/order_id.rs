@@ -0,0 +1,65 @@
+use std::convert::Infallible;
+use std::fmt::Display;
+use std::ops::Deref;
+use std::str::FromStr;
+use compact_str::CompactString;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct OrderId(pub CompactString);
+
+impl Deref for OrderId {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_str()
+    }
+}
+
+impl From<&str> for OrderId {
+    fn from(value: &str) -> Self {
+        OrderId(value.into())
+    }
+}
+
+impl From<String> for OrderId {
+    fn from(value: String) -> Self {
+        OrderId(value.into())
+    }
+}
+
+impl From<&String> for OrderId {
+    fn from(value: &String) -> Self {
+        OrderId(value.into())
+    }
+}
+
+impl FromStr for OrderId {
+    type Err = Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(value.into())
+    }
+}
+
+impl Display for OrderId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OrderId;
+
+    #[test]
+    fn from_str_and_string() {
+        let from_str: OrderId = "id-1".into();
+        let from_string: OrderId = "id-1".to_string().into();
+
+        assert_eq!(from_str, from_string);
+        assert_eq!("id-1", from_str.to_string());
+    }
+}
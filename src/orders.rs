@@ -4,24 +4,35 @@ use crate::{
 };
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use rust_extensions::date_time::DateTimeAsMicroseconds;
-use std::{time::Duration};
+use std::{sync::Arc, time::Duration};
 use rust_extensions::sorted_vec::SortedVec;
 use uuid::Uuid;
 use crate::assets::{AssetAmount, AssetPrice};
 use crate::asset_symbol::AssetSymbol;
-use crate::instrument_symbol::InstrumentSymbol;
+use crate::instrument_symbol::{InstrumentSymbol, Interner};
 use crate::position_id::PositionId;
+use crate::order_id::OrderId;
+use crate::trader_id::TraderId;
 use crate::wallet_id::WalletId;
+use crate::errors::TradingError;
+use ahash::AHashMap;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Order {
-    pub id: String,
-    pub trader_id: String,
+    pub id: OrderId,
+    pub trader_id: TraderId,
     pub wallet_id: WalletId,
     pub instrument: InstrumentSymbol,
     pub base_asset: AssetSymbol,
+    /// Quote asset of instrument, used to convert instrument price moves to base asset
+    pub quote_asset: AssetSymbol,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
     pub invest_assets: SortedVec<AssetSymbol, AssetAmount>,
     pub leverage: f64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::micros"))]
     pub created_date: DateTimeAsMicroseconds,
     pub side: OrderSide,
     pub take_profit: Option<TakeProfitConfig>,
@@ -32,9 +43,34 @@ pub struct Order {
     pub top_up_percent: f64,
     pub funding_fee_period: Option<Duration>,
     pub desire_price: Option<f64>,
+    pub order_type: OrderType,
+    /// Instrument contract multiplier, 1.0 by default
+    pub contract_size: f64,
+    /// Worsens fill price in order direction, 0.0 by default
+    pub slippage_percent: f64,
+    pub time_in_force: TimeInForce,
+    /// Minimum invest amounts by assets, asset without entry has no limit
+    pub min_invest: AHashMap<AssetSymbol, f64>,
+    /// Maximum invest amounts by assets, asset without entry has no limit
+    pub max_invest: AHashMap<AssetSymbol, f64>,
+    /// Maximum count of top-ups, None means unlimited
+    pub max_top_ups: Option<u32>,
+    /// Maximum allowed leverage, None means unlimited
+    pub max_leverage: Option<f64>,
+    /// Limits loss of position by invested amount
+    pub negative_balance_protection: bool,
+    /// Chooses close reason when stop loss and take profit trigger on the same price
+    pub close_priority: ClosePriority,
+    /// Side of quote used to value invest assets, Sell by default
+    pub asset_price_side: OrderSide,
+    /// Stop out and stop loss are ignored until position is held for this time
+    pub min_holding_time: Option<Duration>,
+    /// Base of top_up_percent when calculating required top-up amount
+    pub top_up_sizing: TopUpSizing,
 }
 
-#[derive(Clone, IntoPrimitive, TryFromPrimitive)]
+#[derive(Debug, PartialEq, Clone, IntoPrimitive, TryFromPrimitive)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(i32)]
 pub enum OrderType {
     Market = 0,
@@ -42,6 +78,51 @@ pub enum OrderType {
 }
 
 #[derive(Debug, PartialEq, Clone, IntoPrimitive, TryFromPrimitive)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[repr(i32)]
+pub enum TimeInForce {
+    /// Pending position is locked until assets are reserved
+    GoodTillCancel = 0,
+    /// Pending position is canceled unless all invest assets are reserved when price is reached
+    FillOrKill = 1,
+    /// Pending position is activated with reserved part of invest assets when price is reached,
+    /// canceled if nothing is reserved
+    ImmediateOrCancel = 2,
+}
+
+impl TimeInForce {
+    /// Returns true if position must be canceled instead of waiting for activation
+    pub fn is_immediate(&self) -> bool {
+        match self {
+            TimeInForce::GoodTillCancel => false,
+            TimeInForce::FillOrKill => true,
+            TimeInForce::ImmediateOrCancel => true,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, IntoPrimitive, TryFromPrimitive)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[repr(i32)]
+pub enum ClosePriority {
+    /// Stop loss wins over take profit
+    WorstForClient = 0,
+    /// Take profit wins over stop loss
+    BestForClient = 1,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, IntoPrimitive, TryFromPrimitive)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[repr(i32)]
+pub enum TopUpSizing {
+    /// Percent of total invested amount
+    PercentOfInvest = 0,
+    /// Percent of wallet balance plus position pnl
+    PercentOfEquity = 1,
+}
+
+#[derive(Debug, PartialEq, Clone, IntoPrimitive, TryFromPrimitive)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(i32)]
 pub enum OrderSide {
     Buy = 0,
@@ -49,46 +130,59 @@ pub enum OrderSide {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct TakeProfitConfig {
     pub value: f64,
     pub unit: AutoClosePositionUnit,
 }
 
 impl TakeProfitConfig {
-    pub fn is_triggered(&self, pnl: f64, close_price: f64, side: &OrderSide) -> bool {
+    pub fn is_triggered(&self, pnl: f64, entry_price: f64, close_price: f64, side: &OrderSide) -> bool {
         match self.unit {
             AutoClosePositionUnit::AssetAmountUnit => pnl >= self.value,
             AutoClosePositionUnit::PriceRateUnit => match side {
                 OrderSide::Buy => self.value <= close_price,
                 OrderSide::Sell => self.value >= close_price,
             },
+            AutoClosePositionUnit::PricePercentUnit => match side {
+                OrderSide::Buy => entry_price * (1.0 + self.value / 100.0) <= close_price,
+                OrderSide::Sell => entry_price * (1.0 - self.value / 100.0) >= close_price,
+            },
         }
     }
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct StopLossConfig {
     pub value: f64,
     pub unit: AutoClosePositionUnit,
 }
 
 impl StopLossConfig {
-    pub fn is_triggered(&self, pnl: f64, close_price: f64, side: &OrderSide) -> bool {
+    pub fn is_triggered(&self, pnl: f64, entry_price: f64, close_price: f64, side: &OrderSide) -> bool {
         match self.unit {
             AutoClosePositionUnit::AssetAmountUnit => pnl < 0.0 && pnl.abs() >= self.value,
             AutoClosePositionUnit::PriceRateUnit => match side {
                 OrderSide::Buy => self.value >= close_price,
                 OrderSide::Sell => self.value <= close_price,
             },
+            AutoClosePositionUnit::PricePercentUnit => match side {
+                OrderSide::Buy => entry_price * (1.0 - self.value / 100.0) >= close_price,
+                OrderSide::Sell => entry_price * (1.0 + self.value / 100.0) <= close_price,
+            },
         }
     }
 }
 
 #[derive(Debug, Clone, IntoPrimitive, TryFromPrimitive)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(i32)]
 pub enum AutoClosePositionUnit {
     AssetAmountUnit = 0,
     PriceRateUnit = 1,
+    /// Positive percent of price change from entry price
+    PricePercentUnit = 2,
 }
 
 impl Order {
@@ -117,33 +211,138 @@ impl Order {
         instruments
     }
 
+    /// Same as get_instruments, but takes shared symbols from interner
+    pub fn get_interned_instruments(&self, interner: &mut Interner) -> Vec<Arc<InstrumentSymbol>> {
+        let mut instruments = Vec::with_capacity(self.invest_assets.len() + 1);
+        instruments.push(interner.intern(&self.instrument));
+
+        for asset in self.invest_assets.iter() {
+            instruments.push(interner.intern_pair(&asset.symbol, &self.base_asset));
+        }
+
+        instruments
+    }
+
     pub fn get_type(&self) -> OrderType {
-        if self.desire_price.is_some() {
-            OrderType::Limit
-        } else {
-            OrderType::Market
+        self.order_type.clone()
+    }
+
+    /// Checks that limit order has desire price and market order hasn't
+    pub fn validate_type(&self) -> Result<(), TradingError> {
+        match (&self.order_type, self.desire_price) {
+            (OrderType::Limit, None) => Err(TradingError::InvalidOrderType(
+                "Limit order must have desire price".to_string(),
+            )),
+            (OrderType::Market, Some(_)) => Err(TradingError::InvalidOrderType(
+                "Market order can't have desire price".to_string(),
+            )),
+            _ => Ok(()),
         }
     }
 
-    pub fn generate_id() -> String {
-        Uuid::new_v4().to_string()
+    pub fn generate_id() -> OrderId {
+        Uuid::new_v4().to_string().into()
+    }
+
+    /// Checks that quote is for order instrument
+    pub fn validate_instrument(&self, bidask: &BidAsk) -> Result<(), TradingError> {
+        if bidask.instrument != self.instrument {
+            return Err(TradingError::InstrumentMismatch {
+                expected: self.instrument.clone(),
+                actual: bidask.instrument.clone(),
+            });
+        }
+
+        Ok(())
     }
 
-    pub fn validate_prices(&self, asset_prices: &SortedVec<AssetSymbol, AssetPrice>) -> Result<(), String> {
+    /// Checks that leverage is positive and doesn't exceed max leverage
+    pub fn validate_leverage(&self) -> Result<(), TradingError> {
+        if self.leverage <= 0.0 || !self.leverage.is_finite() {
+            return Err(TradingError::InvalidLeverage(self.leverage));
+        }
+
+        if let Some(max_leverage) = self.max_leverage {
+            if self.leverage > max_leverage {
+                return Err(TradingError::InvalidLeverage(self.leverage));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that amount of asset is positive and fits min and max invest limits
+    pub fn validate_invest_amount(&self, symbol: &AssetSymbol, amount: f64) -> Result<(), TradingError> {
+        if amount <= 0.0 || !amount.is_finite() {
+            return Err(TradingError::InvalidInvestAmount(symbol.clone()));
+        }
+
+        if let Some(min_invest) = self.min_invest.get(symbol) {
+            if amount < *min_invest {
+                return Err(TradingError::InvestBelowMinimum(symbol.clone()));
+            }
+        }
+
+        if let Some(max_invest) = self.max_invest.get(symbol) {
+            if amount > *max_invest {
+                return Err(TradingError::InvestAboveMaximum(symbol.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn validate_invest_assets(&self) -> Result<(), TradingError> {
+        for item in self.invest_assets.iter() {
+            self.validate_invest_amount(&item.symbol, item.amount)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn validate_prices(
+        &self,
+        asset_prices: &SortedVec<AssetSymbol, AssetPrice>,
+    ) -> Result<(), TradingError> {
         for item in self.invest_assets.iter() {
             let price = asset_prices.get(&item.symbol);
 
             if price.is_none() {
-                let message = format!("Not Found price for {}", item.symbol);
-                return Err(message);
+                return Err(TradingError::PriceNotFound(item.symbol.clone()));
             }
         }
 
         Ok(())
     }
 
-    pub fn open(self, bidask: &BidAsk, asset_prices: &SortedVec<AssetSymbol, AssetPrice>) -> Position {
-        self.open_with_id(Position::generate_id(), bidask, asset_prices)
+    /// Opens order only if bidask isn't crossed and its spread doesn't exceed max_spread_percent
+    pub fn open(
+        self,
+        bidask: &BidAsk,
+        asset_prices: &SortedVec<AssetSymbol, AssetPrice>,
+        max_spread_percent: Option<f64>,
+    ) -> Result<Position, TradingError> {
+        self.validate_instrument(bidask)?;
+
+        if bidask.bid > bidask.ask {
+            return Err(TradingError::CrossedBook {
+                bid: bidask.bid,
+                ask: bidask.ask,
+            });
+        }
+
+        if let Some(max_spread_percent) = max_spread_percent {
+            let spread_percent = bidask.spread_percent();
+
+            if spread_percent > max_spread_percent {
+                return Err(TradingError::SpreadTooWide {
+                    spread_percent,
+                    max_spread_percent,
+                });
+            }
+        }
+
+        self.try_open_with_id(Position::generate_id(), bidask, asset_prices)
     }
 
     pub fn open_with_id(
@@ -152,56 +351,123 @@ impl Order {
         bidask: &BidAsk,
         asset_prices: &SortedVec<AssetSymbol, AssetPrice>,
     ) -> Position {
-        if self.validate_prices(asset_prices).is_err() {
-            panic!("Can't open order: invalid prices");
-        }
+        self.open_at(id, bidask, asset_prices, DateTimeAsMicroseconds::now())
+    }
 
-        if self.leverage <= 0.0 {
-            panic!("Can't open order: leverage can't be less or equals zero");
+    pub fn try_open_with_id(
+        self,
+        id: PositionId,
+        bidask: &BidAsk,
+        asset_prices: &SortedVec<AssetSymbol, AssetPrice>,
+    ) -> Result<Position, TradingError> {
+        self.try_open_at(id, bidask, asset_prices, DateTimeAsMicroseconds::now())
+    }
+
+    /// Opens order with provided open date instead of current time, used for replaying history
+    pub fn open_at(
+        self,
+        id: PositionId,
+        bidask: &BidAsk,
+        asset_prices: &SortedVec<AssetSymbol, AssetPrice>,
+        open_date: DateTimeAsMicroseconds,
+    ) -> Position {
+        match self.try_open_at(id, bidask, asset_prices, open_date) {
+            Ok(position) => position,
+            Err(err) => panic!("Can't open order: {}", err),
         }
+    }
 
-        match self.get_type() {
+    /// Same as open_at but returns validation error instead of panicking
+    pub fn try_open_at(
+        self,
+        id: PositionId,
+        bidask: &BidAsk,
+        asset_prices: &SortedVec<AssetSymbol, AssetPrice>,
+        open_date: DateTimeAsMicroseconds,
+    ) -> Result<Position, TradingError> {
+        self.validate_instrument(bidask)?;
+        self.validate_prices(asset_prices)?;
+        self.validate_leverage()?;
+        self.validate_type()?;
+        self.validate_invest_assets()?;
+
+        let position = match self.get_type() {
             OrderType::Market => {
-                let position = self.into_active(id, bidask, asset_prices);
+                let position = self.into_active(id, bidask, asset_prices, open_date);
                 Position::Active(position)
             }
             OrderType::Limit => {
-                let position = self.into_pending(id, bidask, asset_prices);
-                position.try_activate()
+                let position = self.into_pending(id, bidask, asset_prices, open_date);
+                position.try_activate_at(open_date)
             }
+        };
+
+        Ok(position)
+    }
+
+    /// Returns fill price worsened by slippage in order direction
+    pub fn apply_slippage(&self, price: f64) -> f64 {
+        let slippage = self.slippage_percent / 100.0;
+
+        match self.side {
+            OrderSide::Buy => price * (1.0 + slippage),
+            OrderSide::Sell => price * (1.0 - slippage),
         }
     }
 
     pub fn calculate_volume(&self, invest_amount: f64) -> f64 {
-        invest_amount * self.leverage
+        invest_amount * self.leverage * self.contract_size
     }
 
     pub fn calculate_invest_amount(&self, asset_prices: &SortedVec<AssetSymbol, AssetPrice>) -> f64 {
         calculate_total_amount(&self.invest_assets, asset_prices)
     }
 
+    /// Margin required to open order in base asset
+    pub fn required_margin(&self, asset_prices: &SortedVec<AssetSymbol, AssetPrice>) -> f64 {
+        self.calculate_invest_amount(asset_prices)
+    }
+
+    /// Volume of order in base asset
+    pub fn required_notional(&self, asset_prices: &SortedVec<AssetSymbol, AssetPrice>) -> f64 {
+        self.calculate_volume(self.required_margin(asset_prices))
+    }
+
+    /// Estimates daily funding cost in base asset: order volume * fee rate * periods per day
+    pub fn projected_funding_cost(
+        &self,
+        fee_rate: f64,
+        per_day: u32,
+        asset_prices: &SortedVec<AssetSymbol, AssetPrice>,
+    ) -> f64 {
+        self.required_notional(asset_prices) * fee_rate * per_day as f64
+    }
+
     fn into_active(
         self,
         id: PositionId,
         bid_ask: &BidAsk,
         asset_prices: &SortedVec<AssetSymbol, AssetPrice>,
+        now: DateTimeAsMicroseconds,
     ) -> ActivePosition {
-        let now = DateTimeAsMicroseconds::now();
         let mut asset_prices = asset_prices.to_owned();
         asset_prices.insert_or_replace(AssetPrice {price: 1.0, symbol: self.base_asset.clone()});
 
+        let open_price = self.apply_slippage(bid_ask.get_open_price(&self.side));
+
         ActivePosition {
             id,
             open_date: now,
-            open_price: bid_ask.get_open_price(&self.side),
+            open_price,
             open_asset_prices: asset_prices.clone(),
-            activate_price: bid_ask.get_open_price(&self.side),
+            activate_price: open_price,
             activate_date: now,
             activate_asset_prices: asset_prices.clone(),
-            current_price: bid_ask.get_close_price(&self.side),
+            current_bidask: bid_ask.to_owned(),
             current_asset_prices: asset_prices,
             last_update_date: now,
             top_ups: Vec::new(),
+            canceled_top_ups: Vec::new(),
             current_pnl: 0.0,
             current_loss_percent: 0.0,
             prev_loss_percent: 0.0,
@@ -209,6 +475,8 @@ impl Order {
             total_invest_assets: self.invest_assets.clone(),
             order: self,
             bonus_invest_assets: SortedVec::new_with_capacity(0),
+            total_swap: 0.0,
+            close_prices: Vec::new(),
         }
     }
 
@@ -217,8 +485,8 @@ impl Order {
         id: PositionId,
         bidask: &BidAsk,
         asset_prices: &SortedVec<AssetSymbol, AssetPrice>,
+        now: DateTimeAsMicroseconds,
     ) -> PendingPosition {
-        let now = DateTimeAsMicroseconds::now();
         let mut asset_prices = asset_prices.to_owned();
         asset_prices.insert_or_replace(AssetPrice {price: 1.0, symbol: self.base_asset.clone()});
 
@@ -228,10 +496,301 @@ impl Order {
             open_date: now,
             open_asset_prices: asset_prices.clone(),
             current_asset_prices: asset_prices,
-            current_price: bidask.get_open_price(&self.side),
+            current_bidask: bidask.to_owned(),
             last_update_date: now,
             order: self,
             total_invest_assets: SortedVec::new(),
         }
     }
 }
+
+/// Market buy order on ATOMUSDT investing 100 USDT, tests override fields with struct update
+#[cfg(test)]
+pub(crate) fn new_test_order() -> Order {
+    let mut invest_assets = SortedVec::new();
+    invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+
+    Order {
+        base_asset: "USDT".into(),
+        quote_asset: "USDT".into(),
+        id: "test".into(),
+        instrument: "ATOMUSDT".into(),
+        trader_id: "test".into(),
+        wallet_id: Uuid::new_v4().into(),
+        created_date: DateTimeAsMicroseconds::now(),
+        desire_price: None,
+        order_type: OrderType::Market,
+        funding_fee_period: None,
+        invest_assets,
+        leverage: 1.0,
+        side: OrderSide::Buy,
+        take_profit: None,
+        stop_loss: None,
+        stop_out_percent: 90.0,
+        margin_call_percent: 70.0,
+        top_up_enabled: false,
+        top_up_percent: 10.0,
+        contract_size: 1.0,
+        slippage_percent: 0.0,
+        time_in_force: TimeInForce::GoodTillCancel,
+        max_top_ups: None,
+        max_leverage: None,
+        negative_balance_protection: false,
+        close_priority: ClosePriority::WorstForClient,
+        asset_price_side: OrderSide::Sell,
+        min_holding_time: None,
+        top_up_sizing: TopUpSizing::PercentOfInvest,
+        min_invest: Default::default(),
+        max_invest: Default::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{new_test_order, OrderType};
+    use crate::assets::AssetPrice;
+    use crate::calculations::round;
+    use crate::errors::TradingError;
+    use crate::positions::{BidAsk, ClosePositionReason, Position};
+    use rust_extensions::date_time::DateTimeAsMicroseconds;
+    use rust_extensions::sorted_vec::SortedVec;
+    use std::time::Duration;
+
+    #[test]
+    fn open_with_normal_spread() {
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 9.99, 10.0);
+
+        let position = new_test_order().open(&bidask, &new_prices(), Some(0.5));
+
+        assert!(matches!(position, Ok(Position::Active(_))));
+    }
+
+    #[test]
+    fn open_with_zero_spread() {
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+
+        let position = new_test_order().open(&bidask, &new_prices(), Some(0.0));
+
+        assert_eq!(0.0, bidask.spread());
+        assert!(matches!(position, Ok(Position::Active(_))));
+    }
+
+    #[test]
+    fn open_with_too_wide_spread() {
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 9.0, 10.0);
+
+        let position = new_test_order().open(&bidask, &new_prices(), Some(5.0));
+
+        assert!(matches!(position, Err(TradingError::SpreadTooWide { .. })));
+    }
+
+    #[test]
+    fn open_with_crossed_book() {
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.1, 10.0);
+
+        let position = new_test_order().open(&bidask, &new_prices(), Some(5.0));
+
+        assert!(matches!(position, Err(TradingError::CrossedBook { .. })));
+    }
+
+    #[test]
+    fn open_limit_without_desire_price() {
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+        let mut order = new_test_order();
+        order.order_type = OrderType::Limit;
+
+        let position = order.open(&bidask, &new_prices(), None);
+
+        assert!(matches!(position, Err(TradingError::InvalidOrderType(_))));
+    }
+
+    #[test]
+    fn open_market_with_desire_price() {
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+        let mut order = new_test_order();
+        order.desire_price = Some(9.0);
+
+        let position = order.open(&bidask, &new_prices(), None);
+
+        assert!(matches!(position, Err(TradingError::InvalidOrderType(_))));
+    }
+
+    #[test]
+    fn required_margin_and_notional() {
+        let mut order = new_test_order();
+        order.leverage = 10.0;
+
+        let margin = order.required_margin(&new_prices());
+        let notional = order.required_notional(&new_prices());
+
+        assert_eq!(100.0, margin);
+        assert_eq!(order.calculate_volume(margin), notional);
+        assert_eq!(1000.0, notional);
+    }
+
+    #[test]
+    fn open_with_invalid_leverage() {
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+        let mut order = new_test_order();
+        order.max_leverage = Some(100.0);
+
+        for leverage in [0.0, -1.0, 100.5] {
+            order.leverage = leverage;
+            let result = order.clone().open(&bidask, &new_prices(), None);
+
+            assert!(matches!(result, Err(TradingError::InvalidLeverage(value)) if value == leverage));
+        }
+    }
+
+    #[test]
+    fn open_with_valid_leverage() {
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+        let mut order = new_test_order();
+        order.max_leverage = Some(100.0);
+        order.leverage = 0.5;
+
+        let result = order.open(&bidask, &new_prices(), None);
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn open_with_invest_limits() {
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+        let mut order = new_test_order();
+        order.min_invest.insert("USDT".into(), 200.0);
+
+        let below_min = order.clone().open(&bidask, &new_prices(), None);
+
+        order.min_invest.clear();
+        order.max_invest.insert("USDT".into(), 50.0);
+        let above_max = order.clone().open(&bidask, &new_prices(), None);
+
+        order.max_invest.insert("USDT".into(), 100.0);
+        let valid = order.open(&bidask, &new_prices(), None);
+
+        assert!(matches!(below_min, Err(TradingError::InvestBelowMinimum(_))));
+        assert!(matches!(above_max, Err(TradingError::InvestAboveMaximum(_))));
+        assert!(valid.is_ok());
+    }
+
+    #[test]
+    fn validate_non_positive_invest_amount() {
+        let order = new_test_order();
+
+        let result = order.validate_invest_amount(&"USDT".into(), 0.0);
+
+        assert!(matches!(result, Err(TradingError::InvalidInvestAmount(_))));
+    }
+
+    #[test]
+    #[should_panic]
+    fn open_with_id_market_with_desire_price() {
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+        let mut order = new_test_order();
+        order.desire_price = Some(9.0);
+
+        order.open_with_id(Position::generate_id(), &bidask, &new_prices());
+    }
+
+    #[test]
+    fn open_with_quote_of_other_instrument() {
+        let bidask = BidAsk::new_synthetic("BTCUSDT".into(), 20000.0, 20000.0);
+
+        let result = new_test_order().open(&bidask, &new_prices(), None);
+
+        assert!(matches!(result, Err(TradingError::InstrumentMismatch { .. })));
+    }
+
+    #[test]
+    #[should_panic]
+    fn open_with_id_with_quote_of_other_instrument() {
+        let bidask = BidAsk::new_synthetic("BTCUSDT".into(), 20000.0, 20000.0);
+
+        new_test_order().open_with_id(Position::generate_id(), &bidask, &new_prices());
+    }
+
+    #[test]
+    fn try_open_at_with_quote_of_other_instrument() {
+        let bidask = BidAsk::new_synthetic("BTCUSDT".into(), 20000.0, 20000.0);
+        let open_date = DateTimeAsMicroseconds::new(1_600_000_000_000_000);
+
+        let result = new_test_order().try_open_at(Position::generate_id(), &bidask, &new_prices(), open_date);
+
+        assert!(matches!(result, Err(TradingError::InstrumentMismatch { .. })));
+    }
+
+    #[test]
+    fn try_open_with_id_with_invalid_leverage() {
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+        let mut order = new_test_order();
+        order.leverage = 0.0;
+
+        let result = order.try_open_with_id(Position::generate_id(), &bidask, &new_prices());
+
+        assert!(matches!(result, Err(TradingError::InvalidLeverage(_))));
+    }
+
+    #[test]
+    fn try_open_with_id_without_price() {
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+
+        let result = new_test_order().try_open_with_id(Position::generate_id(), &bidask, &SortedVec::new());
+
+        assert!(matches!(result, Err(TradingError::PriceNotFound(_))));
+    }
+
+    #[test]
+    fn open_at_past_date() {
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+        let open_date = DateTimeAsMicroseconds::new(1_600_000_000_000_000);
+
+        let position = new_test_order().open_at(Position::generate_id(), &bidask, &new_prices(), open_date);
+
+        let Position::Active(position) = position else {
+            panic!("position must be active");
+        };
+        assert_eq!(open_date.unix_microseconds, position.open_date.unix_microseconds);
+        assert_eq!(open_date.unix_microseconds, position.activate_date.unix_microseconds);
+        assert_eq!(open_date.unix_microseconds, position.last_update_date.unix_microseconds);
+
+        let mut order = new_test_order();
+        order.desire_price = Some(9.0);
+        order.order_type = OrderType::Limit;
+        let invest_assets = order.invest_assets.clone();
+        let position = order.open_at(Position::generate_id(), &bidask, &new_prices(), open_date);
+        let Position::Pending(mut position) = position else {
+            panic!("position must be pending");
+        };
+        assert_eq!(open_date.unix_microseconds, position.open_date.unix_microseconds);
+        assert_eq!(open_date.unix_microseconds, position.last_update_date.unix_microseconds);
+        let mut tick = BidAsk::new_synthetic("ATOMUSDT".into(), 8.5, 8.5);
+        tick.datetime = open_date.add(Duration::from_secs(60));
+        position.add_invest_assets(&invest_assets).unwrap();
+        position.update(&tick);
+        let position = position.activate().unwrap();
+        assert_eq!(tick.datetime.unix_microseconds, position.activate_date.unix_microseconds);
+        assert_eq!(tick.datetime.unix_microseconds, position.last_update_date.unix_microseconds);
+        let position = position.close(ClosePositionReason::ClientCommand, None);
+        assert_eq!(tick.datetime.unix_microseconds, position.close_date.unix_microseconds);
+    }
+
+    #[test]
+    fn projected_funding_cost_scales_with_leverage() {
+        let mut order = new_test_order();
+        order.leverage = 1.0;
+        let cost = order.projected_funding_cost(0.0001, 3, &new_prices());
+        order.leverage = 10.0;
+        let leveraged_cost = order.projected_funding_cost(0.0001, 3, &new_prices());
+
+        assert_eq!(0.03, round(cost, 8));
+        assert_eq!(0.3, round(leveraged_cost, 8));
+    }
+
+    fn new_prices() -> SortedVec<crate::asset_symbol::AssetSymbol, AssetPrice> {
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+
+        prices
+    }
+}
@@ -1,8 +1,12 @@
 use rust_extensions::sorted_vec::EntityWithKey;
 use std::fmt::Display;
+use std::str::FromStr;
 use uuid::Uuid;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PositionId(pub Uuid);
 
 impl EntityWithKey<PositionId> for PositionId {
@@ -59,8 +63,29 @@ impl TryFrom<String> for PositionId {
     }
 }
 
+impl FromStr for PositionId {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        PositionId::try_from(value)
+    }
+}
+
 impl Display for PositionId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::PositionId;
+
+    #[test]
+    fn parse() {
+        let id = PositionId::from(uuid::Uuid::new_v4());
+
+        assert_eq!(Ok(id.clone()), id.to_string().parse::<PositionId>());
+        assert!("not-a-uuid".parse::<PositionId>().is_err());
+    }
+}
@@ -1,18 +1,24 @@
-use crate::calculations::{calculate_percent, floor};
+use crate::calculations::{calculate_percent, floor, pip_value, round_amounts, RoundingMode};
 use crate::top_ups::{ActiveTopUp, CanceledTopUp};
-use crate::{assets, calculations::calculate_total_amount, orders::{Order, OrderSide, StopLossConfig, TakeProfitConfig}};
+use crate::{assets, calculations::{calculate_total_amount, calculate_total_amount_checked}, orders::{AutoClosePositionUnit, ClosePriority, Order, OrderSide, OrderType, StopLossConfig, TakeProfitConfig, TimeInForce, TopUpSizing}};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use rust_extensions::date_time::DateTimeAsMicroseconds;
+use std::sync::Arc;
 use std::time::Duration;
 use compact_str::CompactString;
 use rust_extensions::sorted_vec::SortedVec;
 use uuid::Uuid;
 use crate::asset_symbol::AssetSymbol;
 use crate::assets::{AssetAmount, AssetPrice};
-use crate::instrument_symbol::InstrumentSymbol;
+use crate::instrument_symbol::{InstrumentSymbol, Interner};
 use crate::position_id::PositionId;
+use crate::errors::TradingError;
+use crate::units::{Price, Volume};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, IntoPrimitive, TryFromPrimitive)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(i32)]
 pub enum ClosePositionReason {
     ClientCommand = 0,
@@ -21,11 +27,45 @@ pub enum ClosePositionReason {
     StopLoss = 3,
     AdminCommand = 4,
     InsufficientBalance = 5,
+    Canceled = 6,
+    /// Instrument of position stopped quoting
+    Stale = 7,
+    /// Position was netted out by opposite order of client
+    Offset = 8,
+}
+
+impl ClosePositionReason {
+    /// Position was closed by monitoring rules without command
+    pub fn is_automatic(&self) -> bool {
+        match self {
+            ClosePositionReason::StopOut
+            | ClosePositionReason::StopLoss
+            | ClosePositionReason::TakeProfit
+            | ClosePositionReason::InsufficientBalance
+            | ClosePositionReason::Stale => true,
+            ClosePositionReason::ClientCommand
+            | ClosePositionReason::AdminCommand
+            | ClosePositionReason::Offset
+            | ClosePositionReason::Canceled => false,
+        }
+    }
+
+    /// Position was closed by client or admin command, including opposite client order
+    pub fn is_manual(&self) -> bool {
+        matches!(
+            self,
+            ClosePositionReason::ClientCommand
+                | ClosePositionReason::AdminCommand
+                | ClosePositionReason::Offset
+        )
+    }
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct BidAsk {
     pub instrument: InstrumentSymbol,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::micros"))]
     pub datetime: DateTimeAsMicroseconds,
     pub bid: f64,
     pub ask: f64,
@@ -49,6 +89,85 @@ impl BidAsk {
         compact_str.into()
     }
 
+    /// Checks that bid and ask are positive numbers and book isn't crossed
+    pub fn validate(&self) -> Result<(), TradingError> {
+        if !self.bid.is_finite() || self.bid <= 0.0 {
+            return Err(TradingError::InvalidQuote(format!(
+                "Invalid bid {} for {}",
+                self.bid, self.instrument
+            )));
+        }
+
+        if !self.ask.is_finite() || self.ask <= 0.0 {
+            return Err(TradingError::InvalidQuote(format!(
+                "Invalid ask {} for {}",
+                self.ask, self.instrument
+            )));
+        }
+
+        if self.bid > self.ask {
+            return Err(TradingError::CrossedBook {
+                bid: self.bid,
+                ask: self.ask,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn spread(&self) -> f64 {
+        self.ask - self.bid
+    }
+
+    /// Spread in percents of ask price
+    pub fn spread_percent(&self) -> f64 {
+        calculate_percent(self.ask, self.spread()).value()
+    }
+
+    /// Encodes quote as u16 instrument length, instrument, bid, ask and datetime micros.
+    /// Numbers are little endian
+    pub fn to_bytes(&self) -> Result<Vec<u8>, TradingError> {
+        let instrument = self.instrument.as_bytes();
+        let Ok(instrument_len) = u16::try_from(instrument.len()) else {
+            return Err(TradingError::InvalidQuote("Instrument symbol is too long".to_string()));
+        };
+        let mut bytes = Vec::with_capacity(2 + instrument.len() + 24);
+        bytes.extend_from_slice(&instrument_len.to_le_bytes());
+        bytes.extend_from_slice(instrument);
+        bytes.extend_from_slice(&self.bid.to_le_bytes());
+        bytes.extend_from_slice(&self.ask.to_le_bytes());
+        bytes.extend_from_slice(&self.datetime.unix_microseconds.to_le_bytes());
+
+        Ok(bytes)
+    }
+
+    /// Decodes quote encoded by to_bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TradingError> {
+        let mut offset = 0;
+        let instrument_len = u16::from_le_bytes(read_bytes(bytes, &mut offset)?) as usize;
+        let Some(instrument) = bytes.get(offset..offset + instrument_len) else {
+            return Err(TradingError::InvalidQuote("Unexpected end of bytes".to_string()));
+        };
+        offset += instrument_len;
+        let Ok(instrument) = std::str::from_utf8(instrument) else {
+            return Err(TradingError::InvalidQuote("Instrument is not utf8".to_string()));
+        };
+        let bid = f64::from_le_bytes(read_bytes(bytes, &mut offset)?);
+        let ask = f64::from_le_bytes(read_bytes(bytes, &mut offset)?);
+        let micros = i64::from_le_bytes(read_bytes(bytes, &mut offset)?);
+
+        if offset != bytes.len() {
+            return Err(TradingError::InvalidQuote("Unexpected trailing bytes".to_string()));
+        }
+
+        Ok(Self {
+            instrument: instrument.into(),
+            datetime: DateTimeAsMicroseconds::new(micros),
+            bid,
+            ask,
+        })
+    }
+
     pub fn get_close_price(&self, side: &OrderSide) -> f64 {
         match side {
             OrderSide::Buy => self.bid,
@@ -83,6 +202,15 @@ impl BidAsk {
     }
 }
 
+fn read_bytes<const N: usize>(bytes: &[u8], offset: &mut usize) -> Result<[u8; N], TradingError> {
+    let Some(slice) = bytes.get(*offset..*offset + N) else {
+        return Err(TradingError::InvalidQuote("Unexpected end of bytes".to_string()));
+    };
+    *offset += N;
+
+    Ok(slice.try_into().expect("Checked length"))
+}
+
 #[derive(Debug, Clone)]
 pub enum Position {
     Active(ActivePosition),
@@ -95,6 +223,13 @@ impl Position {
         Uuid::new_v4().into()
     }
 
+    /// Generates the same id for the same namespace and name
+    pub fn generate_id_from(namespace: &str, name: &str) -> PositionId {
+        let namespace = Uuid::new_v5(&Uuid::NAMESPACE_OID, namespace.as_bytes());
+
+        Uuid::new_v5(&namespace, name.as_bytes()).into()
+    }
+
     pub fn get_id(&self) -> &PositionId {
         match self {
             Position::Active(position) => &position.id,
@@ -155,6 +290,31 @@ impl Position {
         }
     }
 
+    /// Same as get_instruments, but takes shared symbols from interner
+    pub fn get_interned_instruments(&self, interner: &mut Interner) -> Vec<Arc<InstrumentSymbol>> {
+        let top_ups = match self {
+            Position::Pending(_) => return self.get_order().get_interned_instruments(interner),
+            Position::Active(position) => &position.top_ups,
+            Position::Closed(position) => &position.top_ups,
+        };
+        let base_asset = &self.get_order().base_asset;
+        let mut instruments = Vec::with_capacity(10);
+
+        for top_up in top_ups {
+            for item in top_up.total_assets.iter() {
+                let instrument = interner.intern_pair(&item.symbol, base_asset);
+
+                if !instruments.contains(&instrument) {
+                    instruments.push(instrument);
+                }
+            }
+        }
+
+        instruments.extend(self.get_order().get_interned_instruments(interner));
+
+        instruments
+    }
+
     fn get_top_up_instruments(&self, top_ups: &Vec<ActiveTopUp>) -> Vec<InstrumentSymbol> {
         let mut instruments = Vec::with_capacity(10);
 
@@ -172,7 +332,8 @@ impl Position {
     }
 }
 
-#[derive(Clone, IntoPrimitive, TryFromPrimitive, PartialEq)]
+#[derive(Debug, Clone, IntoPrimitive, TryFromPrimitive, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(i32)]
 pub enum PositionStatus {
     Pending = 0,
@@ -182,23 +343,35 @@ pub enum PositionStatus {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PendingPosition {
     pub id: PositionId,
     pub order: Order,
     pub open_price: f64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::micros"))]
     pub open_date: DateTimeAsMicroseconds,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
     pub open_asset_prices: SortedVec<AssetSymbol, AssetPrice>,
-    pub current_price: f64,
+    /// Last quote of position instrument
+    pub current_bidask: BidAsk,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
     pub current_asset_prices: SortedVec<AssetSymbol, AssetPrice>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::micros"))]
     pub last_update_date: DateTimeAsMicroseconds,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
     pub total_invest_assets: SortedVec<AssetSymbol, AssetAmount>,
 }
 
 impl PendingPosition {
+    /// Open price of current_bidask
+    pub fn current_price(&self) -> f64 {
+        self.current_bidask.get_open_price(&self.order.side)
+    }
+
     pub fn update(&mut self, bidask: &BidAsk) {
         self.update_instrument_price(bidask);
         self.update_asset_prices(bidask);
-        self.last_update_date = DateTimeAsMicroseconds::now();
+        self.last_update_date = bidask.datetime;
     }
 
     pub fn is_price_reached(&self) -> bool {
@@ -208,25 +381,25 @@ impl PendingPosition {
 
         let is_limit_sell = self.order.side == OrderSide::Sell && self.open_price <= desired_price;
 
-        if is_limit_sell && self.current_price >= desired_price {
+        if is_limit_sell && self.current_price() >= desired_price {
             return true;
         }
 
         let is_limit_buy = self.order.side == OrderSide::Buy && self.open_price >= desired_price;
 
-        if is_limit_buy && self.current_price <= desired_price {
+        if is_limit_buy && self.current_price() <= desired_price {
             return true;
         }
 
         let is_stop_sell = self.order.side == OrderSide::Sell && self.open_price >= desired_price;
 
-        if is_stop_sell && self.current_price <= desired_price {
+        if is_stop_sell && self.current_price() <= desired_price {
             return true;
         }
 
         let is_stop_buy = self.order.side == OrderSide::Buy && self.open_price <= desired_price;
 
-        if is_stop_buy && self.current_price >= desired_price {
+        if is_stop_buy && self.current_price() >= desired_price {
             return true;
         }
 
@@ -235,7 +408,7 @@ impl PendingPosition {
 
     fn update_instrument_price(&mut self, bidask: &BidAsk) {
         if self.order.instrument == bidask.instrument {
-            self.current_price = bidask.get_open_price(&self.order.side)
+            self.current_bidask = bidask.clone();
         }
     }
 
@@ -244,7 +417,7 @@ impl PendingPosition {
             let id = BidAsk::get_instrument_symbol(&asset.symbol, &self.order.base_asset);
 
             if id == bidask.instrument {
-                let price = bidask.get_asset_price(&asset.symbol, &OrderSide::Sell);
+                let price = bidask.get_asset_price(&asset.symbol, &self.order.asset_price_side);
                 let current_asset_price = self.current_asset_prices.get_mut(&asset.symbol);
 
                 if let Some(current_asset_price) = current_asset_price {
@@ -268,15 +441,78 @@ impl PendingPosition {
         true
     }
 
+    /// Returns true if all order invest assets are reserved
+    pub fn is_fully_reserved(&self) -> bool {
+        self.order.invest_assets.iter().all(|item| {
+            self.total_invest_assets
+                .get(&item.symbol)
+                .is_some_and(|reserved| reserved.amount >= item.amount)
+        })
+    }
+
+    /// Same as can_activate but fill-or-kill position also needs all invest assets reserved
+    pub fn can_fill(&self) -> bool {
+        match self.order.time_in_force {
+            TimeInForce::FillOrKill => self.can_activate() && self.is_fully_reserved(),
+            TimeInForce::GoodTillCancel | TimeInForce::ImmediateOrCancel => self.can_activate(),
+        }
+    }
+
+    /// Returns true if desire price is better than open price: buy below or sell above market
+    pub fn is_limit(&self) -> bool {
+        let Some(desired_price) = self.order.desire_price else {
+            panic!("PendingPosition without desire price");
+        };
+
+        match self.order.side {
+            OrderSide::Buy => self.open_price >= desired_price,
+            OrderSide::Sell => self.open_price <= desired_price,
+        }
+    }
+
+    /// Price of activation before slippage. Limit position is filled at the better of current and
+    /// desire prices when market gapped through desire price, stop position at current price
+    pub fn get_fill_price(&self) -> f64 {
+        if !self.is_limit() {
+            return self.current_price();
+        }
+
+        let desired_price = self.order.desire_price.expect("checked in is_limit");
+
+        match self.order.side {
+            OrderSide::Buy => self.current_price().min(desired_price),
+            OrderSide::Sell => self.current_price().max(desired_price),
+        }
+    }
+
     pub fn try_activate(self) -> Position {
+        let activate_date = self.current_bidask.datetime;
+
+        self.try_activate_at(activate_date)
+    }
+
+    /// Same as try_activate but with provided activate date, used for replaying history
+    pub fn try_activate_at(self, activate_date: DateTimeAsMicroseconds) -> Position {
         if self.can_activate() {
-            return Position::Active(self.activate().expect("checked in can_activate"));
+            let position = self.activate_at(activate_date).expect("checked in can_activate");
+
+            return Position::Active(position);
         }
 
         Position::Pending(self)
     }
 
+    /// Activates position at time of its last tick
     pub fn activate(self) -> Result<ActivePosition, String> {
+        let activate_date = self.current_bidask.datetime;
+
+        self.activate_at(activate_date)
+    }
+
+    pub fn activate_at(
+        self,
+        activate_date: DateTimeAsMicroseconds,
+    ) -> Result<ActivePosition, String> {
         if !self.is_price_reached() {
             return Err("desire_price isn't reached".to_string());
         }
@@ -285,7 +521,7 @@ impl PendingPosition {
             return Err("total_invest_assets is empty".to_string());
         }
 
-        let now = DateTimeAsMicroseconds::now();
+        let activate_price = self.order.apply_slippage(self.get_fill_price());
         let mut order = self.order;
         order.invest_assets = self.total_invest_assets;
 
@@ -294,13 +530,14 @@ impl PendingPosition {
             open_price: self.open_price,
             open_date: self.open_date,
             open_asset_prices: self.open_asset_prices,
-            activate_price: self.current_price,
-            activate_date: now,
+            activate_price,
+            activate_date,
             activate_asset_prices: self.current_asset_prices.to_owned(),
-            current_price: self.current_price,
+            current_bidask: self.current_bidask,
             current_asset_prices: self.current_asset_prices,
-            last_update_date: now,
+            last_update_date: activate_date,
             top_ups: Vec::new(),
+            canceled_top_ups: Vec::new(),
             current_pnl: 0.0,
             current_loss_percent: 0.0,
             prev_loss_percent: 0.0,
@@ -308,6 +545,8 @@ impl PendingPosition {
             total_invest_assets: order.invest_assets.clone(),
             order,
             bonus_invest_assets: SortedVec::new(),
+            total_swap: 0.0,
+            close_prices: Vec::new(),
         })
     }
 
@@ -323,33 +562,109 @@ impl PendingPosition {
         self.order.desire_price = Some(value);
     }
 
+    /// Atomically changes desire price and order invest assets. Nothing is changed if any value
+    /// is invalid. Already reserved total_invest_assets are changed to new assets too and the
+    /// returned reservation delta by assets must be applied by caller: positive amount has to be
+    /// reserved, negative one is released
+    pub fn amend(
+        &mut self,
+        new_desire_price: Option<f64>,
+        new_assets: Option<SortedVec<AssetSymbol, AssetAmount>>,
+    ) -> Result<SortedVec<AssetSymbol, AssetAmount>, TradingError> {
+        if let Some(price) = new_desire_price {
+            if price <= 0.0 || !price.is_finite() {
+                return Err(TradingError::InvalidDesirePrice(price));
+            }
+        }
+
+        if let Some(assets) = &new_assets {
+            for item in assets.iter() {
+                if !self.open_asset_prices.contains(&item.symbol) {
+                    return Err(TradingError::PriceNotFound(item.symbol.clone()));
+                }
+
+                self.order.validate_invest_amount(&item.symbol, item.amount)?;
+            }
+        }
+
+        if let Some(price) = new_desire_price {
+            self.order.desire_price = Some(price);
+        }
+
+        let mut reservation_delta = SortedVec::new();
+
+        if let Some(assets) = new_assets {
+            if !self.total_invest_assets.is_empty() {
+                for item in assets.iter() {
+                    let reserved_amount = self
+                        .total_invest_assets
+                        .get(&item.symbol)
+                        .map(|reserved| reserved.amount)
+                        .unwrap_or(0.0);
+
+                    if item.amount != reserved_amount {
+                        reservation_delta.insert_or_replace(AssetAmount {
+                            amount: item.amount - reserved_amount,
+                            symbol: item.symbol.clone(),
+                        });
+                    }
+                }
+
+                for reserved in self.total_invest_assets.iter() {
+                    if !assets.contains(&reserved.symbol) {
+                        reservation_delta.insert_or_replace(AssetAmount {
+                            amount: -reserved.amount,
+                            symbol: reserved.symbol.clone(),
+                        });
+                    }
+                }
+
+                self.total_invest_assets = assets.clone();
+            }
+
+            self.order.invest_assets = assets;
+        }
+
+        Ok(reservation_delta)
+    }
+
     pub fn add_invest_assets(
         &mut self,
         amounts_by_assets: &SortedVec<AssetSymbol, AssetAmount>,
-    ) -> Result<(), String> {
+    ) -> Result<(), TradingError> {
         for item in amounts_by_assets.iter() {
             if !self.open_asset_prices.contains(&item.symbol) {
-                return Err(format!(
-                    "Can't invest '{}': not found open price",
-                    &item.symbol
-                ));
+                return Err(TradingError::PriceNotFound(item.symbol.clone()));
             }
 
-            let invested_asset_amount: Option<&mut AssetAmount> = self.total_invest_assets.get_mut(&item.symbol);
-
-            if let Some(invested_asset_amount) = invested_asset_amount {
-                invested_asset_amount.amount += item.amount;
-            } else {
-                self.total_invest_assets
-                    .insert_or_replace(item.to_owned());
+            if item.amount <= 0.0 {
+                return Err(TradingError::InvalidInvestAmount(item.symbol.clone()));
             }
+
+            let invested_amount = self
+                .total_invest_assets
+                .get(&item.symbol)
+                .map(|invested| invested.amount)
+                .unwrap_or(0.0);
+            self.order
+                .validate_invest_amount(&item.symbol, invested_amount + item.amount)?;
         }
 
+        AssetAmount::accumulate_into(&mut self.total_invest_assets, amounts_by_assets.iter());
+
         Ok(())
     }
 
+    /// Consumes position and returns reserved invest assets, so they can be credited back to wallet
+    pub fn release_assets(self) -> SortedVec<AssetSymbol, AssetAmount> {
+        self.total_invest_assets
+    }
+
     pub fn close(self, reason: ClosePositionReason) -> ClosedPosition {
+        let close_price = self.current_price();
+
         ClosedPosition {
+            status: PositionStatus::Canceled,
             pnl: None,
             asset_pnls: SortedVec::new(),
             open_price: self.open_price,
@@ -358,42 +673,67 @@ impl PendingPosition {
             activate_date: None,
             activate_price: None,
             activate_asset_prices: SortedVec::new(),
-            close_date: DateTimeAsMicroseconds::now(),
-            close_price: self.current_price,
+            close_date: self.current_bidask.datetime,
+            close_price,
+            close_bidask: self.current_bidask,
             close_reason: reason,
             close_asset_prices: self.current_asset_prices.to_owned(),
             id: self.id,
             top_ups: Vec::with_capacity(0),
+            canceled_top_ups: Vec::with_capacity(0),
             total_invest_assets: self.total_invest_assets,
             order: self.order,
             invest_bonus_assets: SortedVec::new(),
+            total_swap: 0.0,
+            close_prices: Vec::with_capacity(0),
         }
     }
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ActivePosition {
     pub id: PositionId,
     pub order: Order,
     pub open_price: f64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::micros"))]
     pub open_date: DateTimeAsMicroseconds,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
     pub open_asset_prices: SortedVec<AssetSymbol, AssetPrice>,
     pub activate_price: f64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::micros"))]
     pub activate_date: DateTimeAsMicroseconds,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
     pub activate_asset_prices: SortedVec<AssetSymbol, AssetPrice>,
-    pub current_price: f64,
+    /// Last quote of position instrument
+    pub current_bidask: BidAsk,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
     pub current_asset_prices: SortedVec<AssetSymbol, AssetPrice>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::micros"))]
     pub last_update_date: DateTimeAsMicroseconds,
     pub top_ups: Vec<ActiveTopUp>,
+    /// Top-ups canceled by try_cancel_top_ups
+    pub canceled_top_ups: Vec<CanceledTopUp>,
     pub current_pnl: f64,
     pub current_loss_percent: f64,
     pub prev_loss_percent: f64,
     pub top_up_locked: bool,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
     pub total_invest_assets: SortedVec<AssetSymbol, AssetAmount>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
     pub bonus_invest_assets: SortedVec<AssetSymbol, AssetAmount>,
+    /// Total swap and funding fees charged in base asset
+    pub total_swap: f64,
+    /// Prices and fractions of initial volume of already closed parts
+    pub close_prices: Vec<(f64, f64)>,
 }
 
 impl ActivePosition {
+    /// Close price of current_bidask
+    pub fn current_price(&self) -> f64 {
+        self.current_bidask.get_close_price(&self.order.side)
+    }
+
     pub fn set_take_profit(&mut self, value: Option<TakeProfitConfig>) {
         self.order.take_profit = value;
     }
@@ -402,6 +742,38 @@ impl ActivePosition {
         self.order.stop_loss = value;
     }
 
+    /// Sets take profit only if it isn't triggered at current price
+    pub fn set_take_profit_checked(&mut self, value: Option<TakeProfitConfig>) -> Result<(), TradingError> {
+        if let Some(config) = value.as_ref() {
+            if config.is_triggered(self.current_pnl, self.activate_price, self.current_price(), &self.order.side) {
+                return Err(TradingError::InvalidTakeProfit {
+                    value: config.value,
+                    current_price: self.current_price(),
+                });
+            }
+        }
+
+        self.set_take_profit(value);
+
+        Ok(())
+    }
+
+    /// Sets stop loss only if it isn't triggered at current price
+    pub fn set_stop_loss_checked(&mut self, value: Option<StopLossConfig>) -> Result<(), TradingError> {
+        if let Some(config) = value.as_ref() {
+            if config.is_triggered(self.current_pnl, self.activate_price, self.current_price(), &self.order.side) {
+                return Err(TradingError::InvalidStopLoss {
+                    value: config.value,
+                    current_price: self.current_price(),
+                });
+            }
+        }
+
+        self.set_stop_loss(value);
+
+        Ok(())
+    }
+
     pub fn update(&mut self, bidask: &BidAsk) {
         self.try_update_instrument_price(bidask);
         self.try_update_asset_price(bidask);
@@ -420,6 +792,7 @@ impl ActivePosition {
         let mut canceled_top_ups = Vec::with_capacity(self.top_ups.len() / 3);
         let delay_start_date = DateTimeAsMicroseconds::now();
         let delay_start_date = delay_start_date.sub(delay);
+        let current_price = self.current_price();
 
         self.top_ups.retain(|top_up| {
             if top_up.date.is_later_than(delay_start_date) {
@@ -429,13 +802,13 @@ impl ActivePosition {
             let change_percent = price_change_percent / 100.0;
 
             if self.order.side == OrderSide::Buy
-                && self.current_price < top_up.instrument_price * (1.0 + change_percent)
+                && current_price < top_up.instrument_price * (1.0 + change_percent)
             {
                 return true;
             }
 
             if self.order.side == OrderSide::Sell
-                && self.current_price > top_up.instrument_price * (1.0 - change_percent)
+                && current_price > top_up.instrument_price * (1.0 - change_percent)
             {
                 return true;
             }
@@ -464,17 +837,20 @@ impl ActivePosition {
                 }
             }
 
-            canceled_top_ups.push(top_up.to_owned().cancel(self.current_price));
+            canceled_top_ups.push(top_up.to_owned().cancel(current_price));
 
             false
         });
 
+        self.canceled_top_ups.extend(canceled_top_ups.iter().cloned());
+
         canceled_top_ups
     }
 
     fn try_update_instrument_price(&mut self, bidask: &BidAsk) {
         if self.order.instrument == bidask.instrument {
-            self.current_price = bidask.get_close_price(&self.order.side)
+            self.current_bidask = bidask.clone();
+            self.last_update_date = bidask.datetime;
         }
     }
 
@@ -483,7 +859,7 @@ impl ActivePosition {
             let id = BidAsk::get_instrument_symbol(&asset.symbol, &self.order.base_asset);
 
             if id == bidask.instrument {
-                let price = bidask.get_asset_price(&asset.symbol, &OrderSide::Sell);
+                let price = bidask.get_asset_price(&asset.symbol, &self.order.asset_price_side);
                 let current_asset_price = self.current_asset_prices.get_mut(&asset.symbol);
 
                 if let Some(current_asset_price) = current_asset_price {
@@ -496,14 +872,44 @@ impl ActivePosition {
     }
 
     pub fn close(self, reason: ClosePositionReason, pnl_accuracy: Option<u32>) -> ClosedPosition {
+        self.close_checked(reason, pnl_accuracy)
+            .unwrap_or_else(|err| panic!("Can't close position: {}", err))
+    }
+
+    /// Closes position or returns error if price of any invested asset is missing
+    pub fn close_checked(
+        mut self,
+        reason: ClosePositionReason,
+        pnl_accuracy: Option<u32>,
+    ) -> Result<ClosedPosition, TradingError> {
+        if !self.close_prices.is_empty() {
+            let remaining_fraction = self.remaining_fraction();
+
+            if remaining_fraction > 0.0 {
+                self.close_prices.push((self.current_price(), remaining_fraction));
+            }
+        }
+
+        self.into_closed(reason, pnl_accuracy)
+    }
+
+    fn into_closed(
+        self,
+        reason: ClosePositionReason,
+        pnl_accuracy: Option<u32>,
+    ) -> Result<ClosedPosition, TradingError> {
         let pnls_by_assets = self.calc_pnls_by_assets(pnl_accuracy);
-        let mut total_pnl = calculate_total_amount(&pnls_by_assets, &self.current_asset_prices);
+        let mut total_pnl =
+            calculate_total_amount_checked(&pnls_by_assets, &self.current_asset_prices)?;
 
         if let Some(pnl_accuracy) = pnl_accuracy {
             total_pnl = floor(total_pnl, pnl_accuracy);
         }
 
-        ClosedPosition {
+        let close_price = self.current_price();
+
+        Ok(ClosedPosition {
+            status: PositionStatus::Filled,
             total_invest_assets: self.total_invest_assets,
             pnl: Some(total_pnl),
             asset_pnls: pnls_by_assets,
@@ -513,31 +919,53 @@ impl ActivePosition {
             activate_date: Some(self.activate_date),
             activate_price: Some(self.activate_price),
             activate_asset_prices: self.activate_asset_prices,
-            close_date: DateTimeAsMicroseconds::now(),
-            close_price: self.current_price,
+            close_date: self.current_bidask.datetime,
+            close_price,
+            close_bidask: self.current_bidask,
             close_reason: reason,
             close_asset_prices: self.current_asset_prices.to_owned(),
             order: self.order,
             id: self.id,
             top_ups: self.top_ups,
+            canceled_top_ups: self.canceled_top_ups,
             invest_bonus_assets: self.bonus_invest_assets,
-        }
+            total_swap: self.total_swap,
+            close_prices: self.close_prices,
+        })
     }
 
     pub fn determine_close_reason(&self) -> Option<ClosePositionReason> {
-        if self.is_stop_out() {
+        self.determine_close_reason_inner(false)
+    }
+
+    /// Same as determine_close_reason but checks stop out even if position is due for top-up,
+    /// use when top-up can't be made
+    pub fn determine_close_reason_ignoring_top_up(&self) -> Option<ClosePositionReason> {
+        self.determine_close_reason_inner(true)
+    }
+
+    fn determine_close_reason_inner(&self, ignore_top_up: bool) -> Option<ClosePositionReason> {
+        let is_holding = self.is_within_min_holding_time();
+
+        let is_stop_out = if ignore_top_up {
+            self.is_stop_out_loss()
+        } else {
+            self.is_stop_out()
+        };
+
+        if !is_holding && is_stop_out {
             return Some(ClosePositionReason::StopOut);
         }
 
-        if self.is_stop_loss() {
-            return Some(ClosePositionReason::StopLoss);
-        }
+        let is_stop_loss = !is_holding && self.is_stop_loss();
+        let is_take_profit = self.is_take_profit();
 
-        if self.is_take_profit() {
-            return Some(ClosePositionReason::TakeProfit);
+        match (is_stop_loss, is_take_profit, self.order.close_priority) {
+            (true, true, ClosePriority::BestForClient) => Some(ClosePositionReason::TakeProfit),
+            (true, _, _) => Some(ClosePositionReason::StopLoss),
+            (false, true, _) => Some(ClosePositionReason::TakeProfit),
+            (false, false, _) => None,
         }
-
-        None
     }
 
     pub fn try_close(self, pnl_accuracy: Option<u32>) -> Position {
@@ -548,9 +976,125 @@ impl ActivePosition {
         Position::Closed(self.close(reason, pnl_accuracy))
     }
 
+    /// Closes fraction of position volume and invested assets. Fraction 1.0 closes all and
+    /// leaves position with empty invest assets
+    pub fn close_part(
+        &mut self,
+        fraction: f64,
+        reason: ClosePositionReason,
+        pnl_accuracy: Option<u32>,
+    ) -> ClosedPosition {
+        let fraction = fraction.clamp(0.0, 1.0);
+        let remaining_fraction = self.remaining_fraction();
+        self.close_prices
+            .push((self.current_price(), fraction * remaining_fraction));
+        let mut part = self.clone();
+        part.scale(fraction);
+        self.scale(1.0 - fraction);
+        part.update_pnl();
+        self.update_pnl();
+
+        part.into_closed(reason, pnl_accuracy)
+            .unwrap_or_else(|err| panic!("Can't close position part: {}", err))
+    }
+
+    /// Moves fraction of invested assets and top-ups to new position with new id and the same
+    /// entry prices
+    pub fn split(&mut self, fraction: f64) -> Result<ActivePosition, TradingError> {
+        if !(fraction > 0.0 && fraction < 1.0) {
+            return Err(TradingError::InvalidFraction(fraction));
+        }
+
+        let mut position = self.clone();
+        position.id = Position::generate_id();
+        position.close_prices = Vec::new();
+        position.canceled_top_ups = Vec::new();
+        position.scale(fraction);
+        self.scale(1.0 - fraction);
+        position.update_pnl();
+        self.update_pnl();
+
+        Ok(position)
+    }
+
+    /// Fraction of initial volume which is not closed yet
+    pub fn remaining_fraction(&self) -> f64 {
+        let closed_fraction: f64 = self.close_prices.iter().map(|(_, fraction)| fraction).sum();
+
+        (1.0 - closed_fraction).max(0.0)
+    }
+
+    /// Partially closes position so loss percent of the rest drops to target_loss_percent.
+    /// Equity left in closed part stays in position as margin
+    pub fn liquidate_to_margin(
+        &mut self,
+        target_loss_percent: f64,
+        reason: ClosePositionReason,
+    ) -> Option<ClosedPosition> {
+        if self.current_pnl >= 0.0 || self.current_loss_percent <= target_loss_percent {
+            return None;
+        }
+
+        let invest_amount =
+            calculate_total_amount(&self.total_invest_assets, &self.current_asset_prices);
+        let loss = self.current_pnl.abs();
+        let target = target_loss_percent / 100.0;
+
+        if target <= 0.0 || target >= 1.0 {
+            return Some(self.close_part(1.0, reason, None));
+        }
+
+        let fraction = (loss - target * invest_amount) / (loss * (1.0 - target));
+
+        if fraction >= 1.0 {
+            return Some(self.close_part(1.0, reason, None));
+        }
+
+        let mut part = self.close_part(fraction, reason, None);
+
+        for item in part.total_invest_assets.iter_mut() {
+            let pnl = part
+                .asset_pnls
+                .get(&item.symbol)
+                .map(|pnl| pnl.amount)
+                .unwrap_or(0.0);
+            let equity = item.amount + pnl;
+
+            if equity <= 0.0 {
+                continue;
+            }
+
+            item.amount -= equity;
+            let invested_equity = AssetAmount {
+                amount: equity,
+                symbol: item.symbol.clone(),
+            };
+            AssetAmount::accumulate_into(
+                &mut self.total_invest_assets,
+                std::iter::once(&invested_equity),
+            );
+        }
+
+        self.update_pnl();
+
+        Some(part)
+    }
+
+    fn scale(&mut self, factor: f64) {
+        self.total_swap *= factor;
+        scale_amounts(&mut self.order.invest_assets, factor);
+        scale_amounts(&mut self.total_invest_assets, factor);
+        scale_amounts(&mut self.bonus_invest_assets, factor);
+
+        for top_up in self.top_ups.iter_mut() {
+            scale_amounts(&mut top_up.total_assets, factor);
+            scale_amounts(&mut top_up.bonus_assets, factor);
+        }
+    }
+
     fn is_take_profit(&self) -> bool {
         if let Some(take_profit_config) = self.order.take_profit.as_ref() {
-            take_profit_config.is_triggered(self.current_pnl, self.current_price, &self.order.side)
+            take_profit_config.is_triggered(self.current_pnl, self.activate_price, self.current_price(), &self.order.side)
         } else {
             false
         }
@@ -558,7 +1102,7 @@ impl ActivePosition {
 
     fn is_stop_loss(&self) -> bool {
         if let Some(stop_loss_config) = self.order.stop_loss.as_ref() {
-            stop_loss_config.is_triggered(self.current_pnl, self.current_price, &self.order.side)
+            stop_loss_config.is_triggered(self.current_pnl, self.activate_price, self.current_price(), &self.order.side)
         } else {
             false
         }
@@ -569,6 +1113,14 @@ impl ActivePosition {
             return false;
         }
 
+        self.is_stop_out_loss()
+    }
+
+    fn is_stop_out_loss(&self) -> bool {
+        if self.order.negative_balance_protection && self.current_loss_percent >= 100.0 {
+            return true;
+        }
+
         self.current_loss_percent >= self.order.stop_out_percent
     }
 
@@ -581,6 +1133,30 @@ impl ActivePosition {
             && self.prev_loss_percent < self.order.margin_call_percent
     }
 
+    /// Sets stop out and margin call percents of live position. New limits are checked by
+    /// determine_close_reason right away
+    pub fn set_risk_limits(
+        &mut self,
+        stop_out_percent: f64,
+        margin_call_percent: f64,
+    ) -> Result<(), TradingError> {
+        let is_valid = 0.0 < margin_call_percent
+            && margin_call_percent < stop_out_percent
+            && stop_out_percent <= 100.0;
+
+        if !is_valid {
+            return Err(TradingError::InvalidRiskLimits {
+                stop_out_percent,
+                margin_call_percent,
+            });
+        }
+
+        self.order.stop_out_percent = stop_out_percent;
+        self.order.margin_call_percent = margin_call_percent;
+
+        Ok(())
+    }
+
     pub fn set_top_up_lock(&mut self, is_locked: bool) {
         self.top_up_locked = is_locked;
     }
@@ -594,283 +1170,1610 @@ impl ActivePosition {
             return false;
         }
 
+        if let Some(max_top_ups) = self.order.max_top_ups {
+            if self.top_up_count() >= max_top_ups {
+                return false;
+            }
+        }
+
         self.current_loss_percent >= self.order.top_up_percent
     }
 
-    /// Calculates amount for next top-up in base asset
-    pub fn calculate_required_top_up_amount(&self) -> f64 {
+    /// Calculates amount for next top-up in base asset. Wallet balance in base asset is used
+    /// only by equity top-up sizing
+    pub fn calculate_required_top_up_amount(&self, wallet_balance: f64) -> f64 {
+        self.try_calculate_required_top_up_amount(wallet_balance)
+            .unwrap_or_else(|| panic!("Position top-up is not possible"))
+    }
+
+    /// Same as calculate_required_top_up_amount but returns None if top-up is not possible.
+    /// Equity top-up isn't possible when equity isn't positive
+    pub fn try_calculate_required_top_up_amount(&self, wallet_balance: f64) -> Option<f64> {
         if !self.is_top_up() {
-            panic!("Position top-up is not possible")
+            return None;
         }
 
-        let total_amount =
-            calculate_total_amount(&self.total_invest_assets, &self.current_asset_prices);
+        let base_amount = match self.order.top_up_sizing {
+            TopUpSizing::PercentOfInvest => {
+                calculate_total_amount(&self.total_invest_assets, &self.current_asset_prices)
+            }
+            TopUpSizing::PercentOfEquity => {
+                let equity = wallet_balance + self.current_pnl;
+
+                if equity <= 0.0 {
+                    return None;
+                }
 
-        total_amount * self.order.top_up_percent / 100.0
+                equity
+            }
+        };
+
+        Some(base_amount * self.order.top_up_percent / 100.0)
     }
 
-    /// Calculates total pnl in base asset by position
-    fn calculate_pnl(&self, invest_amount: f64, initial_price: f64) -> f64 {
-        let volume = self.order.calculate_volume(invest_amount);
+    /// Duration from activation to now
+    pub fn holding_duration(&self, now: DateTimeAsMicroseconds) -> Duration {
+        now.duration_since(self.activate_date).as_positive_or_zero()
+    }
 
-        match self.order.side {
-            OrderSide::Buy => (self.current_price / initial_price - 1.0) * volume,
-            OrderSide::Sell => (self.current_price / initial_price - 1.0) * -volume,
-        }
+    fn is_within_min_holding_time(&self) -> bool {
+        self.order
+            .min_holding_time
+            .is_some_and(|min_holding_time| {
+                self.activate_date
+                    .add(min_holding_time)
+                    .is_later_than(self.current_bidask.datetime)
+            })
     }
 
-    pub fn add_top_up(&mut self, top_up: ActiveTopUp) {
-        for item in top_up.asset_prices.iter() {
-            self.current_asset_prices.insert_or_replace(item.clone());
+    /// Calculates average entry price weighted by invest amounts of order and top-ups
+    pub fn weighted_avg_entry_price(&self) -> f64 {
+        if self.top_ups.is_empty() {
+            return self.activate_price;
         }
 
-        for item in top_up.total_assets.iter() {
-            let invested_asset_amount = self.total_invest_assets.get_mut(&item.symbol);
+        let order_amount = calculate_total_amount(&self.order.invest_assets, &self.current_asset_prices);
+        let mut total_amount = order_amount;
+        let mut weighted_price = self.activate_price * order_amount;
 
-            if let Some(invested_asset_amount) = invested_asset_amount {
-                invested_asset_amount.amount += item.amount;
-            } else {
-                self.total_invest_assets.insert_or_replace(item.clone());
-            }
+        for top_up in self.top_ups.iter() {
+            let top_up_amount = calculate_total_amount(&top_up.total_assets, &self.current_asset_prices);
+            total_amount += top_up_amount;
+            weighted_price += top_up.instrument_price * top_up_amount;
         }
 
-        for item in top_up.bonus_assets.iter() {
-            let bonus_asset_amount = self.bonus_invest_assets.get_mut(&item.symbol);
-
-            if let Some(bonus_asset_amount) = bonus_asset_amount {
-                bonus_asset_amount.amount += item.amount;
-            } else {
-                self.bonus_invest_assets.insert_or_replace(item.clone());
-            }
+        if total_amount == 0.0 {
+            return self.activate_price;
         }
 
-        self.top_ups.push(top_up);
-        self.update_pnl();
+        weighted_price / total_amount
     }
 
-    fn update_pnl(&mut self) {
-        let pnls_by_assets = self.calc_pnls_by_assets(None);
-        self.current_pnl = calculate_total_amount(&pnls_by_assets, &self.current_asset_prices);
-        self.prev_loss_percent = self.current_loss_percent;
-
-        if self.current_pnl < 0.0 {
-            let total_invest_amount =
-                calculate_total_amount(&self.total_invest_assets, &self.current_asset_prices);
-            self.current_loss_percent =
-                calculate_percent(total_invest_amount, self.current_pnl.abs());
+    /// Calculates value of one pip move in base asset by current position volume. Order quote
+    /// asset is converted by current asset prices, panics if it has no price
+    pub fn pip_value(&self, pip_size: f64) -> f64 {
+        let invest_amount = calculate_total_amount(&self.total_invest_assets, &self.current_asset_prices);
+        let volume = self.order.calculate_volume(invest_amount) / self.current_price();
+        let quote_asset = &self.order.quote_asset;
+        let quote_to_base_rate = if *quote_asset == self.order.base_asset {
+            1.0
         } else {
-            self.current_loss_percent = 0.0;
-        }
+            self.current_asset_prices
+                .get(quote_asset)
+                .unwrap_or_else(|| panic!("Price not found for {}", quote_asset))
+                .price
+        };
+
+        pip_value(Volume::new(volume), Price::new(pip_size), Price::new(quote_to_base_rate))
     }
 
-    /// Calculates total asset amounts invested to position. Including order and all active top-ups
-    pub fn calc_total_invest_assets(&self) -> SortedVec<AssetSymbol, AssetAmount> {
-        let mut amounts = SortedVec::new_with_capacity(self.order.invest_assets.len() + 5);
+    /// Margin in base asset used by position. Equals total invest amount at current asset prices
+    pub fn used_margin(&self) -> f64 {
+        calculate_total_amount(&self.total_invest_assets, &self.current_asset_prices)
+    }
 
-        for item in self.order.invest_assets.iter() {
-            let total_amount: Option<&mut AssetAmount> = amounts.get_mut(&item.symbol);
+    /// Change of wallet free margin caused by position: current pnl minus used margin
+    pub fn free_margin_impact(&self) -> f64 {
+        self.current_pnl - self.used_margin()
+    }
 
-            if let Some(total_amount) = total_amount {
-                total_amount.amount += item.amount;
-            } else {
-                amounts.insert_or_replace(item.clone());
-            }
+    /// Calculates percent change from open to current price per invest asset. Assets without
+    /// open or current price are skipped
+    pub fn asset_price_drift(&self) -> SortedVec<AssetSymbol, AssetAmount> {
+        let mut drifts = SortedVec::new_with_capacity(self.total_invest_assets.len());
+
+        for asset in self.total_invest_assets.iter() {
+            let Some(open_price) = self.open_asset_prices.get(&asset.symbol) else {
+                continue;
+            };
+            let Some(current_price) = self.current_asset_prices.get(&asset.symbol) else {
+                continue;
+            };
+
+            drifts.insert_or_replace(AssetAmount {
+                amount: calculate_percent(open_price.price, current_price.price).value() - 100.0,
+                symbol: asset.symbol.clone(),
+            });
         }
 
-        for top_up in self.top_ups.iter() {
-            for item in top_up.total_assets.iter() {
-                let total_amount = amounts.get_mut(&item.symbol);
+        drifts
+    }
 
-                if let Some(total_amount) = total_amount {
-                    total_amount.amount += item.amount;
-                } else {
-                    amounts.insert_or_replace(item.clone());
-                }
+    /// Calculates total pnl in base asset by position
+    fn calculate_pnl(&self, invest_amount: f64, initial_price: f64, price: f64) -> f64 {
+        let volume = self.order.calculate_volume(invest_amount);
+
+        match self.order.side {
+            OrderSide::Buy => (price / initial_price - 1.0) * volume,
+            OrderSide::Sell => (price / initial_price - 1.0) * -volume,
+        }
+    }
+
+    /// Volume of order and top-ups in base asset at current asset prices. Margin added by
+    /// add_margin doesn't change volume
+    pub fn current_volume(&self) -> f64 {
+        let order_amount = calculate_total_amount(&self.order.invest_assets, &self.current_asset_prices);
+        let top_ups_amount: f64 = self
+            .top_ups
+            .iter()
+            .map(|top_up| calculate_total_amount(&top_up.total_assets, &self.current_asset_prices))
+            .sum();
+
+        self.order.calculate_volume(order_amount + top_ups_amount)
+    }
+
+    /// Volume of order and top-ups in base asset revalued by instrument price change since entry
+    pub fn current_notional(&self) -> f64 {
+        let order_amount = calculate_total_amount(&self.order.invest_assets, &self.current_asset_prices);
+        let mut notional =
+            self.order.calculate_volume(order_amount) * self.current_price() / self.activate_price;
+
+        for top_up in self.top_ups.iter() {
+            let amount = calculate_total_amount(&top_up.total_assets, &self.current_asset_prices);
+            notional +=
+                self.order.calculate_volume(amount) * self.current_price() / top_up.instrument_price;
+        }
+
+        notional
+    }
+
+    /// Calculates pnl in base asset at hypothetical price without changing position
+    pub fn project_pnl(&self, hypothetical_price: f64) -> f64 {
+        let pnls_by_assets = self.calc_pnls_by_assets_at(hypothetical_price, None);
+
+        calculate_total_amount(&pnls_by_assets, &self.current_asset_prices)
+    }
+
+    /// Calculates loss percent at hypothetical price without changing position
+    pub fn project_loss_percent(&self, hypothetical_price: f64) -> f64 {
+        let pnl = self.project_pnl(hypothetical_price);
+        debug_assert!(pnl.is_finite(), "Non-finite projected pnl of position {}", self.id);
+
+        if pnl < 0.0 {
+            let total_invest_amount =
+                calculate_total_amount(&self.total_invest_assets, &self.current_asset_prices);
+            debug_assert!(
+                total_invest_amount.is_finite(),
+                "Non-finite invest amount of position {}",
+                self.id
+            );
+            calculate_percent(total_invest_amount, pnl.abs()).value()
+        } else {
+            0.0
+        }
+    }
+
+    /// Calculates instrument price at which loss percent reaches stop-out percent.
+    /// Top-up loss limits are not taken into account
+    pub fn stop_out_price(&self) -> f64 {
+        let (volume, volume_by_price) = self.volume_and_volume_by_price();
+
+        if volume_by_price == 0.0 {
+            return self.current_price();
+        }
+
+        let total_invest_amount =
+            calculate_total_amount(&self.total_invest_assets, &self.current_asset_prices);
+        let stop_out_loss = total_invest_amount * self.order.stop_out_percent / 100.0;
+
+        match self.order.side {
+            OrderSide::Buy => (volume - stop_out_loss) / volume_by_price,
+            OrderSide::Sell => (volume + stop_out_loss) / volume_by_price,
+        }
+    }
+
+    /// Calculates instrument price at which current pnl of order and top-ups equals target pnl
+    /// in base asset. Asset prices are considered unchanged
+    pub fn price_for_target_pnl(&self, target_pnl: f64) -> f64 {
+        let (volume, volume_by_price) = self.volume_and_volume_by_price();
+
+        if volume_by_price == 0.0 {
+            return self.current_price();
+        }
+
+        match self.order.side {
+            OrderSide::Buy => (volume + target_pnl) / volume_by_price,
+            OrderSide::Sell => (volume - target_pnl) / volume_by_price,
+        }
+    }
+
+    /// Returns volume of order and top-ups in base asset and sum of volumes divided by their
+    /// entry prices. Pnl at price p is p * volume_by_price - volume for buy side
+    pub(crate) fn volume_and_volume_by_price(&self) -> (f64, f64) {
+        let mut volume = 0.0;
+        let mut volume_by_price = 0.0;
+
+        let order_items = self
+            .order
+            .invest_assets
+            .iter()
+            .map(|item| (item, self.activate_price));
+        let top_up_items = self.top_ups.iter().flat_map(|top_up| {
+            top_up
+                .total_assets
+                .iter()
+                .map(move |item| (item, top_up.instrument_price))
+        });
+
+        for (item, initial_price) in order_items.chain(top_up_items) {
+            let asset_price = self
+                .current_asset_prices
+                .get(&item.symbol)
+                .map(|price| price.price)
+                .unwrap_or(0.0);
+            let item_volume = self.order.calculate_volume(item.amount) * asset_price;
+            volume += item_volume;
+            volume_by_price += item_volume / initial_price;
+        }
+
+        (volume, volume_by_price)
+    }
+
+    pub fn add_top_up(&mut self, top_up: ActiveTopUp) -> Result<(), TradingError> {
+        if let Some(max_top_ups) = self.order.max_top_ups {
+            if self.top_up_count() >= max_top_ups {
+                return Err(TradingError::TopUpsLimitReached(max_top_ups));
             }
         }
 
-        amounts
+        for item in top_up.asset_prices.iter() {
+            self.current_asset_prices.insert_or_replace(item.clone());
+        }
+
+        AssetAmount::accumulate_into(&mut self.total_invest_assets, top_up.total_assets.iter());
+        AssetAmount::accumulate_into(&mut self.bonus_invest_assets, top_up.bonus_assets.iter());
+
+        self.top_ups.push(top_up);
+        self.update_pnl();
+
+        Ok(())
+    }
+
+    pub fn top_up_count(&self) -> u32 {
+        self.top_ups.len() as u32
+    }
+
+    /// Sums invested assets of all active top-ups
+    pub fn total_top_up_invest(&self) -> SortedVec<AssetSymbol, AssetAmount> {
+        let mut amounts: SortedVec<AssetSymbol, AssetAmount> = SortedVec::new();
+
+        for top_up in self.top_ups.iter() {
+            AssetAmount::accumulate_into(&mut amounts, top_up.total_assets.iter());
+        }
+
+        amounts
+    }
+
+    pub fn top_up_ids(&self) -> Vec<&str> {
+        self.top_ups.iter().map(|top_up| &*top_up.id).collect()
+    }
+
+    /// Spread of the last quote
+    pub fn current_spread(&self) -> f64 {
+        self.current_bidask.spread()
+    }
+
+    /// Accumulates charged swap or funding fee in base asset
+    pub fn charge_swap(&mut self, amount: f64) {
+        self.total_swap += amount;
+    }
+
+    /// Adds margin to position without changing its volume: only loss percent is decreased
+    pub fn add_margin(
+        &mut self,
+        amounts: &SortedVec<AssetSymbol, AssetAmount>,
+        prices: &SortedVec<AssetSymbol, AssetPrice>,
+    ) -> Result<(), TradingError> {
+        for item in amounts.iter() {
+            if !prices.contains(&item.symbol) && !self.current_asset_prices.contains(&item.symbol) {
+                return Err(TradingError::PriceNotFound(item.symbol.clone()));
+            }
+        }
+
+        for item in amounts.iter() {
+            if let Some(price) = prices.get(&item.symbol) {
+                self.current_asset_prices.insert_or_replace(price.clone());
+            }
+        }
+
+        AssetAmount::accumulate_into(&mut self.total_invest_assets, amounts.iter());
+
+        self.update_pnl();
+
+        Ok(())
+    }
+
+    /// Adds invested assets at current price without leverage leg tracking: order invest
+    /// assets are increased and activate price is blended by invested amounts
+    pub fn average_in(
+        &mut self,
+        amounts: &SortedVec<AssetSymbol, AssetAmount>,
+        current_bidask: &BidAsk,
+    ) -> Result<(), TradingError> {
+        if current_bidask.instrument != self.order.instrument {
+            return Err(TradingError::InvalidQuote(format!(
+                "{} bidask for {} position",
+                current_bidask.instrument, self.order.instrument
+            )));
+        }
+
+        for item in amounts.iter() {
+            if !self.current_asset_prices.contains(&item.symbol) {
+                return Err(TradingError::PriceNotFound(item.symbol.clone()));
+            }
+
+            if item.amount <= 0.0 {
+                return Err(TradingError::InvalidInvestAmount(item.symbol.clone()));
+            }
+
+            let invested_amount = self
+                .total_invest_assets
+                .get(&item.symbol)
+                .map(|invested| invested.amount)
+                .unwrap_or(0.0);
+            self.order
+                .validate_invest_amount(&item.symbol, invested_amount + item.amount)?;
+        }
+
+        self.update(current_bidask);
+        let fill_price = self
+            .order
+            .apply_slippage(current_bidask.get_open_price(&self.order.side));
+        let invested_amount =
+            calculate_total_amount(&self.order.invest_assets, &self.current_asset_prices);
+        let added_amount = calculate_total_amount(amounts, &self.current_asset_prices);
+        self.activate_price = (invested_amount + added_amount)
+            / (invested_amount / self.activate_price + added_amount / fill_price);
+
+        AssetAmount::accumulate_into(&mut self.order.invest_assets, amounts.iter());
+        AssetAmount::accumulate_into(&mut self.total_invest_assets, amounts.iter());
+
+        self.update_pnl();
+
+        Ok(())
+    }
+
+    fn update_pnl(&mut self) {
+        let pnls_by_assets = self.calc_pnls_by_assets(None);
+        self.current_pnl = calculate_total_amount(&pnls_by_assets, &self.current_asset_prices);
+        debug_assert!(self.current_pnl.is_finite(), "Non-finite pnl of position {}", self.id);
+        self.prev_loss_percent = self.current_loss_percent;
+
+        if self.current_pnl < 0.0 {
+            let total_invest_amount =
+                calculate_total_amount(&self.total_invest_assets, &self.current_asset_prices);
+            debug_assert!(
+                total_invest_amount.is_finite(),
+                "Non-finite invest amount of position {}",
+                self.id
+            );
+            self.current_loss_percent =
+                calculate_percent(total_invest_amount, self.current_pnl.abs()).value();
+
+            if self.order.negative_balance_protection {
+                self.current_loss_percent = self.current_loss_percent.min(100.0);
+            }
+        } else {
+            self.current_loss_percent = 0.0;
+        }
+    }
+
+    /// Calculates total asset amounts invested to position. Including order and all active top-ups
+    pub fn calc_total_invest_assets(&self) -> SortedVec<AssetSymbol, AssetAmount> {
+        let mut amounts = SortedVec::new_with_capacity(self.order.invest_assets.len() + 5);
+        AssetAmount::accumulate_into(&mut amounts, self.order.invest_assets.iter());
+
+        for top_up in self.top_ups.iter() {
+            AssetAmount::accumulate_into(&mut amounts, top_up.total_assets.iter());
+        }
+
+        amounts
+    }
+
+    /// Calculates pnl by all invested assets, includes order, and top-ups
+    pub fn calc_pnls_by_assets(&self, pnl_accuracy: Option<u32>) -> SortedVec<AssetSymbol, AssetAmount> {
+        self.calc_pnls_by_assets_at(self.current_price(), pnl_accuracy)
+    }
+
+    fn calc_pnls_by_assets_at(&self, price: f64, pnl_accuracy: Option<u32>) -> SortedVec<AssetSymbol, AssetAmount> {
+        let mut asset_pnls: SortedVec<AssetSymbol, AssetAmount> = SortedVec::new_with_capacity(self.order.invest_assets.len() + 5);
+        let order_pnls = self.calc_order_pnls_by_assets_at(price);
+        let top_ups_pnls = self.calc_top_ups_pnls_by_assets_at(price);
+
+        AssetAmount::accumulate_into(&mut asset_pnls, order_pnls.iter().chain(top_ups_pnls.iter()));
+
+        if self.order.negative_balance_protection {
+            for asset_pnl in asset_pnls.iter_mut() {
+                let invest_amount = self
+                    .total_invest_assets
+                    .get(&asset_pnl.symbol)
+                    .map(|item| item.amount)
+                    .unwrap_or(0.0);
+                asset_pnl.amount = asset_pnl.amount.max(-invest_amount);
+            }
+        }
+
+        if let Some(pnl_accuracy) = pnl_accuracy {
+            round_amounts(&mut asset_pnls, pnl_accuracy, RoundingMode::Floor);
+        }
+
+        asset_pnls
+    }
+
+    /// Calculates pnl by invested assets initially in order
+    pub fn calc_order_pnls_by_assets(&self) -> SortedVec<AssetSymbol, AssetAmount> {
+        self.calc_order_pnls_by_assets_at(self.current_price())
+    }
+
+    fn calc_order_pnls_by_assets_at(&self, price: f64) -> SortedVec<AssetSymbol, AssetAmount> {
+        let mut pnls_by_assets = SortedVec::new_with_capacity(self.order.invest_assets.len());
+
+        for item in self.order.invest_assets.iter() {
+            let pnl = self.calculate_pnl(item.amount, self.activate_price, price);
+
+            pnls_by_assets.insert_or_replace(assets::AssetAmount { amount:pnl, symbol: item.symbol.clone()});
+        }
+
+        pnls_by_assets
+    }
+
+    /// Calculates pnl by invested assets in top-ups
+    pub fn calc_top_ups_pnls_by_assets(&self) -> SortedVec<AssetSymbol, AssetAmount> {
+        self.calc_top_ups_pnls_by_assets_at(self.current_price())
+    }
+
+    fn calc_top_ups_pnls_by_assets_at(&self, price: f64) -> SortedVec<AssetSymbol, AssetAmount> {
+        let mut pnls_by_assets = SortedVec::new_with_capacity(10);
+
+        for top_up in self.top_ups.iter() {
+            for item in top_up.total_assets.iter() {
+                let pnl = self.calculate_pnl(item.amount, top_up.instrument_price, price);
+                let max_loss_amount = item.amount * -1.0; // limit for isolated trade
+                let pnl = if pnl < max_loss_amount {
+                    max_loss_amount
+                } else {
+                    pnl
+                };
+
+                let asset_pnl = AssetAmount {amount: pnl, symbol: item.symbol.clone()};
+                AssetAmount::accumulate_into(&mut pnls_by_assets, std::iter::once(&asset_pnl));
+            }
+        }
+
+        pnls_by_assets
+    }
+}
+
+fn scale_amounts(amounts: &mut SortedVec<AssetSymbol, AssetAmount>, factor: f64) {
+    if factor <= 0.0 {
+        *amounts = SortedVec::new();
+        return;
+    }
+
+    for item in amounts.iter_mut() {
+        item.amount *= factor;
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ClosedPosition {
+    pub id: PositionId,
+    pub order: Order,
+    pub open_price: f64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::micros"))]
+    pub open_date: DateTimeAsMicroseconds,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
+    pub open_asset_prices: SortedVec<AssetSymbol, AssetPrice>,
+    pub activate_price: Option<f64>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::option_micros"))]
+    pub activate_date: Option<DateTimeAsMicroseconds>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
+    pub activate_asset_prices: SortedVec<AssetSymbol, AssetPrice>,
+    pub close_price: f64,
+    /// Last quote of instrument received before close
+    pub close_bidask: BidAsk,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::micros"))]
+    pub close_date: DateTimeAsMicroseconds,
+    pub close_reason: ClosePositionReason,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
+    pub close_asset_prices: SortedVec<AssetSymbol, AssetPrice>,
+    pub pnl: Option<f64>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
+    pub asset_pnls: SortedVec<AssetSymbol, AssetAmount>,
+    pub top_ups: Vec<ActiveTopUp>,
+    pub canceled_top_ups: Vec<CanceledTopUp>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
+    pub total_invest_assets: SortedVec<AssetSymbol, AssetAmount>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
+    pub invest_bonus_assets: SortedVec<AssetSymbol, AssetAmount>,
+    /// Total swap and funding fees charged in base asset
+    pub total_swap: f64,
+    /// Prices and fractions of initial volume of closed parts, empty if closed at once
+    pub close_prices: Vec<(f64, f64)>,
+    /// Filled if position was active when closed, Canceled if it was closed as pending
+    pub status: PositionStatus,
+}
+
+impl ClosedPosition {
+    pub fn get_status(&self) -> PositionStatus {
+        self.status.clone()
+    }
+
+    /// Duration from activation to close, None if position was never activated
+    pub fn holding_duration(&self) -> Option<Duration> {
+        self.activate_date
+            .map(|activate_date| self.close_date.duration_since(activate_date).as_positive_or_zero())
+    }
+
+    /// Opens market position on new instrument with parameters and invested assets of closed
+    /// position. Take profit and stop loss by price rate are dropped since they are tied to
+    /// prices of old instrument
+    pub fn rollover(
+        &self,
+        new_instrument: InstrumentSymbol,
+        bidask: &BidAsk,
+        asset_prices: &SortedVec<AssetSymbol, AssetPrice>,
+    ) -> Position {
+        let mut order = self.order.clone();
+        order.instrument = new_instrument;
+        order.created_date = DateTimeAsMicroseconds::now();
+        order.order_type = OrderType::Market;
+        order.desire_price = None;
+        order.invest_assets = self.total_invest_assets.clone();
+        order.take_profit = order
+            .take_profit
+            .filter(|config| !matches!(config.unit, AutoClosePositionUnit::PriceRateUnit));
+        order.stop_loss = order
+            .stop_loss
+            .filter(|config| !matches!(config.unit, AutoClosePositionUnit::PriceRateUnit));
+
+        order.open_with_id(Position::generate_id(), bidask, asset_prices)
+    }
+
+    /// Close price weighted by closed fractions of volume, close_price if closed at once
+    pub fn weighted_avg_close_price(&self) -> f64 {
+        let total_fraction: f64 = self.close_prices.iter().map(|(_, fraction)| fraction).sum();
+
+        if total_fraction <= 0.0 {
+            return self.close_price;
+        }
+
+        let weighted_sum: f64 = self
+            .close_prices
+            .iter()
+            .map(|(price, fraction)| price * fraction)
+            .sum();
+
+        weighted_sum / total_fraction
+    }
+
+    /// Pnl by each invested asset in units of that asset. asset_pnls are already calculated
+    /// in asset units, so no conversion by close prices is needed
+    pub fn pnl_by_original_asset(&self) -> SortedVec<AssetSymbol, AssetAmount> {
+        self.asset_pnls.clone()
+    }
+
+    /// Realized pnl net of charged swap
+    pub fn net_pnl(&self) -> Option<f64> {
+        self.pnl.map(|pnl| pnl - self.total_swap)
+    }
+
+    /// Converts base asset pnl to target asset. Returns None if pnl or conversion price is missing
+    pub fn pnl_in(
+        &self,
+        target: &AssetSymbol,
+        prices: &SortedVec<AssetSymbol, AssetPrice>,
+    ) -> Option<f64> {
+        let pnl = self.pnl?;
+
+        if target == &self.order.base_asset {
+            return Some(pnl);
+        }
+
+        let price = prices.get(target)?;
+
+        if price.price == 0.0 {
+            return None;
+        }
+
+        Some(pnl / price.price)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ActivePosition, ClosePositionReason};
+    use crate::{assets, orders::{new_test_order, ClosePriority, Order, OrderSide, OrderType, StopLossConfig, TakeProfitConfig, TopUpSizing}, positions::{BidAsk, Position, PositionStatus}};
+    use crate::errors::TradingError;
+    use crate::calculations::round;
+    use std::time::Duration;
+    use rust_extensions::date_time::DateTimeAsMicroseconds;
+    use rust_extensions::sorted_vec::SortedVec;
+    use uuid::Uuid;
+    use crate::asset_symbol::AssetSymbol;
+    use crate::assets::{AssetAmount, AssetPrice};
+    use crate::instrument_symbol::InstrumentSymbol;
+    use crate::top_ups::ActiveTopUp;
+
+    #[tokio::test]
+    async fn close_active_position() {
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(assets::AssetAmount{ amount: 100.0, symbol: "BTC".into()});
+        let order = Order {
+            invest_assets,
+            stop_out_percent: 10.0,
+            margin_call_percent: 10.0,
+            ..new_test_order()
+        };
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(assets::AssetPrice{ price: 22300.0, symbol: "BTC".into()});
+        let bidask = BidAsk {
+            ask: 14.748,
+            bid: 14.748,
+            datetime: DateTimeAsMicroseconds::now(),
+            instrument: "ATOMUSDT".into(),
+        };
+        let position = order.open(&bidask, &prices, None).unwrap();
+        let mut position = match position {
+            Position::Active(position) => position,
+            _ => {
+                panic!("Invalid position")
+            }
+        };
+
+        position.current_bidask = BidAsk::new_synthetic(position.order.instrument.clone(), 14.75, 14.75);
+        let closed_position = position.close(ClosePositionReason::ClientCommand, None);
+
+        let pnl = closed_position.pnl.unwrap();
+        let asset_pnl = closed_position.asset_pnls.get(&AssetSymbol("BTC".into())).clone().unwrap();
+
+        assert_ne!(pnl, asset_pnl.amount);
+        assert_eq!(302.41388662883173, pnl);
+        assert_eq!(0.01356116083537362, asset_pnl.amount);
+    }
+
+    #[test]
+    fn generate_id_from_is_deterministic() {
+        let id = Position::generate_id_from("tests", "position-1");
+        let same_id = Position::generate_id_from("tests", "position-1");
+        let other_id = Position::generate_id_from("tests", "position-2");
+
+        assert_eq!(id, same_id);
+        assert_ne!(id, other_id);
+    }
+
+    #[test]
+    fn closed_position_pnl_in_target_asset() {
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+        let order = new_order("ATOMUSDT".into(), invest_assets, 1.0, OrderSide::Buy);
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+        let position = new_active_position(order, &bidask, &prices);
+        let mut closed_position = position.close(ClosePositionReason::ClientCommand, None);
+        closed_position.pnl = Some(108.0);
+        let mut conversion_prices = SortedVec::new();
+        conversion_prices.insert_or_replace(AssetPrice {price: 1.08, symbol: "EUR".into()});
+
+        let eur_pnl = closed_position.pnl_in(&"EUR".into(), &conversion_prices);
+        let usdt_pnl = closed_position.pnl_in(&"USDT".into(), &conversion_prices);
+        let gbp_pnl = closed_position.pnl_in(&"GBP".into(), &conversion_prices);
+
+        assert_eq!(Some(100.0), eur_pnl.map(|pnl| round(pnl, 8)));
+        assert_eq!(Some(108.0), usdt_pnl);
+        assert_eq!(None, gbp_pnl);
+    }
+
+    #[test]
+    fn add_margin_decreases_loss_percent() {
+        let instrument: InstrumentSymbol = "ATOMUSDT".into();
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+        let order = new_order(instrument.clone(), invest_assets.clone(), 10.0, OrderSide::Buy);
+        let bidask = BidAsk::new_synthetic(instrument.clone(), 10.0, 10.0);
+        let mut position = new_active_position(order, &bidask, &prices);
+        position.update(&BidAsk::new_synthetic(instrument, 9.5, 9.5));
+        let pnl_before = position.current_pnl;
+        let loss_percent_before = position.current_loss_percent;
+
+        position.add_margin(&invest_assets, &prices).unwrap();
+
+        assert_eq!(pnl_before, position.current_pnl);
+        assert!(position.current_loss_percent < loss_percent_before);
+    }
+
+    #[test]
+    fn set_stop_loss_checked_rejects_triggered() {
+        let instrument: InstrumentSymbol = "ATOMUSDT".into();
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+        let order = new_order(instrument.clone(), invest_assets, 1.0, OrderSide::Buy);
+        let bidask = BidAsk::new_synthetic(instrument, 10.0, 10.0);
+        let mut position = new_active_position(order, &bidask, &prices);
+        let invalid_stop_loss = StopLossConfig {
+            unit: crate::orders::AutoClosePositionUnit::PriceRateUnit,
+            value: 10.5,
+        };
+        let valid_stop_loss = StopLossConfig {
+            unit: crate::orders::AutoClosePositionUnit::PriceRateUnit,
+            value: 9.5,
+        };
+
+        let result = position.set_stop_loss_checked(Some(invalid_stop_loss));
+
+        assert!(matches!(result, Err(TradingError::InvalidStopLoss { .. })));
+        assert!(position.order.stop_loss.is_none());
+
+        position.set_stop_loss_checked(Some(valid_stop_loss)).unwrap();
+
+        assert!(position.order.stop_loss.is_some());
+    }
+
+    #[test]
+    fn take_profit_by_price_percent() {
+        let mut position = new_losing_position(10.0);
+        position.set_take_profit(Some(TakeProfitConfig {
+            unit: crate::orders::AutoClosePositionUnit::PricePercentUnit,
+            value: 5.0,
+        }));
+        position.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.49, 10.49));
+        let reason_before = position.determine_close_reason();
+
+        position.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.5, 10.5));
+
+        assert!(reason_before.is_none());
+        assert!(matches!(
+            position.determine_close_reason(),
+            Some(ClosePositionReason::TakeProfit)
+        ));
+    }
+
+    #[test]
+    fn stop_loss_by_price_percent() {
+        let mut position = new_losing_position(10.0);
+        position.set_stop_loss(Some(StopLossConfig {
+            unit: crate::orders::AutoClosePositionUnit::PricePercentUnit,
+            value: 3.0,
+        }));
+
+        position.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 9.7, 9.7));
+
+        assert!(matches!(
+            position.determine_close_reason(),
+            Some(ClosePositionReason::StopLoss)
+        ));
+    }
+
+    #[test]
+    fn average_in_at_lower_price() {
+        let mut position = new_losing_position(8.0);
+        let mut amounts = SortedVec::new();
+        amounts.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+        let pnl_before = position.current_pnl;
+
+        position
+            .average_in(&amounts, &BidAsk::new_synthetic("ATOMUSDT".into(), 8.0, 8.0))
+            .unwrap();
+
+        assert!(position.activate_price < 10.0);
+        assert!((position.activate_price - 80.0 / 9.0).abs() < 1e-9);
+        assert_eq!(200.0, position.order.invest_assets.get(&"USDT".into()).unwrap().amount);
+        assert_eq!(200.0, position.total_invest_assets.get(&"USDT".into()).unwrap().amount);
+        assert!((position.current_pnl - pnl_before).abs() < 1e-9);
+    }
+
+    #[test]
+    fn average_in_with_invalid_amount() {
+        let mut position = new_losing_position(8.0);
+        let mut amounts = SortedVec::new();
+        amounts.insert_or_replace(AssetAmount {amount: 0.0, symbol: "USDT".into()});
+
+        let result = position.average_in(&amounts, &BidAsk::new_synthetic("ATOMUSDT".into(), 8.0, 8.0));
+
+        assert!(matches!(result, Err(TradingError::InvalidInvestAmount(_))));
+        assert_eq!(10.0, position.activate_price);
+    }
+
+    #[test]
+    fn close_reason_classification() {
+        let automatic = [
+            ClosePositionReason::StopOut,
+            ClosePositionReason::StopLoss,
+            ClosePositionReason::TakeProfit,
+            ClosePositionReason::InsufficientBalance,
+            ClosePositionReason::Stale,
+        ];
+        let manual = [
+            ClosePositionReason::ClientCommand,
+            ClosePositionReason::AdminCommand,
+            ClosePositionReason::Offset,
+        ];
+
+        for reason in automatic.iter() {
+            assert!(reason.is_automatic() && !reason.is_manual(), "{:?}", reason);
+        }
+
+        for reason in manual.iter() {
+            assert!(reason.is_manual() && !reason.is_automatic(), "{:?}", reason);
+        }
+
+        assert!(!ClosePositionReason::Canceled.is_automatic());
+        assert!(!ClosePositionReason::Canceled.is_manual());
+    }
+
+    #[test]
+    fn try_calculate_required_top_up_amount_for_healthy_position() {
+        let mut position = new_losing_position(10.0);
+        position.order.top_up_enabled = true;
+
+        assert!(!position.is_top_up());
+        assert_eq!(None, position.try_calculate_required_top_up_amount(1000.0));
+    }
+
+    #[test]
+    fn top_up_sizing() {
+        let mut position = new_losing_position(9.5);
+        position.order.top_up_enabled = true;
+
+        let by_invest = position.calculate_required_top_up_amount(1000.0);
+        position.order.top_up_sizing = TopUpSizing::PercentOfEquity;
+        let by_equity = position.calculate_required_top_up_amount(1000.0);
+
+        assert_eq!(-50.0, round(position.current_pnl, 8));
+        assert_eq!(10.0, round(by_invest, 8));
+        assert_eq!(95.0, round(by_equity, 8));
+    }
+
+    #[test]
+    fn top_up_sizing_by_equity_without_balance() {
+        let mut position = new_losing_position(9.5);
+        position.order.top_up_enabled = true;
+        position.order.top_up_sizing = TopUpSizing::PercentOfEquity;
+
+        assert_eq!(None, position.try_calculate_required_top_up_amount(0.0));
+        assert_eq!(None, position.try_calculate_required_top_up_amount(50.0));
+    }
+
+    #[test]
+    fn close_reason_ignoring_top_up() {
+        let mut position = new_losing_position(9.0);
+        position.order.top_up_enabled = true;
+
+        assert!(position.is_top_up());
+        assert!(position.determine_close_reason().is_none());
+        assert!(matches!(
+            position.determine_close_reason_ignoring_top_up(),
+            Some(ClosePositionReason::StopOut)
+        ));
+        assert!(!position.top_up_locked);
+    }
+
+    #[test]
+    fn min_holding_time() {
+        let mut position = new_losing_position(10.0);
+        position.order.min_holding_time = Some(Duration::from_secs(30));
+        position.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 9.0, 9.0));
+
+        let reason_within_window = position.determine_close_reason();
+        position.activate_date = DateTimeAsMicroseconds::now().sub(Duration::from_secs(31));
+        let reason_after_window = position.determine_close_reason();
+
+        assert!(reason_within_window.is_none());
+        assert!(matches!(reason_after_window, Some(ClosePositionReason::StopOut)));
+    }
+
+    #[test]
+    fn last_update_date_of_tick() {
+        let mut position = new_losing_position(10.0);
+        let mut bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 9.0, 9.0);
+        bidask.datetime = DateTimeAsMicroseconds::new(1_600_000_000_000_000);
+
+        position.update(&bidask);
+
+        assert_eq!(bidask.datetime.unix_microseconds, position.last_update_date.unix_microseconds);
+    }
+
+    #[test]
+    fn min_holding_time_by_tick_time() {
+        let mut position = new_losing_position(10.0);
+        position.order.min_holding_time = Some(Duration::from_secs(30));
+        position.activate_date = DateTimeAsMicroseconds::new(1_600_000_000_000_000);
+        let mut bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 9.0, 9.0);
+
+        bidask.datetime = position.activate_date.add(Duration::from_secs(10));
+        position.update(&bidask);
+        let reason_within_window = position.determine_close_reason();
+        bidask.datetime = position.activate_date.add(Duration::from_secs(31));
+        position.update(&bidask);
+        let reason_after_window = position.determine_close_reason();
+
+        assert!(reason_within_window.is_none());
+        assert!(matches!(reason_after_window, Some(ClosePositionReason::StopOut)));
+    }
+
+    #[test]
+    fn close_bidask_of_last_tick() {
+        let mut position = new_losing_position(9.5);
+        let last_bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 9.4, 9.6);
+        position.update(&last_bidask);
+
+        let closed_position = position.close(ClosePositionReason::ClientCommand, None);
+
+        assert_eq!(last_bidask.bid, closed_position.close_bidask.bid);
+        assert_eq!(last_bidask.ask, closed_position.close_bidask.ask);
+        assert_eq!(last_bidask.datetime.unix_microseconds, closed_position.close_bidask.datetime.unix_microseconds);
+        assert_eq!(9.4, closed_position.close_price);
+    }
+
+    #[test]
+    fn closed_status_of_stop_out_and_cancel() {
+        let instrument: InstrumentSymbol = "ATOMUSDT".into();
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+        let mut order = new_order(instrument.clone(), invest_assets, 10.0, OrderSide::Buy);
+        order.desire_price = Some(9.0);
+        order.order_type = OrderType::Limit;
+        let Position::Pending(mut pending_position) = order.open(&BidAsk::new_synthetic(instrument, 10.0, 10.0), &prices, None).unwrap() else {
+            panic!("Must be pending position");
+        };
+        pending_position.add_invest_assets(&pending_position.order.invest_assets.clone()).unwrap();
+        let mut stopped_out_position = new_losing_position(8.0);
+        stopped_out_position.close_part(1.0, ClosePositionReason::StopOut, None);
+
+        let stopped_out_position = stopped_out_position.close(ClosePositionReason::StopOut, None);
+        let canceled_position = pending_position.close(ClosePositionReason::ClientCommand);
+
+        assert!(stopped_out_position.total_invest_assets.is_empty());
+        assert_eq!(PositionStatus::Filled, stopped_out_position.get_status());
+        assert!(!canceled_position.total_invest_assets.is_empty());
+        assert_eq!(PositionStatus::Canceled, canceled_position.get_status());
+    }
+
+    #[test]
+    fn rollover() {
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+        let mut order = new_order("ATOMUSDT".into(), invest_assets, 5.0, OrderSide::Sell);
+        order.take_profit = Some(TakeProfitConfig {
+            unit: crate::orders::AutoClosePositionUnit::PricePercentUnit,
+            value: 5.0,
+        });
+        order.stop_loss = Some(StopLossConfig {
+            unit: crate::orders::AutoClosePositionUnit::PriceRateUnit,
+            value: 11.0,
+        });
+        let position = new_active_position(order, &BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0), &prices);
+        let closed_position = position.close(ClosePositionReason::ClientCommand, None);
+        let bidask = BidAsk::new_synthetic("ATOMUSDT-Q2".into(), 10.2, 10.3);
+
+        let Position::Active(position) = closed_position.rollover("ATOMUSDT-Q2".into(), &bidask, &prices) else {
+            panic!("Must be active position");
+        };
+
+        assert_ne!(closed_position.id, position.id);
+        assert_eq!(InstrumentSymbol::from("ATOMUSDT-Q2"), position.order.instrument);
+        assert_eq!(5.0, position.order.leverage);
+        assert!(position.order.side == OrderSide::Sell);
+        assert_eq!(10.2, position.activate_price);
+        assert_eq!(100.0, position.total_invest_assets.get(&"USDT".into()).unwrap().amount);
+        assert!(position.order.take_profit.is_some());
+        assert!(position.order.stop_loss.is_none());
+    }
+
+    #[test]
+    fn split() {
+        let mut position = new_losing_position(9.5);
+        let pnl_before = position.current_pnl;
+        let id_before = position.id.clone();
+
+        let split_position = position.split(0.3).unwrap();
+
+        let amount = position.total_invest_assets.get(&"USDT".into()).unwrap().amount;
+        let split_amount = split_position.total_invest_assets.get(&"USDT".into()).unwrap().amount;
+        assert_eq!(100.0, amount + split_amount);
+        assert_eq!(30.0, split_amount);
+        assert_eq!(id_before, position.id);
+        assert_ne!(position.id, split_position.id);
+        assert_eq!(position.activate_price, split_position.activate_price);
+        assert!((pnl_before - position.current_pnl - split_position.current_pnl).abs() < 1e-9);
+        assert!(matches!(position.split(1.0), Err(TradingError::InvalidFraction(_))));
+    }
+
+    #[test]
+    fn asset_price_side() {
+        let instrument: InstrumentSymbol = "ATOMUSDT".into();
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 20000.0, symbol: "BTC".into()});
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(AssetAmount {amount: 0.01, symbol: "BTC".into()});
+        let order = new_order(instrument.clone(), invest_assets, 10.0, OrderSide::Buy);
+        let bidask = BidAsk::new_synthetic(instrument, 10.0, 10.0);
+        let mut sell_side_position = new_active_position(order.clone(), &bidask, &prices);
+        let mut buy_side_position = new_active_position(order, &bidask, &prices);
+        buy_side_position.order.asset_price_side = OrderSide::Buy;
+        let btc_bidask = BidAsk::new_synthetic("BTCUSDT".into(), 20000.0, 20010.0);
+
+        sell_side_position.update(&btc_bidask);
+        buy_side_position.update(&btc_bidask);
+
+        let sell_side_price = sell_side_position.current_asset_prices.get(&"BTC".into()).unwrap().price;
+        let buy_side_price = buy_side_position.current_asset_prices.get(&"BTC".into()).unwrap().price;
+        assert_eq!(btc_bidask.spread(), sell_side_price - buy_side_price);
+    }
+
+    #[test]
+    fn used_margin() {
+        let mut position = new_losing_position(10.0);
+        position.current_asset_prices.insert_or_replace(AssetPrice {price: 0.5, symbol: "USDT".into()});
+        position.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 9.5, 9.5));
+
+        assert_eq!(50.0, position.used_margin());
+        assert_eq!(position.current_pnl - 50.0, position.free_margin_impact());
+    }
+
+    #[test]
+    fn asset_price_drift() {
+        let instrument: InstrumentSymbol = "ATOMUSDT".into();
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 20000.0, symbol: "BTC".into()});
+        prices.insert_or_replace(AssetPrice {price: 1500.0, symbol: "ETH".into()});
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(AssetAmount {amount: 0.01, symbol: "BTC".into()});
+        invest_assets.insert_or_replace(AssetAmount {amount: 0.1, symbol: "ETH".into()});
+        invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+        let order = new_order(instrument.clone(), invest_assets, 10.0, OrderSide::Buy);
+        let mut position = new_active_position(order, &BidAsk::new_synthetic(instrument, 10.0, 10.0), &prices);
+        position.current_asset_prices.insert_or_replace(AssetPrice {price: 22000.0, symbol: "BTC".into()});
+        position.current_asset_prices.remove(&"ETH".into());
+
+        let drifts = position.asset_price_drift();
+
+        assert_eq!(2, drifts.len());
+        assert_eq!(10.0, round(drifts.get(&"BTC".into()).unwrap().amount, 8));
+        assert_eq!(0.0, drifts.get(&"USDT".into()).unwrap().amount);
+        assert!(drifts.get(&"ETH".into()).is_none());
+    }
+
+    #[test]
+    fn current_volume_and_notional() {
+        let mut position = new_losing_position(10.0);
+        let mut margin = SortedVec::new();
+        margin.insert_or_replace(AssetAmount {amount: 50.0, symbol: "USDT".into()});
+        let volume_at_entry = position.current_volume();
+        let notional_at_entry = position.current_notional();
+        position.add_margin(&margin, &SortedVec::new()).unwrap();
+
+        position.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 12.5, 12.5));
+
+        assert_eq!(10.0 * 100.0, volume_at_entry);
+        assert_eq!(volume_at_entry, notional_at_entry);
+        assert_eq!(volume_at_entry, position.current_volume());
+        assert_eq!(1250.0, position.current_notional());
+    }
+
+    #[test]
+    fn set_risk_limits_below_current_loss() {
+        let mut position = new_losing_position(9.5);
+        let reason_before = position.determine_close_reason();
+
+        position.set_risk_limits(40.0, 30.0).unwrap();
+
+        assert!(reason_before.is_none());
+        assert!(matches!(
+            position.determine_close_reason(),
+            Some(ClosePositionReason::StopOut)
+        ));
+    }
+
+    #[test]
+    fn set_risk_limits_invalid() {
+        let mut position = new_losing_position(9.5);
+
+        let margin_call_above_stop_out = position.set_risk_limits(40.0, 50.0);
+        let stop_out_above_100 = position.set_risk_limits(110.0, 50.0);
+        let zero_margin_call = position.set_risk_limits(40.0, 0.0);
+
+        assert!(matches!(margin_call_above_stop_out, Err(TradingError::InvalidRiskLimits { .. })));
+        assert!(matches!(stop_out_above_100, Err(TradingError::InvalidRiskLimits { .. })));
+        assert!(matches!(zero_margin_call, Err(TradingError::InvalidRiskLimits { .. })));
+        assert_eq!(90.0, position.order.stop_out_percent);
+    }
+
+    #[test]
+    fn close_priority_on_gap() {
+        let mut position = new_losing_position(10.0);
+        position.set_take_profit(Some(TakeProfitConfig {
+            unit: crate::orders::AutoClosePositionUnit::PricePercentUnit,
+            value: 5.0,
+        }));
+        position.set_stop_loss(Some(StopLossConfig {
+            unit: crate::orders::AutoClosePositionUnit::PriceRateUnit,
+            value: 11.0,
+        }));
+        position.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 10.8, 10.8));
+
+        let worst_reason = position.determine_close_reason();
+        position.order.close_priority = ClosePriority::BestForClient;
+        let best_reason = position.determine_close_reason();
+
+        assert!(matches!(worst_reason, Some(ClosePositionReason::StopLoss)));
+        assert!(matches!(best_reason, Some(ClosePositionReason::TakeProfit)));
+    }
+
+    #[test]
+    fn calc_pnls_by_assets_rounds_once() {
+        let instrument: InstrumentSymbol = "ATOMUSDT".into();
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+        let order = new_order(instrument.clone(), invest_assets.clone(), 1.0, OrderSide::Buy);
+        let bidask = BidAsk::new_synthetic(instrument, 10.0, 10.0);
+        let mut position = new_active_position(order, &bidask, &prices);
+        position.add_top_up(ActiveTopUp {
+            id: "1".into(),
+            date: DateTimeAsMicroseconds::now(),
+            total_assets: invest_assets,
+            instrument_price: 10.0,
+            asset_prices: prices,
+            bonus_assets: SortedVec::new(),
+        }).unwrap();
+        position.current_bidask = BidAsk::new_synthetic(position.order.instrument.clone(), 10.0019, 10.0019); // 0.019 pnl by order and by top-up
+
+        let pnls = position.calc_pnls_by_assets(Some(2));
+
+        assert_eq!(0.03, pnls.get(&"USDT".into()).unwrap().amount);
+    }
+
+    #[test]
+    fn liquidate_to_margin_partially() {
+        let mut position = new_losing_position(9.5); // 50% loss
+
+        let part = position
+            .liquidate_to_margin(20.0, ClosePositionReason::StopOut)
+            .unwrap();
+
+        assert_eq!(20.0, round(position.current_loss_percent, 8));
+        assert_eq!(-37.5, round(part.pnl.unwrap(), 8));
+        assert_eq!(37.5, round(part.total_invest_assets.get(&"USDT".into()).unwrap().amount, 8));
+        assert_eq!(62.5, round(position.total_invest_assets.get(&"USDT".into()).unwrap().amount, 8));
+    }
+
+    #[test]
+    fn liquidate_to_margin_fully() {
+        let mut position = new_losing_position(8.5); // 150% loss
+
+        let part = position
+            .liquidate_to_margin(20.0, ClosePositionReason::StopOut)
+            .unwrap();
+
+        assert_eq!(-150.0, round(part.pnl.unwrap(), 8));
+        assert!(position.total_invest_assets.is_empty());
+    }
+
+    #[test]
+    fn weighted_avg_close_price_of_slices() {
+        let mut position = new_losing_position(9.5);
+        position.close_part(0.5, ClosePositionReason::ClientCommand, None);
+        position.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 11.0, 11.0));
+
+        let last_part = position.close_part(1.0, ClosePositionReason::ClientCommand, None);
+
+        assert_eq!(10.25, round(last_part.weighted_avg_close_price(), 8));
     }
 
-    /// Calculates pnl by all invested assets, includes order, and top-ups
-    pub fn calc_pnls_by_assets(&self, pnl_accuracy: Option<u32>) -> SortedVec<AssetSymbol, AssetAmount> {
-        let mut asset_pnls: SortedVec<AssetSymbol, AssetAmount> = SortedVec::new_with_capacity(self.order.invest_assets.len() + 5);
+    #[test]
+    fn weighted_avg_close_price_of_single_close() {
+        let position = new_losing_position(9.5);
 
-        for item in self.calc_order_pnls_by_assets().iter() {
-            let asset_pnl: Option<&mut AssetAmount> = asset_pnls.get_mut(&item.symbol);
+        let closed_position = position.close(ClosePositionReason::ClientCommand, None);
 
-            if let Some(asset_pnl) = asset_pnl {
-                asset_pnl.amount += item.amount;
+        assert_eq!(closed_position.close_price, closed_position.weighted_avg_close_price());
+    }
 
-                if let Some(pnl_accuracy) = pnl_accuracy {
-                    asset_pnl.amount = floor(asset_pnl.amount, pnl_accuracy);
-                };
-            } else {
-                let amount = if let Some(pnl_accuracy) = pnl_accuracy {
-                    floor(item.amount, pnl_accuracy)
-                } else {
-                    item.amount
-                };
+    #[test]
+    fn bidask_bytes_round_trip() {
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 9.8, 9.9);
 
-                asset_pnls.insert_or_replace(assets::AssetAmount {symbol: item.symbol.clone(), amount});
-            }
-        }
+        let decoded = BidAsk::from_bytes(&bidask.to_bytes().unwrap()).unwrap();
 
-        for item in self.calc_top_ups_pnls_by_assets().iter() {
-            let asset_pnl: Option<&mut AssetAmount> = asset_pnls.get_mut(&item.symbol);
+        assert_eq!(bidask.instrument, decoded.instrument);
+        assert_eq!(bidask.bid, decoded.bid);
+        assert_eq!(bidask.ask, decoded.ask);
+        assert_eq!(bidask.datetime.unix_microseconds, decoded.datetime.unix_microseconds);
+    }
 
-            if let Some(asset_pnl) = asset_pnl {
-                asset_pnl.amount += item.amount;
+    #[test]
+    fn bidask_to_bytes_with_too_long_instrument() {
+        let instrument = "A".repeat(u16::MAX as usize + 1);
+        let bidask = BidAsk::new_synthetic(instrument.into(), 9.8, 9.9);
 
-                if let Some(pnl_accuracy) = pnl_accuracy {
-                    asset_pnl.amount = floor(asset_pnl.amount, pnl_accuracy);
-                };
-            } else {
-                let amount = if let Some(pnl_accuracy) = pnl_accuracy {
-                    floor(item.amount, pnl_accuracy)
-                } else {
-                    item.amount
-                };
+        assert!(matches!(bidask.to_bytes(), Err(TradingError::InvalidQuote(_))));
+    }
 
-                asset_pnls.insert_or_replace(assets::AssetAmount{symbol:item.symbol.clone(), amount});
-            }
+    #[test]
+    fn bidask_from_truncated_bytes() {
+        let bytes = BidAsk::new_synthetic("ATOMUSDT".into(), 9.8, 9.9).to_bytes().unwrap();
+
+        for len in [0, 1, 5, bytes.len() - 1] {
+            assert!(matches!(
+                BidAsk::from_bytes(&bytes[..len]),
+                Err(TradingError::InvalidQuote(_))
+            ));
         }
+    }
 
-        asset_pnls
+    #[test]
+    fn stop_out_at_stop_out_price() {
+        let mut position = new_losing_position(10.0);
+        position.order.stop_out_percent = 50.0;
+
+        let price = position.stop_out_price();
+        position.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 9.51, 9.51));
+        let is_stop_out_before = position.is_stop_out();
+        position.update(&BidAsk::new_synthetic("ATOMUSDT".into(), price, price));
+
+        assert_eq!(9.5, round(price, 8));
+        assert!(!is_stop_out_before);
+        assert!(position.is_stop_out());
     }
 
-    /// Calculates pnl by invested assets initially in order
-    pub fn calc_order_pnls_by_assets(&self) -> SortedVec<AssetSymbol, AssetAmount> {
-        let mut pnls_by_assets = SortedVec::new_with_capacity(self.order.invest_assets.len());
+    #[test]
+    fn pnl_by_original_asset_with_two_assets() {
+        let instrument: InstrumentSymbol = "ATOMUSDT".into();
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        prices.insert_or_replace(AssetPrice {price: 20000.0, symbol: "BTC".into()});
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+        invest_assets.insert_or_replace(AssetAmount {amount: 1.0, symbol: "BTC".into()});
+        let order = new_order(instrument.clone(), invest_assets, 1.0, OrderSide::Buy);
+        let mut position = new_active_position(order, &BidAsk::new_synthetic(instrument.clone(), 10.0, 10.0), &prices);
+        position.update(&BidAsk::new_synthetic(instrument, 11.0, 11.0));
 
-        for item in self.order.invest_assets.iter() {
-            let pnl = self.calculate_pnl(item.amount, self.activate_price);
+        let closed_position = position.close(ClosePositionReason::ClientCommand, None);
+        let pnls = closed_position.pnl_by_original_asset();
 
-            pnls_by_assets.insert_or_replace(assets::AssetAmount { amount:pnl, symbol: item.symbol.clone()});
-        }
+        assert_eq!(10.0, round(pnls.get(&"USDT".into()).unwrap().amount, 8));
+        assert_eq!(0.1, round(pnls.get(&"BTC".into()).unwrap().amount, 8));
+        assert_eq!(2010.0, round(closed_position.pnl.unwrap(), 8));
+    }
 
-        pnls_by_assets
+    #[test]
+    fn negative_balance_protection_on_gap() {
+        let mut position = new_losing_position(10.0);
+        position.order.negative_balance_protection = true;
+
+        position.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 5.0, 5.0));
+        let loss_percent = position.current_loss_percent;
+        let closed_position = position.close(ClosePositionReason::StopOut, None);
+
+        assert_eq!(100.0, loss_percent);
+        assert_eq!(-100.0, closed_position.pnl.unwrap());
     }
 
-    /// Calculates pnl by invested assets in top-ups
-    pub fn calc_top_ups_pnls_by_assets(&self) -> SortedVec<AssetSymbol, AssetAmount> {
-        let mut pnls_by_assets = SortedVec::new_with_capacity(10);
+    #[test]
+    fn update_stores_last_bidask() {
+        let mut position = new_losing_position(9.5);
 
-        for top_up in self.top_ups.iter() {
-            for item in top_up.total_assets.iter() {
-                let pnl = self.calculate_pnl(item.amount, top_up.instrument_price);
-                let max_loss_amount = item.amount * -1.0; // limit for isolated trade
-                let pnl = if pnl < max_loss_amount {
-                    max_loss_amount
-                } else {
-                    pnl
-                };
+        position.update(&BidAsk::new_synthetic("ATOMUSDT".into(), 9.8, 9.9));
 
-                let total_asset_pnl: Option<&mut AssetAmount> = pnls_by_assets.get_mut(&item.symbol);
-                
-                if let Some(total_asset_pnl) = total_asset_pnl {
-                    total_asset_pnl.amount += pnl;
-                } else {
-                    pnls_by_assets.insert_or_replace(assets::AssetAmount {amount:pnl, symbol: item.symbol.clone()});
-                }
-            }
-        }
+        assert_eq!(9.8, position.current_bidask.bid);
+        assert_eq!(9.9, position.current_bidask.ask);
+        assert_eq!(9.8, position.current_price());
+    }
 
-        pnls_by_assets
+    #[test]
+    fn add_top_up_over_limit() {
+        let mut position = new_losing_position(9.9);
+        position.order.top_up_enabled = true;
+        position.order.max_top_ups = Some(1);
+
+        let is_top_up_before = position.is_top_up();
+        let first_result = position.add_top_up(new_top_up(&position));
+        let second_result = position.add_top_up(new_top_up(&position));
+
+        assert!(is_top_up_before);
+        assert!(first_result.is_ok());
+        assert_eq!(Err(TradingError::TopUpsLimitReached(1)), second_result);
+        assert_eq!(1, position.top_up_count());
+        assert!(!position.is_top_up());
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct ClosedPosition {
-    pub id: PositionId,
-    pub order: Order,
-    pub open_price: f64,
-    pub open_date: DateTimeAsMicroseconds,
-    pub open_asset_prices: SortedVec<AssetSymbol, AssetPrice>,
-    pub activate_price: Option<f64>,
-    pub activate_date: Option<DateTimeAsMicroseconds>,
-    pub activate_asset_prices: SortedVec<AssetSymbol, AssetPrice>,
-    pub close_price: f64,
-    pub close_date: DateTimeAsMicroseconds,
-    pub close_reason: ClosePositionReason,
-    pub close_asset_prices: SortedVec<AssetSymbol, AssetPrice>,
-    pub pnl: Option<f64>,
-    pub asset_pnls: SortedVec<AssetSymbol, AssetAmount>,
-    pub top_ups: Vec<ActiveTopUp>,
-    pub total_invest_assets: SortedVec<AssetSymbol, AssetAmount>,
-    pub invest_bonus_assets: SortedVec<AssetSymbol, AssetAmount>,
-}
+    #[test]
+    fn total_top_up_invest() {
+        let mut position = new_losing_position(9.9);
+        let first_top_up = new_top_up(&position);
+        let mut second_top_up = new_top_up(&position);
+        second_top_up.total_assets.insert_or_replace(AssetAmount {amount: 2.5, symbol: "USDT".into()});
+        let ids = vec![first_top_up.id.to_string(), second_top_up.id.to_string()];
+        position.add_top_up(first_top_up).unwrap();
+        position.add_top_up(second_top_up).unwrap();
+
+        let total = position.total_top_up_invest();
+
+        assert_eq!(1, total.len());
+        assert_eq!(3.5, total.get(&"USDT".into()).unwrap().amount);
+        assert_eq!(ids, position.top_up_ids());
+        assert_eq!(103.5, position.total_invest_assets.get(&"USDT".into()).unwrap().amount);
+    }
 
-impl ClosedPosition {
-    pub fn get_status(&self) -> PositionStatus {
-        if self.total_invest_assets.is_empty() {
-            PositionStatus::Canceled
-        } else {
-            PositionStatus::Filled
+    #[test]
+    fn price_for_target_pnl() {
+        let mut buy_position = new_losing_position(9.9);
+        buy_position.add_top_up(new_top_up(&buy_position)).unwrap();
+        let mut sell_position = new_losing_position(10.0);
+        sell_position.order.side = OrderSide::Sell;
+
+        let buy_price = buy_position.price_for_target_pnl(50.0);
+        let sell_price = sell_position.price_for_target_pnl(50.0);
+        buy_position.update(&BidAsk::new_synthetic("ATOMUSDT".into(), buy_price, buy_price));
+        sell_position.update(&BidAsk::new_synthetic("ATOMUSDT".into(), sell_price, sell_price));
+
+        assert!(buy_price > 10.0);
+        assert_eq!(9.5, round(sell_price, 8));
+        assert!((buy_position.current_pnl - 50.0).abs() < 1e-9);
+        assert!((sell_position.current_pnl - 50.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn canceled_top_ups_kept_on_close() {
+        let mut position = new_losing_position(9.9);
+        let mut top_up = new_top_up(&position);
+        top_up.date = DateTimeAsMicroseconds::now().sub(Duration::from_secs(10));
+        top_up.instrument_price = 9.0;
+        let top_up_id = top_up.id.clone();
+        position.add_top_up(top_up).unwrap();
+
+        let canceled_top_ups = position.try_cancel_top_ups(5.0, Duration::from_secs(1));
+        let closed_position = position.close(ClosePositionReason::ClientCommand, None);
+
+        assert_eq!(1, canceled_top_ups.len());
+        assert!(closed_position.top_ups.is_empty());
+        assert_eq!(1, closed_position.canceled_top_ups.len());
+        assert_eq!(top_up_id, closed_position.canceled_top_ups[0].id);
+        assert_eq!(9.9, closed_position.canceled_top_ups[0].cancel_instrument_price);
+    }
+
+    fn new_top_up(position: &ActivePosition) -> ActiveTopUp {
+        let mut total_assets = SortedVec::new();
+        total_assets.insert_or_replace(AssetAmount{ amount: 1.0, symbol: "USDT".into()});
+
+        ActiveTopUp {
+            id: Uuid::new_v4().to_string().into(),
+            date: DateTimeAsMicroseconds::now(),
+            total_assets,
+            instrument_price: position.current_price(),
+            asset_prices: position.current_asset_prices.clone(),
+            bonus_assets: SortedVec::new(),
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::{ActivePosition, ClosePositionReason};
-    use crate::{assets, orders::{Order, OrderSide, TakeProfitConfig}, positions::{BidAsk, Position}};
-    use rust_extensions::date_time::DateTimeAsMicroseconds;
-    use rust_extensions::sorted_vec::SortedVec;
-    use uuid::Uuid;
-    use crate::asset_symbol::AssetSymbol;
-    use crate::assets::{AssetAmount, AssetPrice};
-    use crate::instrument_symbol::InstrumentSymbol;
-    use crate::top_ups::ActiveTopUp;
+    fn new_losing_position(price: f64) -> ActivePosition {
+        let instrument: InstrumentSymbol = "ATOMUSDT".into();
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+        let order = new_order(instrument.clone(), invest_assets, 10.0, OrderSide::Buy);
+        let bidask = BidAsk::new_synthetic(instrument.clone(), 10.0, 10.0);
+        let mut position = new_active_position(order, &bidask, &prices);
+        position.update(&BidAsk::new_synthetic(instrument, price, price));
 
-    #[tokio::test]
-    async fn close_active_position() {
+        position
+    }
+
+    #[test]
+    fn project_pnl_at_current_price() {
+        let mut position = new_losing_position(9.5);
+        let mut total_assets = SortedVec::new();
+        total_assets.insert_or_replace(AssetAmount{ amount: 50.0, symbol: "USDT".into()});
+        position.add_top_up(ActiveTopUp {
+            id: "1".into(),
+            date: DateTimeAsMicroseconds::now(),
+            total_assets,
+            instrument_price: 9.7,
+            asset_prices: position.current_asset_prices.clone(),
+            bonus_assets: SortedVec::new(),
+        }).unwrap();
+        let current_price = position.current_price();
+
+        assert_eq!(position.current_pnl, position.project_pnl(current_price));
+        assert_eq!(position.current_loss_percent, position.project_loss_percent(current_price));
+        assert_eq!(current_price, position.current_price());
+    }
+
+    #[test]
+    fn pnl_scales_with_contract_size() {
+        let position = new_losing_position(9.5);
+        let mut contract_position = position.clone();
+        contract_position.order.contract_size = 100.0;
+
+        assert_eq!(
+            round(position.project_pnl(9.0) * 100.0, 8),
+            round(contract_position.project_pnl(9.0), 8)
+        );
+    }
+
+    #[test]
+    fn stop_buy_activates_with_slippage() {
+        let instrument: InstrumentSymbol = "ATOMUSDT".into();
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
         let mut invest_assets = SortedVec::new();
-        invest_assets.insert_or_replace(assets::AssetAmount{ amount: 100.0, symbol: "BTC".into()});
-        let order = Order {
-            base_asset: "USDT".into(),
-            id: "test".to_string(),
-            instrument: "ATOMUSDT".into(),
-            trader_id: "test".to_string(),
-            wallet_id: Uuid::new_v4().into(),
-            created_date: DateTimeAsMicroseconds::now(),
-            desire_price: None,
-            funding_fee_period: None,
-            invest_assets,
-            leverage: 1.0,
-            side: OrderSide::Buy,
-            take_profit: None,
-            stop_loss: None,
-            stop_out_percent: 10.0,
-            margin_call_percent: 10.0,
-            top_up_enabled: false,
-            top_up_percent: 10.0,
+        invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+        let mut order = new_order(instrument.clone(), invest_assets.clone(), 1.0, OrderSide::Buy);
+        order.desire_price = Some(26000.00);
+        order.order_type = OrderType::Limit;
+        order.slippage_percent = 1.0;
+        let bidask = BidAsk::new_synthetic(instrument.clone(), 25900.00, 25900.00);
+        let Position::Pending(mut pending_position) = order.open(&bidask, &prices, None).unwrap() else {
+            panic!("Must be pending position");
         };
+        pending_position.add_invest_assets(&invest_assets).unwrap();
+        pending_position.update(&BidAsk::new_synthetic(instrument, 26000.00, 26000.00));
+
+        let position = pending_position.activate().unwrap();
+
+        assert_eq!(26260.0, round(position.activate_price, 8));
+    }
+
+    #[test]
+    fn pip_value_with_non_base_quote() {
         let mut prices = SortedVec::new();
-        prices.insert_or_replace(assets::AssetPrice{ price: 22300.0, symbol: "BTC".into()});
-        let bidask = BidAsk {
-            ask: 14.748,
-            bid: 14.748,
-            datetime: DateTimeAsMicroseconds::now(),
-            instrument: "ATOMUSDT".into(),
-        };
-        let position = order.open(&bidask, &prices);
-        let mut position = match position {
-            Position::Active(position) => position,
-            _ => {
-                panic!("Invalid position")
-            }
-        };
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+        let mut order = new_order("BTCJPY".into(), invest_assets, 10.0, OrderSide::Buy);
+        order.quote_asset = "JPY".into();
+        let bidask = BidAsk::new_synthetic("BTCJPY".into(), 100.0, 100.0);
+        let mut position = new_active_position(order, &bidask, &prices);
+        position.current_asset_prices.insert_or_replace(AssetPrice {price: 0.01, symbol: "JPY".into()});
+
+        let value = position.pip_value(0.01);
+
+        // volume 10 * pip 0.01 * 0.01 JPY rate
+        assert_eq!(0.001, round(value, 8));
+    }
+
+    #[test]
+    #[should_panic(expected = "Price not found for JPY")]
+    fn pip_value_without_quote_price() {
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+        let mut order = new_order("BTCJPY".into(), invest_assets, 10.0, OrderSide::Buy);
+        order.quote_asset = "JPY".into();
+        let bidask = BidAsk::new_synthetic("BTCJPY".into(), 100.0, 100.0);
+        let position = new_active_position(order, &bidask, &prices);
+
+        position.pip_value(0.01);
+    }
+
+    #[test]
+    fn pip_value_with_base_quote() {
+        let position = new_losing_position(10.0);
+
+        let value = position.pip_value(0.01);
+        let expected = position.current_volume() / position.current_price() * 0.01;
+
+        assert_eq!(round(expected, 8), round(value, 8));
+    }
+
+    #[test]
+    fn weighted_avg_entry_price_without_top_ups() {
+        let position = new_losing_position(9.5);
+
+        assert_eq!(position.activate_price, position.weighted_avg_entry_price());
+    }
+
+    #[test]
+    fn weighted_avg_entry_price_with_top_ups() {
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+        let mut order = new_order("ATOMUSDT".into(), invest_assets, 10.0, OrderSide::Sell);
+        order.top_up_enabled = true;
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 0.33, 0.33);
+        let mut position = new_active_position(order, &bidask, &prices);
+
+        for (id, instrument_price) in [("1", 0.36), ("2", 0.39)] {
+            let mut total_assets = SortedVec::new();
+            total_assets.insert_or_replace(AssetAmount {amount: 50.0, symbol: "USDT".into()});
+            position.add_top_up(ActiveTopUp {
+                id: id.into(),
+                date: DateTimeAsMicroseconds::now(),
+                total_assets,
+                instrument_price,
+                asset_prices: prices.clone(),
+                bonus_assets: SortedVec::new(),
+            }).unwrap();
+        }
+
+        // (100 * 0.33 + 50 * 0.36 + 50 * 0.39) / 200
+        assert_eq!(0.3525, round(position.weighted_avg_entry_price(), 8));
+    }
+
+    #[test]
+    fn closed_position_net_pnl() {
+        let mut position = new_losing_position(9.5);
+
+        for _ in 0..3 {
+            position.charge_swap(1.5);
+        }
 
-        position.current_price = 14.75;
         let closed_position = position.close(ClosePositionReason::ClientCommand, None);
 
-        let pnl = closed_position.pnl.unwrap();
-        let asset_pnl = closed_position.asset_pnls.get(&AssetSymbol("BTC".into())).clone().unwrap();
+        assert_eq!(4.5, closed_position.total_swap);
+        assert_eq!(
+            Some(closed_position.pnl.unwrap() - 4.5),
+            closed_position.net_pnl()
+        );
+    }
 
-        assert_ne!(pnl, asset_pnl.amount);
-        assert_eq!(302.41388662883173, pnl);
-        assert_eq!(0.01356116083537362, asset_pnl.amount);
+    #[test]
+    fn close_checked_with_missing_price() {
+        let mut position = new_losing_position(9.5);
+        position.current_asset_prices = SortedVec::new();
+
+        let result = position.close_checked(ClosePositionReason::ClientCommand, None);
+
+        assert!(matches!(result, Err(TradingError::PriceNotFound(symbol)) if symbol == "USDT".into()));
+    }
+
+    #[test]
+    fn active_position_holding_duration() {
+        let mut position = new_losing_position(9.5);
+        let now = DateTimeAsMicroseconds::now();
+        position.activate_date = now.sub(Duration::from_secs(60));
+
+        assert_eq!(Duration::from_secs(60), position.holding_duration(now));
+    }
+
+    #[test]
+    fn closed_position_holding_duration() {
+        let position = new_losing_position(9.5);
+        let mut closed_position = position.close(ClosePositionReason::ClientCommand, None);
+        closed_position.activate_date = Some(closed_position.close_date.sub(Duration::from_secs(3600)));
+
+        assert_eq!(Some(Duration::from_secs(3600)), closed_position.holding_duration());
+
+        closed_position.activate_date = None;
+
+        assert_eq!(None, closed_position.holding_duration());
     }
 
     #[tokio::test]
@@ -894,7 +2797,7 @@ mod tests {
             value: 13.817,
         };
         position.set_take_profit(Some(take_profit));
-        position.current_price = 13.817;
+        position.current_bidask = BidAsk::new_synthetic(position.order.instrument.clone(), 13.817, 13.817);
 
         let position = position.try_close(None);
         let _position = match position {
@@ -951,35 +2854,35 @@ mod tests {
         total_assets.insert_or_replace(AssetAmount{ amount: 50.0, symbol: "USDT".into()});
         let mut position = new_active_position(order, &bidask, &prices);
         position.add_top_up(ActiveTopUp {
-            id: "1".to_string(),
+            id: "1".into(),
             date: DateTimeAsMicroseconds::now(),
             total_assets,
             instrument_price: 0.354,
             asset_prices: prices.clone(),
             bonus_assets: SortedVec::new(),
-        });
+        }).unwrap();
 
         let mut total_assets = SortedVec::new();
         total_assets.insert_or_replace(AssetAmount{ amount: 75.0, symbol: "USDT".into()});
         position.add_top_up(ActiveTopUp {
-            id: "2".to_string(),
+            id: "2".into(),
             date: DateTimeAsMicroseconds::now(),
             total_assets,
             instrument_price: 0.355,
             asset_prices: prices.clone(),
             bonus_assets: SortedVec::new(),
-        });
+        }).unwrap();
         
         let mut total_assets = SortedVec::new();
         total_assets.insert_or_replace(AssetAmount{ amount: 112.5, symbol: "USDT".into()});
         position.add_top_up(ActiveTopUp {
-            id: "3".to_string(),
+            id: "3".into(),
             date: DateTimeAsMicroseconds::now(),
             total_assets,
             instrument_price: 0.37,
             asset_prices: prices,
             bonus_assets: SortedVec::new(),
-        });
+        }).unwrap();
         position.update(&BidAsk {
             ask: 0.37,
             bid: 0.37,
@@ -1001,13 +2904,14 @@ mod tests {
         invest_assets.insert_or_replace(assets::AssetAmount {amount: 100342.0, symbol: "USDT".into()});
         let mut order = new_order(instrument.clone(), invest_assets, 1.0, OrderSide::Buy);
         order.desire_price = Some(26000.00);
+        order.order_type = OrderType::Limit;
         let bidask = BidAsk {
             ask: 25900.00,
             bid: 25900.00,
             datetime: DateTimeAsMicroseconds::now(),
             instrument,
         };
-        let position = order.open(&bidask, &prices);
+        let position = order.open(&bidask, &prices, None).unwrap();
         let Position::Pending(pending_position) = position else {
             panic!("Must be pending position");
         };
@@ -1027,17 +2931,18 @@ mod tests {
         
         let mut order = new_order(instrument.clone(), invest_assets, 1.0, OrderSide::Buy);
         order.desire_price = Some(26000.00);
+        order.order_type = OrderType::Limit;
         let bidask = BidAsk {
             ask: 25900.00,
             bid: 25900.00,
             datetime: DateTimeAsMicroseconds::now(),
             instrument,
         };
-        let position = order.open(&bidask, &prices);
+        let position = order.open(&bidask, &prices, None).unwrap();
         let Position::Pending(mut pending_position) = position else {
             panic!("Must be pending position");
         };
-        pending_position.current_price = 26100.00;
+        pending_position.current_bidask = BidAsk::new_synthetic(pending_position.order.instrument.clone(), 26100.00, 26100.00);
 
         let is_price_reached = pending_position.is_price_reached();
 
@@ -1054,23 +2959,145 @@ mod tests {
         
         let mut order = new_order(instrument.clone(), invest_assets, 1.0, OrderSide::Buy);
         order.desire_price = Some(25000.00);
+        order.order_type = OrderType::Limit;
         let bidask = BidAsk {
             ask: 25900.00,
             bid: 25900.00,
             datetime: DateTimeAsMicroseconds::now(),
             instrument,
         };
-        let position = order.open(&bidask, &prices);
+        let position = order.open(&bidask, &prices, None).unwrap();
         let Position::Pending(mut pending_position) = position else {
             panic!("Must be pending position");
         };
-        pending_position.current_price = 24100.00;
+        pending_position.current_bidask = BidAsk::new_synthetic(pending_position.order.instrument.clone(), 24100.00, 24100.00);
 
         let is_price_reached = pending_position.is_price_reached();
 
         assert!(is_price_reached);
     }
 
+    #[test]
+    fn amend_pending() {
+        let instrument: InstrumentSymbol = "ATOMUSDT".into();
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+        let mut order = new_order(instrument.clone(), invest_assets, 1.0, OrderSide::Buy);
+        order.desire_price = Some(9.0);
+        order.order_type = OrderType::Limit;
+        let Position::Pending(mut pending_position) = order.open(&BidAsk::new_synthetic(instrument.clone(), 10.0, 10.0), &prices, None).unwrap() else {
+            panic!("Must be pending position");
+        };
+        pending_position.update(&BidAsk::new_synthetic(instrument, 9.2, 9.2));
+        let is_reached_before = pending_position.is_price_reached();
+        let mut new_assets = SortedVec::new();
+        new_assets.insert_or_replace(AssetAmount {amount: 200.0, symbol: "USDT".into()});
+        let mut unknown_assets = SortedVec::new();
+        unknown_assets.insert_or_replace(AssetAmount {amount: 1.0, symbol: "BTC".into()});
+
+        pending_position.amend(Some(9.5), Some(new_assets)).unwrap();
+        let invalid_result = pending_position.amend(Some(-1.0), None);
+        let unknown_asset_result = pending_position.amend(Some(9.0), Some(unknown_assets));
+
+        assert!(!is_reached_before);
+        assert!(pending_position.is_price_reached());
+        assert_eq!(Some(9.5), pending_position.order.desire_price);
+        assert_eq!(200.0, pending_position.order.invest_assets.get(&"USDT".into()).unwrap().amount);
+        assert_eq!(Err(TradingError::InvalidDesirePrice(-1.0)), invalid_result.map(|_| ()));
+        assert_eq!(Err(TradingError::PriceNotFound("BTC".into())), unknown_asset_result.map(|_| ()));
+    }
+
+    #[test]
+    fn amend_reserved_pending_size() {
+        let instrument: InstrumentSymbol = "ATOMUSDT".into();
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+        let mut order = new_order(instrument.clone(), invest_assets.clone(), 1.0, OrderSide::Buy);
+        order.desire_price = Some(9.0);
+        order.order_type = OrderType::Limit;
+        let Position::Pending(mut pending_position) = order.open(&BidAsk::new_synthetic(instrument.clone(), 10.0, 10.0), &prices, None).unwrap() else {
+            panic!("Must be pending position");
+        };
+        pending_position.add_invest_assets(&invest_assets).unwrap();
+        let mut new_assets = SortedVec::new();
+        new_assets.insert_or_replace(AssetAmount {amount: 150.0, symbol: "USDT".into()});
+
+        let reservation_delta = pending_position.amend(None, Some(new_assets)).unwrap();
+        pending_position.update(&BidAsk::new_synthetic(instrument, 8.5, 8.5));
+        let active_position = pending_position.activate().unwrap();
+
+        assert_eq!(50.0, reservation_delta.get(&"USDT".into()).unwrap().amount);
+        assert_eq!(150.0, active_position.order.invest_assets.get(&"USDT".into()).unwrap().amount);
+    }
+
+    #[test]
+    fn release_reserved_assets() {
+        let instrument: InstrumentSymbol = "ATOMUSDT".into();
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(AssetAmount {amount: 40.0, symbol: "USDT".into()});
+        let mut order = new_order(instrument.clone(), invest_assets.clone(), 1.0, OrderSide::Buy);
+        order.desire_price = Some(9.0);
+        order.order_type = OrderType::Limit;
+        let Position::Pending(mut pending_position) = order.open(&BidAsk::new_synthetic(instrument, 10.0, 10.0), &prices, None).unwrap() else {
+            panic!("Must be pending position");
+        };
+        pending_position.add_invest_assets(&invest_assets).unwrap();
+        pending_position.add_invest_assets(&invest_assets).unwrap();
+
+        let released_assets = pending_position.release_assets();
+
+        assert_eq!(1, released_assets.len());
+        assert_eq!(80.0, released_assets.get(&"USDT".into()).unwrap().amount);
+    }
+
+    #[test]
+    fn limit_buy_activates_at_gapped_price() {
+        let instrument: InstrumentSymbol = "ATOMUSDT".into();
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+        let mut order = new_order(instrument.clone(), invest_assets, 1.0, OrderSide::Buy);
+        order.desire_price = Some(9.0);
+        order.order_type = OrderType::Limit;
+        let Position::Pending(mut pending_position) = order.open(&BidAsk::new_synthetic(instrument.clone(), 10.0, 10.0), &prices, None).unwrap() else {
+            panic!("Must be pending position");
+        };
+        pending_position.add_invest_assets(&pending_position.order.invest_assets.clone()).unwrap();
+        pending_position.update(&BidAsk::new_synthetic(instrument, 8.5, 8.5));
+
+        let active_position = pending_position.activate().unwrap();
+
+        assert_eq!(8.5, active_position.activate_price);
+    }
+
+    #[test]
+    fn limit_sell_activates_at_gapped_price() {
+        let instrument: InstrumentSymbol = "ATOMUSDT".into();
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        let mut invest_assets = SortedVec::new();
+        invest_assets.insert_or_replace(AssetAmount {amount: 100.0, symbol: "USDT".into()});
+        let mut order = new_order(instrument.clone(), invest_assets, 1.0, OrderSide::Sell);
+        order.desire_price = Some(11.0);
+        order.order_type = OrderType::Limit;
+        let Position::Pending(mut pending_position) = order.open(&BidAsk::new_synthetic(instrument.clone(), 10.0, 10.0), &prices, None).unwrap() else {
+            panic!("Must be pending position");
+        };
+        pending_position.add_invest_assets(&pending_position.order.invest_assets.clone()).unwrap();
+        pending_position.update(&BidAsk::new_synthetic(instrument, 11.5, 11.5));
+
+        let active_position = pending_position.activate().unwrap();
+
+        assert_eq!(11.5, active_position.activate_price);
+    }
+
     #[tokio::test]
     async fn limit_buy_not_reached() {
         let instrument: InstrumentSymbol = "ATOMUSDT".into();
@@ -1081,17 +3108,18 @@ mod tests {
         
         let mut order = new_order(instrument.clone(), invest_assets, 1.0, OrderSide::Buy);
         order.desire_price = Some(25000.00);
+        order.order_type = OrderType::Limit;
         let bidask = BidAsk {
             ask: 25900.00,
             bid: 25900.00,
             datetime: DateTimeAsMicroseconds::now(),
             instrument,
         };
-        let position = order.open(&bidask, &prices);
+        let position = order.open(&bidask, &prices, None).unwrap();
         let Position::Pending(mut pending_position) = position else {
             panic!("Must be pending position");
         };
-        pending_position.current_price = 26100.00;
+        pending_position.current_bidask = BidAsk::new_synthetic(pending_position.order.instrument.clone(), 26100.00, 26100.00);
 
         let is_price_reached = pending_position.is_price_reached();
 
@@ -1108,13 +3136,14 @@ mod tests {
         
         let mut order = new_order(instrument.clone(), invest_assets, 1.0, OrderSide::Sell);
         order.desire_price = Some(26000.00);
+        order.order_type = OrderType::Limit;
         let bidask = BidAsk {
             ask: 25900.00,
             bid: 25900.00,
             datetime: DateTimeAsMicroseconds::now(),
             instrument,
         };
-        let position = order.open(&bidask, &prices);
+        let position = order.open(&bidask, &prices, None).unwrap();
         let Position::Pending(pending_position) = position else {
             panic!("Must be pending position");
         };
@@ -1134,17 +3163,18 @@ mod tests {
         
         let mut order = new_order(instrument.clone(), invest_assets, 1.0, OrderSide::Sell);
         order.desire_price = Some(26000.00);
+        order.order_type = OrderType::Limit;
         let bidask = BidAsk {
             ask: 25900.00,
             bid: 25900.00,
             datetime: DateTimeAsMicroseconds::now(),
             instrument,
         };
-        let position = order.open(&bidask, &prices);
+        let position = order.open(&bidask, &prices, None).unwrap();
         let Position::Pending(mut pending_position) = position else {
             panic!("Must be pending position");
         };
-        pending_position.current_price = 26100.00;
+        pending_position.current_bidask = BidAsk::new_synthetic(pending_position.order.instrument.clone(), 26100.00, 26100.00);
 
         let is_price_reached = pending_position.is_price_reached();
 
@@ -1161,17 +3191,18 @@ mod tests {
         
         let mut order = new_order(instrument.clone(), invest_assets, 1.0, OrderSide::Sell);
         order.desire_price = Some(25000.00);
+        order.order_type = OrderType::Limit;
         let bidask = BidAsk {
             ask: 25900.00,
             bid: 25900.00,
             datetime: DateTimeAsMicroseconds::now(),
             instrument,
         };
-        let position = order.open(&bidask, &prices);
+        let position = order.open(&bidask, &prices, None).unwrap();
         let Position::Pending(mut pending_position) = position else {
             panic!("Must be pending position");
         };
-        pending_position.current_price = 24900.00;
+        pending_position.current_bidask = BidAsk::new_synthetic(pending_position.order.instrument.clone(), 24900.00, 24900.00);
 
         let is_price_reached = pending_position.is_price_reached();
 
@@ -1188,17 +3219,18 @@ mod tests {
         
         let mut order = new_order(instrument.clone(), invest_assets, 1.0, OrderSide::Sell);
         order.desire_price = Some(25000.00);
+        order.order_type = OrderType::Limit;
         let bidask = BidAsk {
             ask: 25900.00,
             bid: 25900.00,
             datetime: DateTimeAsMicroseconds::now(),
             instrument,
         };
-        let position = order.open(&bidask, &prices);
+        let position = order.open(&bidask, &prices, None).unwrap();
         let Position::Pending(mut pending_position) = position else {
             panic!("Must be pending position");
         };
-        pending_position.current_price = 25900.00;
+        pending_position.current_bidask = BidAsk::new_synthetic(pending_position.order.instrument.clone(), 25900.00, 25900.00);
 
         let is_price_reached = pending_position.is_price_reached();
 
@@ -1212,23 +3244,11 @@ mod tests {
         side: OrderSide,
     ) -> Order {
         Order {
-            base_asset: "USDT".into(),
-            id: "test".to_string(),
             instrument,
-            trader_id: "test".to_string(),
-            wallet_id: Uuid::new_v4().into(),
-            created_date: DateTimeAsMicroseconds::now(),
-            desire_price: None,
-            funding_fee_period: None,
             invest_assets,
             leverage,
             side,
-            take_profit: None,
-            stop_loss: None,
-            stop_out_percent: 90.0,
-            margin_call_percent: 70.0,
-            top_up_enabled: false,
-            top_up_percent: 10.0,
+            ..new_test_order()
         }
     }
 
@@ -1247,10 +3267,11 @@ mod tests {
             activate_price: bidask.get_open_price(&order.side),
             activate_date: now,
             activate_asset_prices: asset_prices.to_owned(),
-            current_price: bidask.get_close_price(&order.side),
+            current_bidask: bidask.to_owned(),
             current_asset_prices: asset_prices.to_owned(),
             last_update_date: now,
             top_ups: Vec::new(),
+            canceled_top_ups: Vec::new(),
             current_pnl: 0.0,
             current_loss_percent: 0.0,
             prev_loss_percent: 0.0,
@@ -1258,6 +3279,8 @@ mod tests {
             total_invest_assets: order.invest_assets.clone(),
             order,
             bonus_invest_assets: SortedVec::new(),
+            total_swap: 0.0,
+            close_prices: Vec::new(),
         }
     }
 }
@@ -0,0 +1,22 @@
+//! Position predicates for PositionsMonitor::query. Combine them inside a closure:
+//! `monitor.query(|position| by_side(OrderSide::Buy)(position) && loss_above(50.0)(position))`
+
+use crate::instrument_symbol::InstrumentSymbol;
+use crate::orders::OrderSide;
+use crate::positions::Position;
+
+pub fn by_side(side: OrderSide) -> impl Fn(&Position) -> bool {
+    move |position| position.get_order().side == side
+}
+
+pub fn by_instrument(instrument: InstrumentSymbol) -> impl Fn(&Position) -> bool {
+    move |position| position.get_order().instrument == instrument
+}
+
+/// Matches active positions with current loss percent above value
+pub fn loss_above(loss_percent: f64) -> impl Fn(&Position) -> bool {
+    move |position| match position {
+        Position::Active(position) => position.current_loss_percent > loss_percent,
+        _ => false,
+    }
+}
@@ -0,0 +1,79 @@
+//! Serde helpers for types of dependencies without serde support, used with `#[serde(with)]`
+
+pub mod micros {
+    use rust_extensions::date_time::DateTimeAsMicroseconds;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        value: &DateTimeAsMicroseconds,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(value.unix_microseconds)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTimeAsMicroseconds, D::Error> {
+        let micros = i64::deserialize(deserializer)?;
+
+        Ok(DateTimeAsMicroseconds::new(micros))
+    }
+}
+
+pub mod option_micros {
+    use rust_extensions::date_time::DateTimeAsMicroseconds;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        value: &Option<DateTimeAsMicroseconds>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value
+            .map(|value| value.unix_microseconds)
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTimeAsMicroseconds>, D::Error> {
+        let micros = Option::<i64>::deserialize(deserializer)?;
+
+        Ok(micros.map(DateTimeAsMicroseconds::new))
+    }
+}
+
+/// Serializes SortedVec as sequence of its items
+pub mod sorted_vec {
+    use rust_extensions::sorted_vec::{EntityWithKey, SortedVec};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S, TKey, TValue>(
+        value: &SortedVec<TKey, TValue>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        TKey: Ord,
+        TValue: EntityWithKey<TKey> + Serialize,
+    {
+        serializer.collect_seq(value.iter())
+    }
+
+    pub fn deserialize<'de, D, TKey, TValue>(
+        deserializer: D,
+    ) -> Result<SortedVec<TKey, TValue>, D::Error>
+    where
+        D: Deserializer<'de>,
+        TKey: Ord,
+        TValue: EntityWithKey<TKey> + Deserialize<'de>,
+    {
+        let items = Vec::<TValue>::deserialize(deserializer)?;
+        let mut value = SortedVec::new_with_capacity(items.len());
+
+        for item in items {
+            value.insert_or_replace(item);
+        }
+
+        Ok(value)
+    }
+}
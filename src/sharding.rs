@@ -1,15 +1,17 @@
+/// Returns shard index by first 8 bytes of key. Bytes are accumulated into u64 so index is the
+/// same on 32-bit and 64-bit targets
 pub fn get_index(s: &str, count: usize) -> usize {
-    let mut result: usize = 0;
-    
     if count <= 1 {
-        return result;
+        return 0;
     }
 
+    let mut result: u64 = 0;
+
     for byte in s.bytes().take(8) {
-        result = (result << 8) | (byte as usize);
+        result = (result << 8) | (byte as u64);
     }
 
-    result % count
+    (result % count as u64) as usize
 }
 
 #[cfg(test)]
@@ -49,6 +51,13 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_index_is_platform_independent() {
+        // first 8 bytes "BTCUSDT-" as big endian u64 are 4779519138295206957
+        assert_eq!(1, get_index("BTCUSDT-wallet", 7));
+        assert_eq!(957, get_index("BTCUSDT-wallet", 1000));
+    }
+
     #[test]
     fn test_index_distribution() {
         let iterations = 100000;
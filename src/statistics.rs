@@ -0,0 +1,123 @@
+use crate::positions::{ClosedPosition, PositionStatus};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PositionStats {
+    pub total_pnl: f64,
+    pub win_count: usize,
+    pub loss_count: usize,
+    /// Average pnl of winning positions
+    pub average_win: f64,
+    /// Average pnl of losing positions, negative value
+    pub average_loss: f64,
+    /// Largest peak-to-trough decline of cumulative pnl in order of positions
+    pub max_drawdown: f64,
+}
+
+impl PositionStats {
+    pub fn from_closed(positions: &[ClosedPosition]) -> PositionStats {
+        let mut stats = PositionStats::default();
+        let mut total_win = 0.0;
+        let mut total_loss = 0.0;
+        let mut peak_pnl: f64 = 0.0;
+
+        for position in positions {
+            if position.get_status() == PositionStatus::Canceled {
+                continue;
+            }
+
+            let Some(pnl) = position.pnl else {
+                continue;
+            };
+
+            stats.total_pnl += pnl;
+
+            if pnl > 0.0 {
+                stats.win_count += 1;
+                total_win += pnl;
+            } else if pnl < 0.0 {
+                stats.loss_count += 1;
+                total_loss += pnl;
+            }
+
+            peak_pnl = peak_pnl.max(stats.total_pnl);
+            stats.max_drawdown = stats.max_drawdown.max(peak_pnl - stats.total_pnl);
+        }
+
+        if stats.win_count > 0 {
+            stats.average_win = total_win / stats.win_count as f64;
+        }
+
+        if stats.loss_count > 0 {
+            stats.average_loss = total_loss / stats.loss_count as f64;
+        }
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PositionStats;
+    use crate::assets::AssetPrice;
+    use crate::orders::{new_test_order, Order, OrderType};
+    use crate::positions::{BidAsk, ClosePositionReason, ClosedPosition, Position};
+    use rust_extensions::sorted_vec::SortedVec;
+
+    #[test]
+    fn stats_from_closed() {
+        let positions = vec![
+            new_filled(10.0),
+            new_filled(-5.0),
+            new_canceled(),
+            new_filled(20.0),
+            new_filled(-15.0),
+            new_filled(-10.0),
+        ];
+
+        let stats = PositionStats::from_closed(&positions);
+
+        assert_eq!(0.0, stats.total_pnl);
+        assert_eq!(2, stats.win_count);
+        assert_eq!(3, stats.loss_count);
+        assert_eq!(15.0, stats.average_win);
+        assert_eq!(-10.0, stats.average_loss);
+        assert_eq!(25.0, stats.max_drawdown);
+    }
+
+    fn new_order(desire_price: Option<f64>) -> Order {
+        Order {
+            desire_price,
+            order_type: if desire_price.is_some() { OrderType::Limit } else { OrderType::Market },
+            ..new_test_order()
+        }
+    }
+
+    fn new_prices() -> SortedVec<crate::asset_symbol::AssetSymbol, AssetPrice> {
+        let mut prices = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+
+        prices
+    }
+
+    fn new_bidask() -> BidAsk {
+        BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0)
+    }
+
+    fn new_filled(pnl: f64) -> ClosedPosition {
+        let Position::Active(position) = new_order(None).open(&new_bidask(), &new_prices(), None).unwrap() else {
+            panic!("Must be active position");
+        };
+        let mut position = position.close(ClosePositionReason::ClientCommand, None);
+        position.pnl = Some(pnl);
+
+        position
+    }
+
+    fn new_canceled() -> ClosedPosition {
+        let Position::Pending(position) = new_order(Some(5.0)).open(&new_bidask(), &new_prices(), None).unwrap() else {
+            panic!("Must be pending position");
+        };
+
+        position.close(ClosePositionReason::ClientCommand)
+    }
+}
@@ -1,15 +1,23 @@
 use rust_extensions::date_time::DateTimeAsMicroseconds;
 use rust_extensions::sorted_vec::SortedVec;
 use crate::asset_symbol::AssetSymbol;
+use crate::order_id::OrderId;
 use crate::assets::{AssetAmount, AssetPrice};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ActiveTopUp {
-    pub id: String,
+    pub id: OrderId,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::micros"))]
     pub date: DateTimeAsMicroseconds,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
     pub total_assets: SortedVec<AssetSymbol, AssetAmount>,
     pub instrument_price: f64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
     pub asset_prices: SortedVec<AssetSymbol, AssetPrice>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
     pub bonus_assets: SortedVec<AssetSymbol, AssetAmount>,
 }
 
@@ -29,13 +37,19 @@ impl ActiveTopUp {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct CanceledTopUp {
-    pub id: String,
+    pub id: OrderId,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::micros"))]
     pub date: DateTimeAsMicroseconds,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
     pub total_assets: SortedVec<AssetSymbol, AssetAmount>,
     pub instrument_price: f64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
     pub asset_prices: SortedVec<AssetSymbol, AssetPrice>,
     pub cancel_instrument_price: f64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::micros"))]
     pub cancel_date: DateTimeAsMicroseconds,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::sorted_vec"))]
     pub bonus_assets:SortedVec<AssetSymbol, AssetAmount>,
 }
@@ -0,0 +1,177 @@
+//! Unit types for values that are easy to mix up as bare floats.
+//!
+//! Only meaningful arithmetic is implemented, so mixing units doesn't compile:
+//!
+//! ```compile_fail
+//! use trading_sdk::units::{Percent, Volume};
+//!
+//! let _ = Volume::new(100.0) + Percent::new(5.0);
+//! ```
+//!
+//! ```compile_fail
+//! use trading_sdk::units::{Percent, Price};
+//!
+//! let _ = Price::new(10.0) * Percent::new(5.0);
+//! ```
+//!
+//! ```
+//! use trading_sdk::units::{Percent, Price, Volume};
+//!
+//! let amount = Volume::new(100.0) * Price::new(0.5);
+//! let fee = Percent::new(5.0).of(amount);
+//!
+//! assert_eq!(50.0, amount);
+//! assert_eq!(2.5, fee);
+//! ```
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::ops::{Add, Mul, Sub};
+
+/// Volume of position in base asset
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Volume(pub f64);
+
+impl Volume {
+    pub fn new(value: f64) -> Self {
+        Volume(value)
+    }
+
+    pub fn value(&self) -> f64 {
+        self.0
+    }
+}
+
+impl Add for Volume {
+    type Output = Volume;
+
+    fn add(self, rhs: Volume) -> Volume {
+        Volume(self.0 + rhs.0)
+    }
+}
+
+impl Sub for Volume {
+    type Output = Volume;
+
+    fn sub(self, rhs: Volume) -> Volume {
+        Volume(self.0 - rhs.0)
+    }
+}
+
+impl Mul<f64> for Volume {
+    type Output = Volume;
+
+    fn mul(self, rhs: f64) -> Volume {
+        Volume(self.0 * rhs)
+    }
+}
+
+/// Volume multiplied by price is amount in quote asset
+impl Mul<Price> for Volume {
+    type Output = f64;
+
+    fn mul(self, rhs: Price) -> f64 {
+        self.0 * rhs.0
+    }
+}
+
+/// Price of instrument or asset
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Price(pub f64);
+
+impl Price {
+    pub fn new(value: f64) -> Self {
+        Price(value)
+    }
+
+    pub fn value(&self) -> f64 {
+        self.0
+    }
+}
+
+impl Sub for Price {
+    type Output = Price;
+
+    fn sub(self, rhs: Price) -> Price {
+        Price(self.0 - rhs.0)
+    }
+}
+
+impl Mul<f64> for Price {
+    type Output = Price;
+
+    fn mul(self, rhs: f64) -> Price {
+        Price(self.0 * rhs)
+    }
+}
+
+/// Percent where 100.0 is the whole, use to_rate to get fraction
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Percent(pub f64);
+
+impl Percent {
+    pub fn new(value: f64) -> Self {
+        Percent(value)
+    }
+
+    pub fn from_rate(rate: f64) -> Self {
+        Percent(rate * 100.0)
+    }
+
+    pub fn value(&self) -> f64 {
+        self.0
+    }
+
+    pub fn to_rate(&self) -> f64 {
+        self.0 / 100.0
+    }
+
+    /// Returns percent of value
+    pub fn of(&self, value: f64) -> f64 {
+        value * self.to_rate()
+    }
+}
+
+impl Add for Percent {
+    type Output = Percent;
+
+    fn add(self, rhs: Percent) -> Percent {
+        Percent(self.0 + rhs.0)
+    }
+}
+
+impl Sub for Percent {
+    type Output = Percent;
+
+    fn sub(self, rhs: Percent) -> Percent {
+        Percent(self.0 - rhs.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Percent, Price, Volume};
+
+    #[test]
+    fn percent_and_rate() {
+        let percent = Percent::from_rate(0.05);
+
+        assert_eq!(5.0, percent.value());
+        assert_eq!(0.05, percent.to_rate());
+        assert_eq!(10.0, Percent::new(10.0).of(100.0));
+    }
+
+    #[test]
+    fn arithmetic_preserves_values() {
+        let volume = Volume::new(1000.0) + Volume::new(500.0) - Volume::new(250.0);
+        let price = Price::new(10.5) - Price::new(0.5);
+
+        assert_eq!(1250.0, volume.value());
+        assert_eq!(12500.0, volume * price);
+        assert_eq!(2500.0, (volume * 2.0).value());
+        assert_eq!(20.0, (price * 2.0).value());
+    }
+}
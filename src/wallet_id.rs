@@ -1,7 +1,12 @@
+use std::convert::Infallible;
 use std::fmt::Display;
+use std::str::FromStr;
 use uuid::Uuid;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct WalletId(pub String);
 
 impl From<&str> for WalletId {
@@ -28,6 +33,14 @@ impl From<Uuid> for WalletId {
     }
 }
 
+impl FromStr for WalletId {
+    type Err = Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(value.into())
+    }
+}
+
 impl Display for WalletId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0.to_string())
@@ -44,4 +57,11 @@ mod tests {
         let wallet_id: WalletId = str.into();
         println!("{}", wallet_id)
     }
+
+    #[test]
+    fn parse() {
+        let wallet_id = "wallet".parse::<WalletId>();
+
+        assert_eq!(Ok(WalletId::from("wallet")), wallet_id);
+    }
 }
\ No newline at end of file
@@ -1,6 +1,6 @@
 use crate::calculations::calculate_percent;
 use crate::orders::OrderSide;
-use crate::positions::BidAsk;
+use crate::positions::{ActivePosition, BidAsk};
 use ahash::AHashMap;
 use rust_extensions::sorted_vec::{EntityWithKey, SortedVec};
 use crate::asset_symbol::AssetSymbol;
@@ -8,27 +8,82 @@ use crate::assets;
 use crate::assets::{AssetAmount, AssetPrice};
 use crate::instrument_symbol::InstrumentSymbol;
 use crate::wallet_id::WalletId;
+use crate::trader_id::TraderId;
+use crate::balance_id::BalanceId;
+use crate::errors::TradingError;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug)]
 pub struct Wallet {
     pub id: WalletId,
-    pub trader_id: String,
+    pub trader_id: TraderId,
     pub total_unlocked_balance: f64,
     pub margin_call_percent: f64,
     pub current_loss_percent: f64,
-    prev_loss_percent: f64,
     estimate_asset: AssetSymbol,
     balances_by_instruments: SortedVec<InstrumentSymbol, WalletBalance>,
     prices_by_assets: SortedVec<AssetSymbol, AssetPrice>,
     top_up_pnls_by_instruments: AHashMap<InstrumentSymbol, f64>,
     top_up_reserved_balance_by_instruments: AHashMap<InstrumentSymbol, f64>,
     pub total_top_up_reserved_balance: f64,
+    pub net_margin_mode: MarginMode,
+    /// Margin call re-arms only after loss drops below margin_call_percent minus hysteresis
+    pub margin_call_hysteresis: f64,
+    margin_call_armed: bool,
+    margin_call_fired: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MarginMode {
+    /// Margin of opposite positions on the same instrument is summed
+    Hedged,
+    /// Volumes of opposite positions on the same instrument are offset, the rest is margined by
+    /// leverage of positions on the larger side
+    Netting,
+}
+
+/// Volumes and margins of both sides of instrument positions in netting mode
+#[derive(Default)]
+struct NettingLegs {
+    buy_volume: f64,
+    buy_margin: f64,
+    sell_volume: f64,
+    sell_margin: f64,
+}
+
+impl NettingLegs {
+    fn add(&mut self, position: &ActivePosition) {
+        match position.order.side {
+            OrderSide::Buy => {
+                self.buy_volume += position.current_volume();
+                self.buy_margin += position.used_margin();
+            }
+            OrderSide::Sell => {
+                self.sell_volume += position.current_volume();
+                self.sell_margin += position.used_margin();
+            }
+        }
+    }
+
+    /// Margin of net volume by leverage of larger side
+    fn net_margin(&self) -> f64 {
+        let net_volume = self.buy_volume - self.sell_volume;
+
+        if net_volume > 0.0 {
+            self.buy_margin * net_volume / self.buy_volume
+        } else if net_volume < 0.0 {
+            self.sell_margin * -net_volume / self.sell_volume
+        } else {
+            0.0
+        }
+    }
 }
 
 impl Wallet {
     pub fn new(
         id: WalletId,
-        trader_id: impl Into<String>,
+        trader_id: impl Into<TraderId>,
         estimate_asset: AssetSymbol,
         margin_call_percent: f64,
     ) -> Self {
@@ -41,28 +96,64 @@ impl Wallet {
             prices_by_assets: SortedVec::new(),
             margin_call_percent,
             current_loss_percent: 0.0,
-            prev_loss_percent: 0.0,
             top_up_pnls_by_instruments: Default::default(),
             top_up_reserved_balance_by_instruments: Default::default(),
             total_top_up_reserved_balance: 0.0,
+            net_margin_mode: MarginMode::Hedged,
+            margin_call_hysteresis: 0.0,
+            margin_call_armed: true,
+            margin_call_fired: false,
         }
     }
 
+    /// Sets reserved top-up amount of instrument in estimate asset. Keeps previous amount and
+    /// returns error with assets which have no cached price
     pub fn set_top_up_reserved(
         &mut self,
         instrument: &InstrumentSymbol,
         instrument_reserved: &SortedVec<AssetSymbol, AssetAmount>,
-    ) {
+    ) -> Result<(), TradingError> {
+        self.set_top_up_reserved_inner(instrument, instrument_reserved, None)
+    }
+
+    /// Same as set_top_up_reserved but uses fallback prices for assets without cached price
+    pub fn set_top_up_reserved_with_fallback(
+        &mut self,
+        instrument: &InstrumentSymbol,
+        instrument_reserved: &SortedVec<AssetSymbol, AssetAmount>,
+        fallback_prices: &SortedVec<AssetSymbol, AssetPrice>,
+    ) -> Result<(), TradingError> {
+        self.set_top_up_reserved_inner(instrument, instrument_reserved, Some(fallback_prices))
+    }
+
+    fn set_top_up_reserved_inner(
+        &mut self,
+        instrument: &InstrumentSymbol,
+        instrument_reserved: &SortedVec<AssetSymbol, AssetAmount>,
+        fallback_prices: Option<&SortedVec<AssetSymbol, AssetPrice>>,
+    ) -> Result<(), TradingError> {
         let mut new_reserved = 0.0;
+        let mut assets_without_price = Vec::new();
 
         for item in instrument_reserved.iter() {
-            let price = self.prices_by_assets.get(&item.symbol);
+            let price = self
+                .prices_by_assets
+                .get(&item.symbol)
+                .or_else(|| fallback_prices.and_then(|prices| prices.get(&item.symbol)))
+                .map(|price| price.price)
+                .or_else(|| (item.symbol == self.estimate_asset).then_some(1.0));
 
             if let Some(price) = price {
-                new_reserved += price.price * item.amount;
+                new_reserved += price * item.amount;
+            } else {
+                assets_without_price.push(item.symbol.clone());
             }
         }
 
+        if !assets_without_price.is_empty() {
+            return Err(TradingError::PricesNotFound(assets_without_price));
+        }
+
         let old_reserved = self
             .top_up_reserved_balance_by_instruments
             .get_mut(instrument);
@@ -76,6 +167,56 @@ impl Wallet {
         }
 
         self.total_top_up_reserved_balance += new_reserved;
+
+        Ok(())
+    }
+
+    /// Reserved top-up amounts in estimate asset by instruments
+    pub fn reserved_by_instrument(&self) -> &AHashMap<InstrumentSymbol, f64> {
+        &self.top_up_reserved_balance_by_instruments
+    }
+
+    /// Reserved top-up amount of instrument in estimate asset
+    pub fn reserved_for(&self, instrument: &InstrumentSymbol) -> f64 {
+        self.top_up_reserved_balance_by_instruments
+            .get(instrument)
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    /// Asset of total_unlocked_balance and other wallet totals
+    pub fn get_estimate_asset(&self) -> &AssetSymbol {
+        &self.estimate_asset
+    }
+
+    pub fn get_balance(&self, asset: &AssetSymbol) -> Option<&WalletBalance> {
+        self.balances_by_instruments
+            .iter()
+            .find(|b| &b.asset_symbol == asset)
+    }
+
+    pub fn snapshot(&self) -> WalletSnapshot {
+        WalletSnapshot {
+            id: self.id.clone(),
+            trader_id: self.trader_id.clone(),
+            estimate_asset: self.estimate_asset.clone(),
+            total_unlocked_balance: self.total_unlocked_balance,
+            total_top_up_reserved_balance: self.total_top_up_reserved_balance,
+            balances_by_instruments: self
+                .balances_by_instruments
+                .iter()
+                .map(|b| (b.instrument_symbol.clone(), b.clone()))
+                .collect(),
+            prices_by_assets: self
+                .prices_by_assets
+                .iter()
+                .map(|p| (p.symbol.clone(), p.price))
+                .collect(),
+            top_up_pnls_by_instruments: self.top_up_pnls_by_instruments.clone(),
+            top_up_reserved_balance_by_instruments: self
+                .top_up_reserved_balance_by_instruments
+                .clone(),
+        }
     }
 
     pub fn get_instruments(&self) -> Vec<&InstrumentSymbol> {
@@ -114,22 +255,39 @@ impl Wallet {
     }
 
     pub fn update_loss(&mut self) {
-        self.prev_loss_percent = self.current_loss_percent;
         let pnl: f64 = self.calc_total_pnl();
+        debug_assert!(pnl.is_finite(), "Non-finite pnl of wallet {}", self.id);
 
         if pnl < 0.0 {
+            debug_assert!(
+                self.total_unlocked_balance.is_finite()
+                    && self.total_top_up_reserved_balance.is_finite(),
+                "Non-finite balance of wallet {}",
+                self.id
+            );
             self.current_loss_percent = calculate_percent(
                 self.total_unlocked_balance + self.total_top_up_reserved_balance,
                 pnl.abs(),
-            );
+            )
+            .value();
         } else {
             self.current_loss_percent = 0.0;
         }
+
+        self.margin_call_fired = false;
+
+        if self.margin_call_armed && self.current_loss_percent >= self.margin_call_percent {
+            self.margin_call_fired = true;
+            self.margin_call_armed = false;
+        } else if !self.margin_call_armed
+            && self.current_loss_percent < self.margin_call_percent - self.margin_call_hysteresis
+        {
+            self.margin_call_armed = true;
+        }
     }
 
     pub fn is_margin_call(&self) -> bool {
-        self.current_loss_percent >= self.margin_call_percent
-            && self.prev_loss_percent < self.margin_call_percent
+        self.margin_call_fired
     }
 
     pub fn add_balance(&mut self, balance: WalletBalance, bid_ask: &BidAsk) -> Result<(), String> {
@@ -174,11 +332,11 @@ impl Wallet {
         Ok(())
     }
 
-    pub fn set_balance_lock(&mut self, balance_id: &str, is_locked: bool) -> Result<(), String> {
+    pub fn set_balance_lock(&mut self, balance_id: &BalanceId, is_locked: bool) -> Result<(), String> {
         let inner_balance = self
             .balances_by_instruments
             .iter_mut()
-            .find(|b| b.id == balance_id);
+            .find(|b| &b.id == balance_id);
 
         let Some(balance) = inner_balance else {
             return Err("Balance not found".to_string());
@@ -204,6 +362,118 @@ impl Wallet {
         Ok(())
     }
 
+    pub fn set_asset_lock(&mut self, asset: &AssetSymbol, is_locked: bool) -> Result<(), TradingError> {
+        let inner_balance = self
+            .balances_by_instruments
+            .iter_mut()
+            .find(|b| &b.asset_symbol == asset);
+
+        let Some(balance) = inner_balance else {
+            return Err(TradingError::BalanceNotFound(asset.clone()));
+        };
+
+        if balance.is_locked == is_locked {
+            return Ok(()); // no changes no need to do anything
+        }
+
+        let Some(price) = self.prices_by_assets.get(&balance.asset_symbol) else {
+            return Err(TradingError::PriceNotFound(asset.clone()));
+        };
+
+        if is_locked {
+            self.total_unlocked_balance -= balance.asset_amount * price.price;
+        } else {
+            self.total_unlocked_balance += balance.asset_amount * price.price;
+        }
+
+        balance.is_locked = is_locked;
+
+        Ok(())
+    }
+
+    /// Calculates margin in base asset used by wallet positions according to net_margin_mode.
+    /// Positions of other wallets are ignored
+    pub fn used_margin<'a>(&self, positions: impl IntoIterator<Item = &'a ActivePosition>) -> f64 {
+        let mut legs_by_instruments: AHashMap<&InstrumentSymbol, NettingLegs> = AHashMap::new();
+        let mut hedged_margin = 0.0;
+
+        for position in positions {
+            if position.order.wallet_id != self.id {
+                continue;
+            }
+
+            match self.net_margin_mode {
+                MarginMode::Hedged => hedged_margin += position.used_margin(),
+                MarginMode::Netting => legs_by_instruments
+                    .entry(&position.order.instrument)
+                    .or_default()
+                    .add(position),
+            }
+        }
+
+        hedged_margin + legs_by_instruments.values().map(NettingLegs::net_margin).sum::<f64>()
+    }
+
+    /// Calculates instrument price at which wallet loss reaches margin_call_percent while pnl
+    /// of other instruments stays the same. Only wallet positions with enabled top-ups are
+    /// counted since only their pnl affects wallet loss. Returns None if price can't be reached
+    pub fn margin_call_price<'a>(
+        &self,
+        instrument: &InstrumentSymbol,
+        positions: impl IntoIterator<Item = &'a ActivePosition>,
+    ) -> Option<f64> {
+        let balance = self.total_unlocked_balance + self.total_top_up_reserved_balance;
+
+        if balance <= 0.0 {
+            return None;
+        }
+
+        let other_pnl: f64 = self
+            .top_up_pnls_by_instruments
+            .iter()
+            .filter(|(pnl_instrument, _)| *pnl_instrument != instrument)
+            .map(|(_, pnl)| pnl)
+            .sum();
+        let target_pnl = -balance * self.margin_call_percent / 100.0 - other_pnl;
+        // instrument pnl at price p is slope * p + intercept
+        let mut slope = 0.0;
+        let mut intercept = 0.0;
+
+        for position in positions {
+            if position.order.wallet_id != self.id
+                || &position.order.instrument != instrument
+                || !position.order.top_up_enabled
+            {
+                continue;
+            }
+
+            let (volume, volume_by_price) = position.volume_and_volume_by_price();
+
+            match position.order.side {
+                OrderSide::Buy => {
+                    slope += volume_by_price;
+                    intercept -= volume;
+                }
+                OrderSide::Sell => {
+                    slope -= volume_by_price;
+                    intercept += volume;
+                }
+            }
+        }
+
+        if slope == 0.0 {
+            return None;
+        }
+
+        let price = (target_pnl - intercept) / slope;
+
+        if price > 0.0 {
+            Some(price)
+        } else {
+            None
+        }
+    }
+
     pub fn update_price(&mut self, bid_ask: &BidAsk) {
         let balance = self.balances_by_instruments.get(&bid_ask.instrument);
 
@@ -214,7 +484,7 @@ impl Wallet {
                 .get_mut(&balance.asset_symbol)
                 .expect("invalid add or update");
 
-            if balance.is_locked {
+            if !balance.is_locked {
                 self.total_unlocked_balance -= balance.asset_amount * old_price.price;
                 self.total_unlocked_balance += balance.asset_amount * new_price;
             }
@@ -224,9 +494,25 @@ impl Wallet {
     }
 }
 
+/// Full balance state of wallet
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct WalletSnapshot {
+    pub id: WalletId,
+    pub trader_id: TraderId,
+    pub estimate_asset: AssetSymbol,
+    pub total_unlocked_balance: f64,
+    pub total_top_up_reserved_balance: f64,
+    pub balances_by_instruments: AHashMap<InstrumentSymbol, WalletBalance>,
+    pub prices_by_assets: AHashMap<AssetSymbol, f64>,
+    pub top_up_pnls_by_instruments: AHashMap<InstrumentSymbol, f64>,
+    pub top_up_reserved_balance_by_instruments: AHashMap<InstrumentSymbol, f64>,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct WalletBalance {
-    pub id: String,
+    pub id: BalanceId,
     pub instrument_symbol: InstrumentSymbol,
     pub asset_symbol: AssetSymbol,
     pub asset_amount: f64,
@@ -238,3 +524,289 @@ impl EntityWithKey<InstrumentSymbol> for WalletBalance {
         &self.instrument_symbol
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{MarginMode, Wallet, WalletBalance};
+    use crate::asset_symbol::AssetSymbol;
+    use crate::assets::{AssetAmount, AssetPrice};
+    use crate::errors::TradingError;
+    use crate::instrument_symbol::InstrumentSymbol;
+    use crate::orders::{new_test_order, Order, OrderSide};
+    use crate::positions::{ActivePosition, BidAsk, Position};
+    use crate::wallet_id::WalletId;
+    use crate::calculations::round;
+    use rust_extensions::sorted_vec::SortedVec;
+
+    #[test]
+    fn set_asset_lock() {
+        let mut wallet = Wallet::new("test".into(), "test", "USDT".into(), 50.0);
+        let balance = WalletBalance {
+            id: "1".into(),
+            instrument_symbol: "BTCUSDT".into(),
+            asset_symbol: "BTC".into(),
+            asset_amount: 2.0,
+            is_locked: false,
+        };
+        wallet.add_balance(balance, &BidAsk::new_synthetic("BTCUSDT".into(), 20000.0, 20000.0)).unwrap();
+        let unlocked_balance = wallet.total_unlocked_balance;
+
+        wallet.set_asset_lock(&"BTC".into(), true).unwrap();
+
+        assert_eq!(unlocked_balance - 2.0 * 20000.0, wallet.total_unlocked_balance);
+
+        wallet.set_asset_lock(&"BTC".into(), true).unwrap();
+
+        assert_eq!(unlocked_balance - 2.0 * 20000.0, wallet.total_unlocked_balance);
+    }
+
+    #[test]
+    fn update_price_revalues_only_unlocked_balances() {
+        let mut wallet = Wallet::new("test".into(), "test", "USDT".into(), 50.0);
+        let bidask = BidAsk::new_synthetic("BTCUSDT".into(), 20000.0, 20000.0);
+        let btc = WalletBalance {
+            id: "1".into(),
+            instrument_symbol: "BTCUSDT".into(),
+            asset_symbol: "BTC".into(),
+            asset_amount: 2.0,
+            is_locked: false,
+        };
+        let eth = WalletBalance {
+            id: "2".into(),
+            instrument_symbol: "ETHUSDT".into(),
+            asset_symbol: "ETH".into(),
+            asset_amount: 10.0,
+            is_locked: true,
+        };
+        wallet.add_balance(btc, &bidask).unwrap();
+        wallet.add_balance(eth, &BidAsk::new_synthetic("ETHUSDT".into(), 1500.0, 1500.0)).unwrap();
+
+        wallet.update_price(&BidAsk::new_synthetic("BTCUSDT".into(), 21000.0, 21000.0));
+        wallet.update_price(&BidAsk::new_synthetic("ETHUSDT".into(), 1600.0, 1600.0));
+
+        assert_eq!(2.0 * 21000.0, wallet.total_unlocked_balance);
+    }
+
+    #[test]
+    fn margin_call_hysteresis() {
+        let instrument: InstrumentSymbol = "BTCUSDT".into();
+        let mut wallet = Wallet::new("test".into(), "test", "USDT".into(), 50.0);
+        wallet.total_unlocked_balance = 100.0;
+        wallet.margin_call_hysteresis = 5.0;
+        let mut margin_calls = Vec::new();
+
+        for pnl in [-51.0, -49.0, -51.0, -44.0, -51.0] {
+            wallet.set_top_up_pnl(&instrument, pnl);
+            wallet.update_loss();
+            margin_calls.push(wallet.is_margin_call());
+        }
+
+        assert_eq!(vec![true, false, false, false, true], margin_calls);
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut wallet = Wallet::new("test".into(), "test", "USDT".into(), 50.0);
+        let balance = WalletBalance {
+            id: "1".into(),
+            instrument_symbol: "BTCUSDT".into(),
+            asset_symbol: "BTC".into(),
+            asset_amount: 2.0,
+            is_locked: false,
+        };
+        wallet.add_balance(balance, &BidAsk::new_synthetic("BTCUSDT".into(), 20000.0, 20000.0)).unwrap();
+        wallet.set_top_up_pnl(&"BTCUSDT".into(), -10.0);
+
+        let snapshot = wallet.snapshot();
+        let instrument: InstrumentSymbol = "BTCUSDT".into();
+        let asset: AssetSymbol = "BTC".into();
+
+        assert_eq!(40000.0, snapshot.total_unlocked_balance);
+        assert_eq!(2.0, snapshot.balances_by_instruments.get(&instrument).unwrap().asset_amount);
+        assert_eq!(Some(&20000.0), snapshot.prices_by_assets.get(&asset));
+        assert_eq!(Some(&-10.0), snapshot.top_up_pnls_by_instruments.get(&instrument));
+        assert_eq!(2.0, wallet.get_balance(&"BTC".into()).unwrap().asset_amount);
+        assert!(wallet.get_balance(&"ETH".into()).is_none());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_snapshot() {
+        let mut wallet = Wallet::new("test".into(), "test", "USDT".into(), 50.0);
+        let balance = WalletBalance {
+            id: "1".into(),
+            instrument_symbol: "BTCUSDT".into(),
+            asset_symbol: "BTC".into(),
+            asset_amount: 2.0,
+            is_locked: false,
+        };
+        wallet.add_balance(balance, &BidAsk::new_synthetic("BTCUSDT".into(), 20000.0, 20000.0)).unwrap();
+
+        let json = serde_json::to_value(wallet.snapshot()).unwrap();
+        let deserialized: super::WalletSnapshot = serde_json::from_value(json.clone()).unwrap();
+
+        assert_eq!(json, serde_json::to_value(&deserialized).unwrap());
+        assert_eq!(40000.0, deserialized.total_unlocked_balance);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "Non-finite pnl of wallet")]
+    fn update_loss_with_nan_pnl() {
+        let mut wallet = Wallet::new("test".into(), "test", "USDT".into(), 50.0);
+        wallet.set_top_up_pnl(&"BTCUSDT".into(), f64::NAN);
+
+        wallet.update_loss();
+    }
+
+    #[test]
+    fn set_top_up_reserved_without_price() {
+        let instrument: InstrumentSymbol = "BTCUSDT".into();
+        let mut wallet = Wallet::new("test".into(), "test", "USDT".into(), 50.0);
+        let mut reserved = SortedVec::new();
+        reserved.insert_or_replace(AssetAmount {amount: 1.0, symbol: "ETH".into()});
+        let mut fallback_prices = SortedVec::new();
+        fallback_prices.insert_or_replace(AssetPrice {price: 1500.0, symbol: "ETH".into()});
+
+        let result = wallet.set_top_up_reserved(&instrument, &reserved);
+
+        assert_eq!(Err(TradingError::PricesNotFound(vec!["ETH".into()])), result);
+        assert_eq!(0.0, wallet.total_top_up_reserved_balance);
+
+        wallet
+            .set_top_up_reserved_with_fallback(&instrument, &reserved, &fallback_prices)
+            .unwrap();
+
+        assert_eq!(1500.0, wallet.total_top_up_reserved_balance);
+    }
+
+    #[test]
+    fn update_balance_and_lock() {
+        let mut wallet = Wallet::new("test".into(), "test", "USDT".into(), 50.0);
+        let asset: AssetSymbol = "BTC".into();
+        let balance = WalletBalance {
+            id: "1".into(),
+            instrument_symbol: "BTCUSDT".into(),
+            asset_symbol: asset.clone(),
+            asset_amount: 2.0,
+            is_locked: false,
+        };
+        wallet.add_balance(balance.clone(), &BidAsk::new_synthetic("BTCUSDT".into(), 20000.0, 20000.0)).unwrap();
+
+        wallet.update_balance(WalletBalance {asset_amount: 3.0, ..balance}).unwrap();
+
+        assert_eq!(60000.0, wallet.total_unlocked_balance);
+        assert_eq!(3.0, wallet.get_balance(&asset).unwrap().asset_amount);
+
+        wallet.set_balance_lock(&"1".into(), true).unwrap();
+
+        assert_eq!(0.0, wallet.total_unlocked_balance);
+    }
+
+    #[test]
+    fn reserved_total_equals_sum_by_instruments() {
+        let mut wallet = Wallet::new("test".into(), "test", "USDT".into(), 50.0);
+        let btc_instrument: InstrumentSymbol = "BTCUSDT".into();
+        let eth_instrument: InstrumentSymbol = "ETHUSDT".into();
+
+        for (instrument, amount) in [
+            (&btc_instrument, 10.0),
+            (&eth_instrument, 20.0),
+            (&btc_instrument, 5.0),
+            (&eth_instrument, 7.0),
+        ] {
+            let mut reserved = SortedVec::new();
+            reserved.insert_or_replace(AssetAmount {amount, symbol: "USDT".into()});
+            wallet.set_top_up_reserved(instrument, &reserved).unwrap();
+        }
+
+        let sum: f64 = wallet.reserved_by_instrument().values().sum();
+
+        assert_eq!(12.0, wallet.total_top_up_reserved_balance);
+        assert_eq!(sum, wallet.total_top_up_reserved_balance);
+        assert_eq!(5.0, wallet.reserved_for(&btc_instrument));
+        assert_eq!(0.0, wallet.reserved_for(&"XRPUSDT".into()));
+    }
+
+    #[test]
+    fn set_top_up_reserved_twice() {
+        let mut wallet = Wallet::new("test".into(), "test", "USDT".into(), 50.0);
+        let instrument: InstrumentSymbol = "BTCUSDT".into();
+        let mut reserved = SortedVec::new();
+        reserved.insert_or_replace(AssetAmount {amount: 10.0, symbol: "USDT".into()});
+        wallet.set_top_up_reserved(&instrument, &reserved).unwrap();
+        reserved.insert_or_replace(AssetAmount {amount: 25.0, symbol: "USDT".into()});
+
+        wallet.set_top_up_reserved(&instrument, &reserved).unwrap();
+
+        assert_eq!(25.0, wallet.total_top_up_reserved_balance);
+    }
+
+    #[test]
+    fn used_margin_by_mode() {
+        let mut wallet = Wallet::new("test".into(), "test", "USDT".into(), 50.0);
+        let positions = [
+            new_position(&wallet.id, OrderSide::Buy),
+            new_position(&wallet.id, OrderSide::Sell),
+        ];
+
+        let hedged_margin = wallet.used_margin(positions.iter());
+        wallet.net_margin_mode = MarginMode::Netting;
+        let netting_margin = wallet.used_margin(positions.iter());
+
+        assert_eq!(200.0, hedged_margin);
+        assert_eq!(0.0, netting_margin);
+    }
+
+    #[test]
+    fn netting_used_margin_with_different_leverages() {
+        let mut wallet = Wallet::new("test".into(), "test", "USDT".into(), 50.0);
+        wallet.net_margin_mode = MarginMode::Netting;
+        let buy_position = new_position(&wallet.id, OrderSide::Buy);
+        let mut sell_position = new_position(&wallet.id, OrderSide::Sell);
+        sell_position.order.leverage = 5.0;
+
+        let netting_margin = wallet.used_margin([&buy_position, &sell_position]);
+        let sell_only_margin = wallet.used_margin([&sell_position]);
+
+        // 1000 buy volume minus 500 sell volume leaves 500 margined at leverage 10
+        assert_eq!(50.0, netting_margin);
+        assert_eq!(100.0, sell_only_margin);
+    }
+
+    #[test]
+    fn margin_call_price() {
+        let mut wallet = Wallet::new("test".into(), "test", "USDT".into(), 50.0);
+        wallet.total_unlocked_balance = 1000.0;
+        let mut position = new_position(&wallet.id, OrderSide::Buy);
+        position.order.top_up_enabled = true;
+        let instrument = position.order.instrument.clone();
+
+        let price = wallet.margin_call_price(&instrument, [&position]).unwrap();
+        position.update(&BidAsk::new_synthetic(instrument.clone(), price, price));
+        wallet.set_top_up_pnl(&instrument, position.current_pnl);
+        wallet.update_loss();
+
+        assert_eq!(5.0, round(price, 8));
+        assert!(wallet.is_margin_call());
+        assert_eq!(None, wallet.margin_call_price(&"BTCUSDT".into(), [&position]));
+    }
+
+    fn new_position(wallet_id: &WalletId, side: OrderSide) -> ActivePosition {
+        let order = Order {
+            wallet_id: wallet_id.to_owned(),
+            leverage: 10.0,
+            side,
+            ..new_test_order()
+        };
+        let mut prices: SortedVec<AssetSymbol, AssetPrice> = SortedVec::new();
+        prices.insert_or_replace(AssetPrice {price: 1.0, symbol: "USDT".into()});
+        let bidask = BidAsk::new_synthetic("ATOMUSDT".into(), 10.0, 10.0);
+
+        let Position::Active(position) = order.open(&bidask, &prices, None).unwrap() else {
+            panic!("Must be active position");
+        };
+
+        position
+    }
+}